@@ -0,0 +1,258 @@
+/* Copyright (C) 2023 Lucas Haupt
+
+This program is distributed under the terms of the 
+GNU Lesser General Public License v3.0, 
+see COPYING.LESSER file for license information
+*/
+
+//! # lpctl
+//!
+//! Command line tool for scripting a Launchpad Mini.
+//! Run `lpctl help` for the list of commands.
+
+use std::process::exit;
+use std::time::{Duration, Instant};
+
+use launchpad_mini_control::{
+    Color, DeviceInfo, Input, LaunchDevice, LedTestBrightness, MatPos, MidiImpl, MidiInterface,
+    MidiInterfaceError, Output, TOP_ROW,
+};
+
+const USAGE: &str = "usage: lpctl [--unit N] <command> [args]
+
+commands:
+  list                      list midi devices and connected Launchpad units
+  set <row> <col> <color>   set the light at a position (row 8: round control buttons)
+  all <color>               set all square and scene buttons to one color
+  frame <file>              load a frame file (up to 9 lines of up to 9 colors,
+                            line 9 being the round control buttons, '#' starts a comment)
+  test [low|medium|full]    turn on all lights (LED test)
+  reset                     reset the Launchpad
+  duty <num> <den>          set the duty cycle of the LEDs
+  monitor [--json]          print button presses and releases (text or JSON lines)
+  help                      print this message
+
+colors are given by name (e.g. green, medyellow) or velocity byte (e.g. 0x22)";
+
+/// Print an error message and exit with a failure code
+fn fail(msg: &str) -> ! {
+    eprintln!("lpctl: {}", msg);
+    exit(1);
+}
+
+/// Parse a number argument or exit with an error message
+fn parse_num(arg: Option<&String>, what: &str) -> u8 {
+    match arg.map(|a| a.parse::<u8>()) {
+        Some(Ok(n)) => n,
+        _ => fail(&format!("expected {}\n\n{}", what, USAGE)),
+    }
+}
+
+/// Parse a color argument or exit with an error message
+fn parse_color(arg: Option<&String>) -> Color {
+    match arg.map(|a| a.parse::<Color>()) {
+        Some(Ok(color)) => color,
+        Some(Err(e)) => fail(&e.to_string()),
+        None => fail(&format!("expected color\n\n{}", USAGE)),
+    }
+}
+
+fn print_devices<'a>(ctx: &impl MidiInterface<'a>) -> Result<(), MidiInterfaceError> {
+    let devs = ctx.get_devices()?;
+    let inputs: Vec<&DeviceInfo> = devs.iter().filter(|d| d.is_input()).collect();
+    let outputs: Vec<&DeviceInfo> = devs.iter().filter(|d| d.is_output()).collect();
+
+    println!("Available input devices:");
+    for i in inputs {
+        println!("  {:>3}  {}", i.id, i.name);
+    }
+
+    println!("Available output devices:");
+    for o in outputs {
+        println!("  {:>3}  {}", o.id, o.name);
+    }
+
+    println!("Launchpad units:");
+    for unit in ctx.get_units(launchpad_mini_control::LAUNCHPAD_MINI_NAME)? {
+        println!(
+            "  {:>3}  {} (input {}, output {})",
+            unit.id.number, unit.id, unit.input.id, unit.output.id
+        );
+    }
+    Ok(())
+}
+
+/// Parse a frame file into a 9x9 matrix of colors (row 8: round control buttons)
+fn parse_frame(content: &str) -> Result<Vec<Vec<Color>>, MidiInterfaceError> {
+    let mut rows: Vec<Vec<Color>> = Vec::new();
+
+    for (nr, line) in content.lines().enumerate() {
+        let line = match line.split_once('#') {
+            Some((before, _)) => before,
+            None => line,
+        };
+        if line.trim().is_empty() {
+            continue;
+        }
+
+        let row = line
+            .split_whitespace()
+            .map(|c| c.parse::<Color>())
+            .collect::<Result<Vec<Color>, MidiInterfaceError>>()
+            .map_err(|e| MidiInterfaceError::Invalid(format!("line {}: {}", nr + 1, e)))?;
+
+        if row.len() > 9 {
+            return Err(MidiInterfaceError::Invalid(format!(
+                "line {}: more than 9 colors",
+                nr + 1
+            )));
+        }
+        rows.push(row);
+    }
+
+    if rows.len() > 9 {
+        return Err(MidiInterfaceError::Invalid(
+            "frame has more than 9 rows".to_string(),
+        ));
+    }
+    Ok(rows)
+}
+
+fn load_frame<I: Input, O: Output>(
+    lpad: &mut LaunchDevice<I, O>,
+    path: &str,
+) -> Result<(), MidiInterfaceError> {
+    let content = std::fs::read_to_string(path)
+        .map_err(|e| MidiInterfaceError::Invalid(format!("{}: {}", path, e)))?;
+    let frame = parse_frame(&content)?;
+
+    let mut mat = [[Color::Black; 9]; 8];
+    for (i, row) in frame.iter().take(8).enumerate() {
+        for (j, color) in row.iter().enumerate() {
+            mat[i][j] = *color;
+        }
+    }
+    lpad.set_matrix(&mat)?;
+
+    if let Some(ctl_row) = frame.get(8) {
+        for (j, color) in ctl_row.iter().take(8).enumerate() {
+            lpad.send_ctl_msg(0x68 + j as u8, *color as u8)?;
+        }
+    }
+    Ok(())
+}
+
+fn monitor<I: Input, O: Output>(
+    lpad: &LaunchDevice<I, O>,
+    json: bool,
+) -> Result<(), MidiInterfaceError> {
+    let start = Instant::now();
+    loop {
+        if !lpad.poll()? {
+            std::thread::sleep(Duration::from_millis(5));
+            continue;
+        }
+
+        for event in lpad.read_events(64)? {
+            let time_ms = start.elapsed().as_millis();
+            if json {
+                println!(
+                    "{{\"time_ms\":{},\"row\":{},\"col\":{},\"pressed\":{}}}",
+                    time_ms, event.pos.row, event.pos.col, event.pressed
+                );
+            } else {
+                let action = if event.pressed { "pressed" } else { "released" };
+                println!(
+                    "{:>8} ms  ({}, {}) {}",
+                    time_ms, event.pos.row, event.pos.col, action
+                );
+            }
+        }
+    }
+}
+
+fn main() {
+    let mut args: Vec<String> = std::env::args().skip(1).collect();
+
+    let mut unit_nr: Option<u32> = None;
+    if args.first().map(|a| a.as_str()) == Some("--unit") {
+        match args.get(1).map(|a| a.parse::<u32>()) {
+            Some(Ok(n)) => unit_nr = Some(n),
+            _ => fail(&format!("expected unit number\n\n{}", USAGE)),
+        }
+        args.drain(..2);
+    }
+
+    let cmd = match args.first() {
+        Some(cmd) => cmd.as_str(),
+        None => fail(USAGE),
+    };
+
+    if cmd == "help" || cmd == "--help" || cmd == "-h" {
+        println!("{}", USAGE);
+        return;
+    }
+
+    let midi: MidiImpl = MidiImpl::new().unwrap_or_else(|e| fail(&e.to_string()));
+
+    if cmd == "list" {
+        if let Err(e) = print_devices(&midi) {
+            fail(&e.to_string());
+        }
+        return;
+    }
+
+    let lpads = launchpad_mini_control::new_launch_devices_from_midi_interface(&midi)
+        .unwrap_or_else(|e| fail(&e.to_string()));
+    let mut lpad = match unit_nr {
+        None => lpads.into_iter().next(),
+        Some(n) => lpads.into_iter().find(|(id, _)| id.number == n),
+    }
+    .map(|(_, lpad)| lpad)
+    .unwrap_or_else(|| fail("no Launchpad Mini found"));
+
+    let res = match cmd {
+        "set" => {
+            let row = parse_num(args.get(1), "row");
+            let col = parse_num(args.get(2), "column");
+            let color = parse_color(args.get(3));
+            if !MatPos::new(row, col).is_valid() {
+                fail(&format!(
+                    "position ({}, {}) is not on the Launchpad",
+                    row, col
+                ));
+            }
+            if row == TOP_ROW {
+                lpad.send_ctl_msg(0x68 + col, color as u8)
+            } else {
+                lpad.set_position(row, col, color)
+            }
+        }
+        "all" => lpad.set_all(parse_color(args.get(1))),
+        "frame" => match args.get(1) {
+            Some(path) => load_frame(&mut lpad, path),
+            None => fail(&format!("expected frame file\n\n{}", USAGE)),
+        },
+        "test" => {
+            let brightness = match args.get(1).map(|a| a.as_str()) {
+                None | Some("full") => LedTestBrightness::Full,
+                Some("medium") => LedTestBrightness::Medium,
+                Some("low") => LedTestBrightness::Low,
+                Some(other) => fail(&format!("unknown brightness {}", other)),
+            };
+            lpad.led_test(brightness)
+        }
+        "reset" => lpad.reset(),
+        "duty" => {
+            let numerator = parse_num(args.get(1), "numerator");
+            let denominator = parse_num(args.get(2), "denominator");
+            lpad.set_duty_cycle(numerator, denominator)
+        }
+        "monitor" => monitor(&lpad, args.get(1).map(|a| a.as_str()) == Some("--json")),
+        other => fail(&format!("unknown command {}\n\n{}", other, USAGE)),
+    };
+
+    if let Err(e) = res {
+        fail(&e.to_string());
+    }
+}
@@ -0,0 +1,76 @@
+/* Copyright (C) 2023 Lucas Haupt
+
+This program is distributed under the terms of the 
+GNU Lesser General Public License v3.0, 
+see COPYING.LESSER file for license information
+*/
+
+//! # lpsim
+//!
+//! Launchpad Mini simulator in the terminal running a small demo application:
+//! square buttons toggle their light, the round control buttons select the color.
+
+use launchpad_mini_control::{
+    simulator, Color, LaunchDevice, MatPos, MockInput, MockMidi, MockOutput,
+};
+use std::process::exit;
+use std::time::Duration;
+
+/// Colors selectable by the round control buttons
+const COLORS: [Color; 8] = [
+    Color::Green,
+    Color::Yellow,
+    Color::Orange,
+    Color::Red,
+    Color::DimGreen,
+    Color::MedYellow,
+    Color::DimORedange,
+    Color::MedRed,
+];
+
+fn demo(lpad: &mut LaunchDevice<MockInput, MockOutput>) {
+    let mut color = COLORS[0];
+    let mut lit = [[false; 9]; 8];
+
+    lpad.reset().unwrap();
+    lpad.set_position(3, 5, Color::MedYellow).unwrap();
+    lit[3][5] = true;
+    lpad.send_ctl_msg(0x68, color as u8).unwrap();
+
+    loop {
+        std::thread::sleep(Duration::from_millis(10));
+        for event in lpad.read_events(64).unwrap() {
+            if !event.pressed {
+                continue;
+            }
+            let MatPos { row, col } = event.pos;
+            if row == 8 {
+                color = COLORS[col as usize];
+                lpad.set_first_row(Color::Black).unwrap();
+                lpad.send_ctl_msg(0x68 + col, color as u8).unwrap();
+            } else {
+                let on = &mut lit[row as usize][col as usize];
+                *on = !*on;
+                let c = if *on { color } else { Color::Black };
+                lpad.set_position(row, col, c).unwrap();
+            }
+        }
+    }
+}
+
+fn main() {
+    let midi = MockMidi::new();
+    let surface = midi.surface(0).expect("simulated unit");
+
+    std::thread::spawn(move || {
+        let mut lpads =
+            launchpad_mini_control::new_launch_devices_from_midi_interface(&midi).unwrap();
+        let (_, lpad) = lpads.first_mut().expect("simulated unit");
+        demo(lpad);
+    });
+
+    if let Err(e) = simulator::run(surface) {
+        eprintln!("lpsim: {}", e);
+        exit(1);
+    }
+}
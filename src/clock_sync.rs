@@ -0,0 +1,311 @@
+/* Copyright (C) 2023 Lucas Haupt
+
+This program is distributed under the terms of the 
+GNU Lesser General Public License v3.0, 
+see COPYING.LESSER file for license information
+*/
+
+//! # Clock sync
+//!
+//! Following and generating MIDI clock (24 clocks per quarter note): a tempo tracker
+//! derives tempo, song position and beat phase from received real-time messages,
+//! a clock generator sends clock, start, stop and continue messages to an output.
+//! Both can drive beat-synced grid animations.
+
+use std::collections::VecDeque;
+use std::time::{Duration, Instant};
+
+use crate::midilib::{MidiInterfaceError, Output, RealTimeMessage};
+use crate::sequencer::{Clock, SystemClock};
+
+/// Number of clocks per beat (quarter note)
+pub const CLOCKS_PER_BEAT: u64 = 24;
+
+/// Number of clocks per 16th note (unit of the song position)
+pub const CLOCKS_PER_SIXTEENTH: u64 = 6;
+
+/// Number of clock intervals the tempo is averaged over (one beat)
+const AVERAGED_INTERVALS: usize = CLOCKS_PER_BEAT as usize;
+
+/// Longest interval between two clocks (2.5 BPM), longer gaps restart the tempo detection
+const MAX_INTERVAL: Duration = Duration::from_secs(1);
+
+/// Derives tempo, position and beat phase from received real-time messages
+#[derive(Debug, Clone, Default)]
+pub struct TempoTracker {
+    running: bool,
+    /// number of the next clock, counted from the beginning of the song
+    clocks: u64,
+    /// time of the last clock counted while running, None if none since start/stop
+    last_tick: Option<Instant>,
+    /// time of the last clock received (clocks are sent while stopped, too)
+    last_clock: Option<Instant>,
+    intervals: VecDeque<Duration>,
+}
+impl TempoTracker {
+    pub fn new() -> TempoTracker {
+        TempoTracker::default()
+    }
+
+    /// Handle a real-time message received at a time,
+    /// returns the number of the beat starting with it (if it is a clock on a beat)
+    pub fn feed(&mut self, msg: RealTimeMessage, at: Instant) -> Option<u64> {
+        match msg {
+            RealTimeMessage::Clock => {
+                if let Some(last) = self.last_clock {
+                    let interval = at.saturating_duration_since(last);
+                    if interval > MAX_INTERVAL {
+                        self.intervals.clear();
+                    } else {
+                        if self.intervals.len() == AVERAGED_INTERVALS {
+                            self.intervals.pop_front();
+                        }
+                        self.intervals.push_back(interval);
+                    }
+                }
+                self.last_clock = Some(at);
+
+                if !self.running {
+                    return None;
+                }
+                let clock = self.clocks;
+                self.clocks += 1;
+                self.last_tick = Some(at);
+                if clock.is_multiple_of(CLOCKS_PER_BEAT) {
+                    return Some(clock / CLOCKS_PER_BEAT);
+                }
+            }
+            RealTimeMessage::Start => {
+                self.running = true;
+                self.clocks = 0;
+                self.last_tick = None;
+                // a gap before the start (e.g. a generator not clocking while stopped)
+                // is no clock interval
+                self.last_clock = None;
+            }
+            RealTimeMessage::Continue => {
+                self.running = true;
+                self.last_tick = None;
+                self.last_clock = None;
+            }
+            RealTimeMessage::Stop => {
+                self.running = false;
+                self.last_tick = None;
+            }
+            RealTimeMessage::SongPosition(pos) => {
+                self.clocks = pos as u64 * CLOCKS_PER_SIXTEENTH;
+                self.last_tick = None;
+            }
+        }
+        None
+    }
+
+    pub fn is_running(&self) -> bool {
+        self.running
+    }
+
+    /// Number of the next clock, counted from the beginning of the song
+    pub fn clocks(&self) -> u64 {
+        self.clocks
+    }
+
+    /// Average interval of the last clocks received, None until two clocks arrived
+    pub fn clock_interval(&self) -> Option<Duration> {
+        if self.intervals.is_empty() {
+            return None;
+        }
+        Some(self.intervals.iter().sum::<Duration>() / self.intervals.len() as u32)
+    }
+
+    /// Tempo in beats per minute, None until two clocks arrived
+    pub fn bpm(&self) -> Option<f64> {
+        let interval = self.clock_interval()?.as_secs_f64();
+        if interval <= 0.0 {
+            return None;
+        }
+        Some(60.0 / (interval * CLOCKS_PER_BEAT as f64))
+    }
+
+    /// Position in beats from the beginning of the song at a time,
+    /// interpolated between the clocks while running
+    pub fn position(&self, now: Instant) -> f64 {
+        let last_tick = match self.last_tick {
+            Some(at) if self.running => at,
+            _ => return self.clocks as f64 / CLOCKS_PER_BEAT as f64,
+        };
+        let fraction = match self.clock_interval() {
+            Some(interval) if !interval.is_zero() => {
+                let elapsed = now.saturating_duration_since(last_tick);
+                (elapsed.as_secs_f64() / interval.as_secs_f64()).min(1.0)
+            }
+            _ => 0.0,
+        };
+        ((self.clocks - 1) as f64 + fraction) / CLOCKS_PER_BEAT as f64
+    }
+
+    /// Phase within the current beat at a time, from 0.0 (on the beat) to below 1.0
+    pub fn beat_phase(&self, now: Instant) -> f64 {
+        self.position(now).fract()
+    }
+}
+
+/// Point in time a clock count refers to, clock times are computed relative to it
+#[derive(Debug, Clone, Copy)]
+struct Anchor {
+    at: Instant,
+    clock: u64,
+}
+
+/// Sends MIDI clock to an output at a tempo, for other devices to follow
+pub struct ClockGenerator<O: Output, C: Clock = SystemClock> {
+    out_port: O,
+    clock: C,
+    bpm: f64,
+    /// set while running
+    anchor: Option<Anchor>,
+    /// number of the next clock to send, counted from the beginning of the song
+    next_clock: u64,
+    /// tracker fed with the messages sent, for beat-synced animations
+    tracker: TempoTracker,
+}
+impl<O: Output, C: Clock> ClockGenerator<O, C> {
+    /// Create a stopped generator at 120 BPM
+    pub fn new(out_port: O, clock: C) -> ClockGenerator<O, C> {
+        ClockGenerator {
+            out_port,
+            clock,
+            bpm: 120.0,
+            anchor: None,
+            next_clock: 0,
+            tracker: TempoTracker::new(),
+        }
+    }
+
+    pub fn clock(&self) -> &C {
+        &self.clock
+    }
+
+    /// Output port the clock is sent to
+    pub fn output(&mut self) -> &mut O {
+        &mut self.out_port
+    }
+
+    /// Tracker following the clock sent (position and beat phase)
+    pub fn tracker(&self) -> &TempoTracker {
+        &self.tracker
+    }
+
+    pub fn bpm(&self) -> f64 {
+        self.bpm
+    }
+
+    /// Set the tempo in beats per minute, takes effect with the next clock
+    /// Returns Error, if the tempo is not within 1 to 999 BPM
+    pub fn set_bpm(&mut self, bpm: f64) -> Result<(), MidiInterfaceError> {
+        if !(1.0..=999.0).contains(&bpm) {
+            return Err(MidiInterfaceError::Invalid(format!(
+                "tempo {} BPM out of range (1 to 999)",
+                bpm
+            )));
+        }
+        if let Some(at) = self.next_clock_at() {
+            self.anchor = Some(Anchor {
+                at,
+                clock: self.next_clock,
+            });
+        }
+        self.bpm = bpm;
+        Ok(())
+    }
+
+    pub fn is_running(&self) -> bool {
+        self.anchor.is_some()
+    }
+
+    /// Time the next clock is due, None if stopped
+    pub fn next_clock_at(&self) -> Option<Instant> {
+        let anchor = self.anchor?;
+        let clocks = (self.next_clock - anchor.clock) as f64;
+        Some(
+            anchor.at
+                + Duration::from_secs_f64(clocks * 60.0 / (self.bpm * CLOCKS_PER_BEAT as f64)),
+        )
+    }
+
+    /// Send a message and feed it to the tracker
+    fn send(
+        &mut self,
+        msg: RealTimeMessage,
+        at: Instant,
+    ) -> Result<Option<u64>, MidiInterfaceError> {
+        self.out_port.write_realtime(msg)?;
+        Ok(self.tracker.feed(msg, at))
+    }
+
+    /// Send start and run from the beginning of the song (the first clock is sent by the next tick)
+    /// Returns Error, if sending fails
+    pub fn start(&mut self) -> Result<(), MidiInterfaceError> {
+        let now = self.clock.now();
+        self.send(RealTimeMessage::Start, now)?;
+        self.anchor = Some(Anchor { at: now, clock: 0 });
+        self.next_clock = 0;
+        Ok(())
+    }
+
+    /// Send continue and run from the current song position
+    /// Returns Error, if sending fails
+    pub fn resume(&mut self) -> Result<(), MidiInterfaceError> {
+        let now = self.clock.now();
+        self.send(RealTimeMessage::Continue, now)?;
+        self.anchor = Some(Anchor {
+            at: now,
+            clock: self.next_clock,
+        });
+        Ok(())
+    }
+
+    /// Send stop, the song position is kept for resume
+    /// Returns Error, if sending fails
+    pub fn stop(&mut self) -> Result<(), MidiInterfaceError> {
+        let now = self.clock.now();
+        self.anchor = None;
+        self.send(RealTimeMessage::Stop, now)?;
+        Ok(())
+    }
+
+    /// Move to a song position in 16th notes (0 to 16383) and send it
+    /// Returns Error, if running, if the position is out of range or if sending fails
+    pub fn set_song_position(&mut self, pos: u16) -> Result<(), MidiInterfaceError> {
+        if self.is_running() {
+            return Err(MidiInterfaceError::Invalid(
+                "song position can only be set while stopped".to_string(),
+            ));
+        }
+        if pos > 0x3FFF {
+            return Err(MidiInterfaceError::Invalid(format!(
+                "song position {} out of range (0 to 16383)",
+                pos
+            )));
+        }
+        let now = self.clock.now();
+        self.send(RealTimeMessage::SongPosition(pos), now)?;
+        self.next_clock = pos as u64 * CLOCKS_PER_SIXTEENTH;
+        Ok(())
+    }
+
+    /// Send the clocks due at the current time of the clock, returns the number
+    /// of the last beat started. After a stall all missed clocks are sent at once,
+    /// so followers keep the song position
+    /// Returns Error, if sending fails
+    pub fn tick(&mut self) -> Result<Option<u64>, MidiInterfaceError> {
+        let now = self.clock.now();
+        let mut beat = None;
+        while let Some(at) = self.next_clock_at().filter(|at| *at <= now) {
+            if let Some(b) = self.send(RealTimeMessage::Clock, at)? {
+                beat = Some(b);
+            }
+            self.next_clock += 1;
+        }
+        Ok(beat)
+    }
+}
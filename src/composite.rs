@@ -0,0 +1,273 @@
+/* Copyright (C) 2023 Lucas Haupt
+
+This program is distributed under the terms of the 
+GNU Lesser General Public License v3.0, 
+see COPYING.LESSER file for license information
+*/
+
+//! # Composite
+//!
+//! Several Launchpads arranged side by side, treated as one large virtual surface.
+//! Only the square 8x8 grids form the surface, the round buttons (scene column and
+//! control button row) stay attached to their unit.
+
+use crate::launch_device::LaunchDevice;
+use crate::midilib::{Input, LaunchMessage, MidiInterfaceError, Output};
+use crate::utils::{Key, MessageType, PadEvent, Rotation};
+use crate::{Color, MatPos, GRID_SIZE};
+
+/// Placement of a single unit on the virtual surface
+#[derive(Debug, Clone, Copy, Default)]
+pub struct Placement {
+    /// row of the surface the top left pad of the unit is placed at
+    pub row_offset: u8,
+    /// column of the surface the top left pad of the unit is placed at
+    pub col_offset: u8,
+    /// clockwise rotation the unit is mounted with
+    pub rotation: Rotation,
+}
+impl Placement {
+    pub fn new(row_offset: u8, col_offset: u8, rotation: Rotation) -> Placement {
+        Placement {
+            row_offset,
+            col_offset,
+            rotation,
+        }
+    }
+
+    /// Return if the global position lies on the grid of this unit
+    fn contains(&self, row: u8, col: u8) -> bool {
+        let size = GRID_SIZE as u16;
+        (self.row_offset as u16..self.row_offset as u16 + size).contains(&(row as u16))
+            && (self.col_offset as u16..self.col_offset as u16 + size).contains(&(col as u16))
+    }
+
+    /// Return if the grid of this unit lies completely within the u8 coordinates of the surface
+    fn fits(&self) -> bool {
+        self.row_offset.checked_add(GRID_SIZE).is_some()
+            && self.col_offset.checked_add(GRID_SIZE).is_some()
+    }
+
+    /// Translate a global position into the position on the unit
+    fn to_local(self, row: u8, col: u8) -> MatPos {
+        let pos = MatPos::new(row - self.row_offset, col - self.col_offset);
+        self.rotation.inverse().apply(pos, GRID_SIZE)
+    }
+
+    /// Translate a position on the unit's grid into the global position
+    fn to_global(self, pos: MatPos) -> MatPos {
+        let pos = self.rotation.apply(pos, GRID_SIZE);
+        MatPos::new(pos.row + self.row_offset, pos.col + self.col_offset)
+    }
+}
+
+/// Event on the virtual surface
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum SurfaceEvent {
+    /// a square pad has been pressed or released, position in global coordinates
+    Grid(PadEvent),
+    /// a round button (scene column or control button row) of a unit has been
+    /// pressed or released, position in the coordinates of the unit
+    Button { unit: usize, event: PadEvent },
+}
+
+/// Multiple LaunchDevices forming one virtual surface
+pub struct CompositeDevice<I: Input, O: Output> {
+    units: Vec<(Placement, LaunchDevice<I, O>)>,
+    rows: u8,
+    cols: u8,
+}
+impl<I: Input, O: Output> CompositeDevice<I, O> {
+    /// Create a virtual surface from LaunchDevices and their placements
+    /// Returns Error, if the grids of two units overlap, a grid does not fit on the surface
+    /// (offset above 247) or no unit is supplied
+    pub fn new(
+        units: Vec<(Placement, LaunchDevice<I, O>)>,
+    ) -> Result<CompositeDevice<I, O>, MidiInterfaceError> {
+        if units.is_empty() {
+            return Err(MidiInterfaceError::Invalid(
+                "composite device needs at least one unit".to_string(),
+            ));
+        }
+
+        if let Some(i) = units.iter().position(|(p, _)| !p.fits()) {
+            let p = units[i].0;
+            return Err(MidiInterfaceError::Invalid(format!(
+                "grid of unit {} at offset ({}, {}) does not fit on the surface",
+                i, p.row_offset, p.col_offset
+            )));
+        }
+
+        for (i, (a, _)) in units.iter().enumerate() {
+            for (j, (b, _)) in units.iter().enumerate().skip(i + 1) {
+                let rows_overlap = a.row_offset < b.row_offset + GRID_SIZE
+                    && b.row_offset < a.row_offset + GRID_SIZE;
+                let cols_overlap = a.col_offset < b.col_offset + GRID_SIZE
+                    && b.col_offset < a.col_offset + GRID_SIZE;
+                if rows_overlap && cols_overlap {
+                    return Err(MidiInterfaceError::Invalid(format!(
+                        "grids of unit {} and unit {} overlap",
+                        i, j
+                    )));
+                }
+            }
+        }
+
+        let rows = units
+            .iter()
+            .map(|(p, _)| p.row_offset + GRID_SIZE)
+            .max()
+            .unwrap_or(0);
+        let cols = units
+            .iter()
+            .map(|(p, _)| p.col_offset + GRID_SIZE)
+            .max()
+            .unwrap_or(0);
+
+        Ok(CompositeDevice { units, rows, cols })
+    }
+
+    /// Number of rows of the virtual surface
+    pub fn rows(&self) -> u8 {
+        self.rows
+    }
+
+    /// Number of columns of the virtual surface
+    pub fn cols(&self) -> u8 {
+        self.cols
+    }
+
+    /// Number of units forming the surface
+    pub fn unit_count(&self) -> usize {
+        self.units.len()
+    }
+
+    /// Access a single unit (e.g. for its round buttons)
+    pub fn unit(&mut self, index: usize) -> Option<&mut LaunchDevice<I, O>> {
+        self.units.get_mut(index).map(|(_, dev)| dev)
+    }
+
+    /// Dissolve the surface and return the units with their placements
+    pub fn into_units(self) -> Vec<(Placement, LaunchDevice<I, O>)> {
+        self.units
+    }
+
+    /// Returns if messages from any of the units are available or
+    /// an MidiInterfaceError, if polling fails
+    pub fn poll(&self) -> Result<bool, MidiInterfaceError> {
+        for (_, dev) in self.units.iter() {
+            if dev.poll()? {
+                return Ok(true);
+            }
+        }
+        Ok(false)
+    }
+
+    /// Read up to n messages from every unit and translate them into events on the surface
+    /// Returns Error, if reading fails
+    pub fn read_events(&self, n: usize) -> Result<Vec<SurfaceEvent>, MidiInterfaceError> {
+        let mut res: Vec<SurfaceEvent> = Vec::new();
+
+        for (unit, (placement, dev)) in self.units.iter().enumerate() {
+            if !dev.poll()? {
+                continue;
+            }
+
+            for event in dev.read_events(n)? {
+                if event.pos.is_grid() {
+                    res.push(SurfaceEvent::Grid(PadEvent::new(
+                        placement.to_global(event.pos),
+                        event.pressed,
+                    )));
+                } else {
+                    res.push(SurfaceEvent::Button { unit, event });
+                }
+            }
+        }
+
+        Ok(res)
+    }
+
+    /// Set the color/light at a global position on the surface
+    /// Returns Error, if the position is not covered by a unit or the action fails
+    pub fn set_position(
+        &mut self,
+        row: u8,
+        col: u8,
+        color: Color,
+    ) -> Result<(), MidiInterfaceError> {
+        let (placement, dev) = match self.units.iter_mut().find(|(p, _)| p.contains(row, col)) {
+            Some(unit) => unit,
+            None => {
+                return Err(MidiInterfaceError::Invalid(format!(
+                    "position ({}, {}) is not covered by a unit",
+                    row, col
+                )));
+            }
+        };
+
+        let pos = placement.to_local(row, col);
+        dev.set_position(pos.row, pos.col, color)
+    }
+
+    /// Takes a matrix of Colors in global coordinates and sets the lights according to the matrix,
+    /// entries not covered by a unit are ignored
+    /// Returns Error, if action fails
+    pub fn set_matrix<const R: usize, const C: usize>(
+        &mut self,
+        mat: &[[Color; C]; R],
+    ) -> Result<(), MidiInterfaceError> {
+        for (placement, dev) in self.units.iter_mut() {
+            let mut msgs: Vec<LaunchMessage> =
+                Vec::with_capacity(GRID_SIZE as usize * GRID_SIZE as usize);
+
+            for (i, parent) in mat.iter().enumerate() {
+                for (j, elem) in parent.iter().enumerate() {
+                    let (Ok(row), Ok(col)) = (u8::try_from(i), u8::try_from(j)) else {
+                        continue;
+                    };
+                    if !placement.contains(row, col) {
+                        continue;
+                    }
+
+                    msgs.push(LaunchMessage {
+                        status: MessageType::On as u8,
+                        data1: Key::from(placement.to_local(row, col)),
+                        data2: *elem as u8,
+                    });
+                }
+            }
+
+            dev.send_messages(msgs)?;
+        }
+        Ok(())
+    }
+
+    /// Set the square pads and the scene buttons of all units to one color
+    /// (like LaunchDevice::set_all, the control buttons are left as they are)
+    /// Returns Error, if action fails
+    pub fn set_all(&mut self, color: Color) -> Result<(), MidiInterfaceError> {
+        for (_, dev) in self.units.iter_mut() {
+            dev.set_all(color)?;
+        }
+        Ok(())
+    }
+
+    /// Turn the lights of all units off (see LaunchDevice::blackout)
+    /// Returns Error, if action fails
+    pub fn blackout(&mut self) -> Result<(), MidiInterfaceError> {
+        for (_, dev) in self.units.iter_mut() {
+            dev.blackout()?;
+        }
+        Ok(())
+    }
+
+    /// Reset the state of all units
+    /// Returns Error, if action fails
+    pub fn reset(&mut self) -> Result<(), MidiInterfaceError> {
+        for (_, dev) in self.units.iter_mut() {
+            dev.reset()?;
+        }
+        Ok(())
+    }
+}
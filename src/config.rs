@@ -0,0 +1,727 @@
+/* Copyright (C) 2023 Lucas Haupt
+
+This program is distributed under the terms of the 
+GNU Lesser General Public License v3.0, 
+see COPYING.LESSER file for license information
+*/
+
+//! # Config
+//!
+//! Declarative setup of pages from a TOML or JSON file (requires the `config` feature).
+//! Every page consists of colored regions, widgets and midi actions:
+//!
+//! ```toml
+//! navigation = "top_row"
+//! midi_output = "My Synth"
+//!
+//! [[pages]]
+//! name = "drums"
+//! regions = [{ from = [0, 0], to = [3, 7], color = "dimgreen" }]
+//! widgets = [{ type = "fader", name = "volume", orientation = "horizontal", index = 7,
+//!              on_color = "green", off_color = "black" }]
+//! actions = [{ type = "note", pos = [0, 0], note = 36, on_color = "green" },
+//!            { type = "cc", pos = [0, 8], controller = 20, toggle = true, on_color = "red" }]
+//! ```
+//!
+//! Positions are given as [row, col] (row 8 being the control button row).
+//! Errors name the key or position at fault (e.g. `pages[0].actions[1].pos`).
+//! A ConfigWatcher reloads the file when it changes.
+
+use std::path::{Path, PathBuf};
+use std::time::SystemTime;
+
+use serde::Deserialize;
+
+use crate::launch_device::LaunchDevice;
+use crate::mapping::{CcMode, LedFeedback, MidiAction, MidiMapper, VELOCITY_FULL};
+use crate::midilib::{Identifier, Input, LaunchMessage, MidiInterface, MidiInterfaceError, Output};
+use crate::pages::{Navigation, Page, PageEvent, PageManager, MAX_PAGES};
+use crate::utils::PadEvent;
+use crate::widgets::{
+    Fader, FaderOrientation, Momentary, RadioGroup, Toggle, WidgetEvent, WidgetSet, XyPad,
+};
+use crate::{Color, MatPos};
+
+/// Position given as [row, col]
+pub type PosConfig = (u8, u8);
+
+/// Top level of a config file
+#[derive(Debug, Clone, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct Config {
+    #[serde(default)]
+    pub navigation: Navigation,
+    #[serde(default = "default_selected_color")]
+    pub selected_color: String,
+    #[serde(default = "default_page_color")]
+    pub page_color: String,
+    /// name of the midi output the actions are sent to
+    pub midi_output: Option<String>,
+    pub pages: Vec<PageConfig>,
+}
+
+fn default_selected_color() -> String {
+    "green".to_string()
+}
+
+fn default_page_color() -> String {
+    "dimgreen".to_string()
+}
+
+fn default_velocity() -> u8 {
+    VELOCITY_FULL
+}
+
+fn default_cc_on() -> u8 {
+    0x7F
+}
+
+/// A page of the config file
+#[derive(Debug, Clone, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct PageConfig {
+    pub name: Option<String>,
+    #[serde(default)]
+    pub regions: Vec<RegionConfig>,
+    #[serde(default)]
+    pub widgets: Vec<WidgetConfig>,
+    #[serde(default)]
+    pub actions: Vec<ActionConfig>,
+}
+
+/// Rectangle of buttons lit in one color (from the top left to the bottom right button)
+#[derive(Debug, Clone, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct RegionConfig {
+    pub from: PosConfig,
+    /// a single button if left out
+    pub to: Option<PosConfig>,
+    pub color: String,
+}
+
+/// A widget of the config file (see widgets.rs)
+#[derive(Debug, Clone, Deserialize)]
+#[serde(tag = "type", rename_all = "snake_case", deny_unknown_fields)]
+pub enum WidgetConfig {
+    Toggle {
+        name: Option<String>,
+        pos: PosConfig,
+        on_color: String,
+        off_color: String,
+    },
+    Momentary {
+        name: Option<String>,
+        pos: PosConfig,
+        on_color: String,
+        off_color: String,
+    },
+    RadioGroup {
+        name: Option<String>,
+        row: u8,
+        first_col: u8,
+        len: u8,
+        on_color: String,
+        off_color: String,
+    },
+    Fader {
+        name: Option<String>,
+        orientation: FaderOrientation,
+        index: u8,
+        on_color: String,
+        off_color: String,
+    },
+    XyPad {
+        name: Option<String>,
+        from: PosConfig,
+        to: PosConfig,
+        cursor_color: String,
+        cross_color: String,
+        off_color: String,
+    },
+}
+
+/// A midi action of the config file (see mapping.rs), channels count from 0.
+/// The button lights up with the colors given (black if only one is given)
+#[derive(Debug, Clone, Deserialize)]
+#[serde(tag = "type", rename_all = "snake_case", deny_unknown_fields)]
+pub enum ActionConfig {
+    Note {
+        pos: PosConfig,
+        #[serde(default)]
+        channel: u8,
+        note: u8,
+        #[serde(default = "default_velocity")]
+        velocity: u8,
+        on_color: Option<String>,
+        off_color: Option<String>,
+    },
+    Cc {
+        pos: PosConfig,
+        #[serde(default)]
+        channel: u8,
+        controller: u8,
+        #[serde(default)]
+        toggle: bool,
+        #[serde(default = "default_cc_on")]
+        on: u8,
+        #[serde(default)]
+        off: u8,
+        on_color: Option<String>,
+        off_color: Option<String>,
+    },
+    ProgramChange {
+        pos: PosConfig,
+        #[serde(default)]
+        channel: u8,
+        program: u8,
+        on_color: Option<String>,
+        off_color: Option<String>,
+    },
+}
+
+/// Prefix an error with the key it belongs to
+fn at(path: &str, e: MidiInterfaceError) -> MidiInterfaceError {
+    let msg = match e {
+        MidiInterfaceError::Invalid(msg) => msg,
+        other => other.to_string(),
+    };
+    MidiInterfaceError::Invalid(format!("{}: {}", path, msg))
+}
+
+fn parse_color(path: &str, color: &str) -> Result<Color, MidiInterfaceError> {
+    color.parse::<Color>().map_err(|e| at(path, e))
+}
+
+fn parse_pos(path: &str, (row, col): PosConfig) -> Result<MatPos, MidiInterfaceError> {
+    MatPos::try_new(row, col).map_err(|e| at(path, e))
+}
+
+fn parse_feedback(
+    path: &str,
+    on_color: &Option<String>,
+    off_color: &Option<String>,
+) -> Result<Option<LedFeedback>, MidiInterfaceError> {
+    if on_color.is_none() && off_color.is_none() {
+        return Ok(None);
+    }
+    let on = match on_color {
+        Some(c) => parse_color(&format!("{}.on_color", path), c)?,
+        None => Color::Black,
+    };
+    let off = match off_color {
+        Some(c) => parse_color(&format!("{}.off_color", path), c)?,
+        None => Color::Black,
+    };
+    Ok(Some(LedFeedback::new(on, off)))
+}
+
+impl WidgetConfig {
+    fn name(&self) -> Option<String> {
+        match self {
+            WidgetConfig::Toggle { name, .. }
+            | WidgetConfig::Momentary { name, .. }
+            | WidgetConfig::RadioGroup { name, .. }
+            | WidgetConfig::Fader { name, .. }
+            | WidgetConfig::XyPad { name, .. } => name.clone(),
+        }
+    }
+
+    /// Build the widget and add it to a set, returns its index in the set
+    fn add_to(&self, path: &str, set: &mut WidgetSet) -> Result<usize, MidiInterfaceError> {
+        let color = |key: &str, c: &str| parse_color(&format!("{}.{}", path, key), c);
+
+        let widget = match self {
+            WidgetConfig::Toggle {
+                pos,
+                on_color,
+                off_color,
+                ..
+            } => set.add(Toggle::new(
+                parse_pos(&format!("{}.pos", path), *pos)?,
+                color("on_color", on_color)?,
+                color("off_color", off_color)?,
+            )),
+            WidgetConfig::Momentary {
+                pos,
+                on_color,
+                off_color,
+                ..
+            } => set.add(Momentary::new(
+                parse_pos(&format!("{}.pos", path), *pos)?,
+                color("on_color", on_color)?,
+                color("off_color", off_color)?,
+            )),
+            WidgetConfig::RadioGroup {
+                row,
+                first_col,
+                len,
+                on_color,
+                off_color,
+                ..
+            } => {
+                let last_col = *first_col as usize + *len as usize;
+                let cols = if *row == 8 { 8 } else { 9 };
+                if *len == 0 || last_col > cols {
+                    return Err(MidiInterfaceError::Invalid(format!(
+                        "{}.len: {} buttons from column {} do not fit into row {}",
+                        path, len, first_col, row
+                    )));
+                }
+                parse_pos(&format!("{}.row", path), (*row, *first_col))?;
+                set.add(RadioGroup::new(
+                    *row,
+                    *first_col,
+                    *len,
+                    color("on_color", on_color)?,
+                    color("off_color", off_color)?,
+                ))
+            }
+            WidgetConfig::Fader {
+                orientation,
+                index,
+                on_color,
+                off_color,
+                ..
+            } => {
+                if *index > 8 {
+                    return Err(MidiInterfaceError::Invalid(format!(
+                        "{}.index: {} out of range (0 to 8)",
+                        path, index
+                    )));
+                }
+                set.add(Fader::new(
+                    *orientation,
+                    *index,
+                    color("on_color", on_color)?,
+                    color("off_color", off_color)?,
+                ))
+            }
+            WidgetConfig::XyPad {
+                from,
+                to,
+                cursor_color,
+                cross_color,
+                off_color,
+                ..
+            } => {
+                let origin = parse_pos(&format!("{}.from", path), *from)?;
+                let end = parse_pos(&format!("{}.to", path), *to)?;
+                if end.row < origin.row || end.col < origin.col || end.row > 7 {
+                    return Err(MidiInterfaceError::Invalid(format!(
+                        "{}.to: ({}, {}) is not below and right of ({}, {}) on the grid",
+                        path, end.row, end.col, origin.row, origin.col
+                    )));
+                }
+                set.add(XyPad::new(
+                    origin,
+                    end.col - origin.col + 1,
+                    end.row - origin.row + 1,
+                    color("cursor_color", cursor_color)?,
+                    color("cross_color", cross_color)?,
+                    color("off_color", off_color)?,
+                ))
+            }
+        };
+        Ok(widget)
+    }
+}
+
+impl ActionConfig {
+    fn build(
+        &self,
+        path: &str,
+    ) -> Result<(MatPos, MidiAction, Option<LedFeedback>), MidiInterfaceError> {
+        let (pos, action, on_color, off_color) = match self {
+            ActionConfig::Note {
+                pos,
+                channel,
+                note,
+                velocity,
+                on_color,
+                off_color,
+            } => (
+                pos,
+                MidiAction::Note {
+                    channel: *channel,
+                    note: *note,
+                    velocity: *velocity,
+                },
+                on_color,
+                off_color,
+            ),
+            ActionConfig::Cc {
+                pos,
+                channel,
+                controller,
+                toggle,
+                on,
+                off,
+                on_color,
+                off_color,
+            } => {
+                let mode = if *toggle {
+                    CcMode::Toggle { on: *on, off: *off }
+                } else {
+                    CcMode::Momentary { on: *on, off: *off }
+                };
+                (
+                    pos,
+                    MidiAction::Cc {
+                        channel: *channel,
+                        controller: *controller,
+                        mode,
+                    },
+                    on_color,
+                    off_color,
+                )
+            }
+            ActionConfig::ProgramChange {
+                pos,
+                channel,
+                program,
+                on_color,
+                off_color,
+            } => (
+                pos,
+                MidiAction::ProgramChange {
+                    channel: *channel,
+                    program: *program,
+                },
+                on_color,
+                off_color,
+            ),
+        };
+
+        let pos = parse_pos(&format!("{}.pos", path), *pos)?;
+        let feedback = parse_feedback(path, on_color, off_color)?;
+        Ok((pos, action, feedback))
+    }
+}
+
+impl Config {
+    /// Parse and validate a config in TOML format
+    /// Returns Error, if the config is malformed or invalid
+    pub fn from_toml(content: &str) -> Result<Config, MidiInterfaceError> {
+        let config: Config =
+            toml::from_str(content).map_err(|e| MidiInterfaceError::Invalid(e.to_string()))?;
+        config.validate()?;
+        Ok(config)
+    }
+
+    /// Parse and validate a config in JSON format
+    /// Returns Error, if the config is malformed or invalid
+    pub fn from_json(content: &str) -> Result<Config, MidiInterfaceError> {
+        let config: Config = serde_json::from_str(content)
+            .map_err(|e| MidiInterfaceError::Invalid(e.to_string()))?;
+        config.validate()?;
+        Ok(config)
+    }
+
+    /// Load a config file, files ending with .json are read as JSON, all others as TOML
+    /// Returns Error, if the file can not be read or the config is malformed or invalid
+    pub fn load(path: impl AsRef<Path>) -> Result<Config, MidiInterfaceError> {
+        let path = path.as_ref();
+        let name = path.display().to_string();
+        let content = std::fs::read_to_string(path)
+            .map_err(|e| MidiInterfaceError::Invalid(format!("{}: {}", name, e)))?;
+
+        let res = match path.extension().and_then(|ext| ext.to_str()) {
+            Some("json") => Config::from_json(&content),
+            _ => Config::from_toml(&content),
+        };
+        res.map_err(|e| at(&name, e))
+    }
+
+    /// Check the config for values the Launchpad does not support
+    /// Returns Error naming the key or position at fault
+    pub fn validate(&self) -> Result<(), MidiInterfaceError> {
+        self.build_pages().map(|_| ())
+    }
+
+    /// Build the pages described by the config
+    fn build_pages(&self) -> Result<Vec<ConfigPage>, MidiInterfaceError> {
+        if self.pages.len() > MAX_PAGES {
+            return Err(MidiInterfaceError::Invalid(format!(
+                "pages: no more than {} pages possible",
+                MAX_PAGES
+            )));
+        }
+        parse_color("selected_color", &self.selected_color)?;
+        parse_color("page_color", &self.page_color)?;
+
+        // navigation buttons are taken by the page manager
+        let nav_buttons: Vec<MatPos> = (0..self.pages.len())
+            .map(|page| self.navigation.button(page))
+            .collect();
+
+        self.pages
+            .iter()
+            .enumerate()
+            .map(|(i, page)| ConfigPage::build(page, &format!("pages[{}]", i), &nav_buttons))
+            .collect()
+    }
+
+    /// Build the pages with the midi output named in the config
+    /// Returns Error, if the config is invalid or the output can not be opened
+    pub fn build<'a, M: MidiInterface<'a>>(
+        &self,
+        ctx: &'a M,
+    ) -> Result<Setup<M::MidiOutput>, MidiInterfaceError> {
+        let output = match &self.midi_output {
+            Some(name) => Some(
+                ctx.get_output(Identifier::from(name.as_str()))
+                    .map_err(|e| at("midi_output", e))?,
+            ),
+            None => None,
+        };
+        Setup::new(self, output)
+    }
+}
+
+/// Output collecting the messages of the midi actions of a page
+#[derive(Default)]
+struct MessageBuffer {
+    msgs: Vec<LaunchMessage>,
+}
+impl Output for MessageBuffer {
+    fn write_message(&mut self, msg: LaunchMessage) -> Result<(), MidiInterfaceError> {
+        self.msgs.push(msg);
+        Ok(())
+    }
+
+    fn write_messages(&mut self, msgs: Vec<LaunchMessage>) -> Result<(), MidiInterfaceError> {
+        self.msgs.extend(msgs);
+        Ok(())
+    }
+}
+
+/// Event of a page built from a config
+#[derive(Debug, Clone)]
+pub enum ConfigEvent {
+    /// value change of a widget (with the name given in the config)
+    Widget {
+        name: Option<String>,
+        event: WidgetEvent,
+    },
+    /// message of a midi action (sent to the midi output by Setup)
+    Midi(LaunchMessage),
+}
+
+/// Page built from a config
+struct ConfigPage {
+    lights: Vec<(MatPos, Color)>,
+    widgets: WidgetSet,
+    widget_names: Vec<Option<String>>,
+    actions: MidiMapper<MessageBuffer>,
+}
+impl ConfigPage {
+    fn build(
+        config: &PageConfig,
+        path: &str,
+        nav_buttons: &[MatPos],
+    ) -> Result<ConfigPage, MidiInterfaceError> {
+        let mut page = ConfigPage {
+            lights: Vec::new(),
+            widgets: WidgetSet::new(),
+            widget_names: Vec::new(),
+            actions: MidiMapper::new(MessageBuffer::default()),
+        };
+        // buttons taken by widgets and actions, with the key taking them
+        let mut taken: Vec<(MatPos, String)> = nav_buttons
+            .iter()
+            .map(|pos| (*pos, "navigation".to_string()))
+            .collect();
+        let mut take = |pos: MatPos, key: &str| -> Result<(), MidiInterfaceError> {
+            if let Some((_, other)) = taken.iter().find(|(p, _)| *p == pos) {
+                return Err(MidiInterfaceError::Invalid(format!(
+                    "{}: ({}, {}) is already taken by {}",
+                    key, pos.row, pos.col, other
+                )));
+            }
+            taken.push((pos, key.to_string()));
+            Ok(())
+        };
+
+        for (i, region) in config.regions.iter().enumerate() {
+            let key = format!("{}.regions[{}]", path, i);
+            let color = parse_color(&format!("{}.color", key), &region.color)?;
+            let from = parse_pos(&format!("{}.from", key), region.from)?;
+            let to = match region.to {
+                Some(to) => parse_pos(&format!("{}.to", key), to)?,
+                None => from,
+            };
+            if to.row < from.row || to.col < from.col {
+                return Err(MidiInterfaceError::Invalid(format!(
+                    "{}.to: ({}, {}) is not below and right of ({}, {})",
+                    key, to.row, to.col, from.row, from.col
+                )));
+            }
+            // every position covered has to be a button, not only the corners
+            for row in from.row..=to.row {
+                for col in from.col..=to.col {
+                    let pos = MatPos::try_new(row, col).map_err(|e| at(&key, e))?;
+                    page.lights.push((pos, color));
+                }
+            }
+        }
+
+        for (i, widget) in config.widgets.iter().enumerate() {
+            let key = format!("{}.widgets[{}]", path, i);
+            let index = widget.add_to(&key, &mut page.widgets)?;
+            if let Some(built) = page.widgets.get(index) {
+                for (pos, _) in built.render() {
+                    take(pos, &key)?;
+                }
+            }
+            page.widget_names.push(widget.name());
+        }
+
+        for (i, action) in config.actions.iter().enumerate() {
+            let key = format!("{}.actions[{}]", path, i);
+            let (pos, action, feedback) = action.build(&key)?;
+            take(pos, &format!("{}.pos", key))?;
+            page.actions
+                .map(pos, action, feedback)
+                .map_err(|e| at(&key, e))?;
+        }
+
+        Ok(page)
+    }
+}
+impl Page for ConfigPage {
+    type Event = ConfigEvent;
+
+    fn handle(&mut self, event: PadEvent) -> Vec<ConfigEvent> {
+        if let Some(changed) = WidgetSet::handle(&mut self.widgets, event) {
+            return vec![ConfigEvent::Widget {
+                name: self.widget_names[changed.widget].clone(),
+                event: changed,
+            }];
+        }
+
+        // writing to the message buffer can not fail
+        let _ = self.actions.handle(event);
+        std::mem::take(&mut self.actions.output().msgs)
+            .into_iter()
+            .map(ConfigEvent::Midi)
+            .collect()
+    }
+
+    fn render(&self) -> Vec<(MatPos, Color)> {
+        let mut res = self.lights.clone();
+        res.extend(Page::render(&self.widgets));
+        res.extend(self.actions.render());
+        res
+    }
+}
+
+/// Pages built from a config together with the midi output of the actions
+pub struct Setup<O: Output> {
+    pub pages: PageManager<ConfigEvent>,
+    output: Option<O>,
+}
+impl<O: Output> Setup<O> {
+    /// Build the pages of a config, midi actions are sent to the supplied output
+    /// (or only reported, if there is none)
+    /// Returns Error, if the config is invalid
+    pub fn new(config: &Config, output: Option<O>) -> Result<Setup<O>, MidiInterfaceError> {
+        Ok(Setup {
+            pages: Setup::<O>::build_pages(config)?,
+            output,
+        })
+    }
+
+    fn build_pages(config: &Config) -> Result<PageManager<ConfigEvent>, MidiInterfaceError> {
+        let mut pages = PageManager::new(
+            config.navigation,
+            parse_color("selected_color", &config.selected_color)?,
+            parse_color("page_color", &config.page_color)?,
+        );
+        for page in config.build_pages()? {
+            pages.add(page)?;
+        }
+        Ok(pages)
+    }
+
+    /// Output the midi actions are sent to
+    pub fn output(&mut self) -> Option<&mut O> {
+        self.output.as_mut()
+    }
+
+    /// Replace the pages by the pages of another config (e.g. after the file changed),
+    /// the page shown stays selected if it still exists.
+    /// The midi output is kept, the setup stays unchanged if the config is invalid
+    /// Returns Error, if the config is invalid
+    pub fn reload(&mut self, config: &Config) -> Result<(), MidiInterfaceError> {
+        let mut pages = Setup::<O>::build_pages(config)?;
+        let _ = pages.select(self.pages.current());
+        self.pages = pages;
+        Ok(())
+    }
+
+    /// Draw the page shown completely
+    /// Returns Error, if action fails
+    pub fn draw<I: Input, L: Output>(
+        &mut self,
+        dev: &mut LaunchDevice<I, L>,
+    ) -> Result<(), MidiInterfaceError> {
+        self.pages.redraw(dev)
+    }
+
+    /// Read up to n messages from the Launchpad, handle them, send the midi messages
+    /// of the actions, update the lights and return the resulting events
+    /// Returns Error, if reading, sending or drawing fails
+    pub fn process<I: Input, L: Output>(
+        &mut self,
+        dev: &mut LaunchDevice<I, L>,
+        n: usize,
+    ) -> Result<Vec<PageEvent<ConfigEvent>>, MidiInterfaceError> {
+        let events = self.pages.process(dev, n)?;
+        if let Some(out) = self.output.as_mut() {
+            for event in events.iter() {
+                if let PageEvent::Page {
+                    event: ConfigEvent::Midi(msg),
+                    ..
+                } = event
+                {
+                    out.write_message(msg.clone())?;
+                }
+            }
+        }
+        Ok(events)
+    }
+}
+
+/// Watches a config file for changes (by its modification time)
+pub struct ConfigWatcher {
+    path: PathBuf,
+    modified: Option<SystemTime>,
+}
+impl ConfigWatcher {
+    /// Watch a config file, changes after this call are reported
+    pub fn new(path: impl AsRef<Path>) -> ConfigWatcher {
+        let path = path.as_ref().to_path_buf();
+        let modified = ConfigWatcher::modified(&path);
+        ConfigWatcher { path, modified }
+    }
+
+    fn modified(path: &Path) -> Option<SystemTime> {
+        std::fs::metadata(path).and_then(|m| m.modified()).ok()
+    }
+
+    pub fn path(&self) -> &Path {
+        &self.path
+    }
+
+    /// Load the config if the file has changed since the last check, None if unchanged.
+    /// A broken file is reported once, the next change is loaded again
+    /// Returns Error, if the changed file can not be read or is invalid
+    pub fn check(&mut self) -> Result<Option<Config>, MidiInterfaceError> {
+        let modified = ConfigWatcher::modified(&self.path);
+        if modified == self.modified {
+            return Ok(None);
+        }
+        self.modified = modified;
+        Config::load(&self.path).map(Some)
+    }
+}
@@ -0,0 +1,281 @@
+/* Copyright (C) 2023 Lucas Haupt
+
+This program is distributed under the terms of the 
+GNU Lesser General Public License v3.0, 
+see COPYING.LESSER file for license information
+*/
+
+//! # Device State
+//!
+//! Shadow copy of the state of a Launchpad, built from the messages sent to it.
+//! The Launchpad can not be queried for its state, so the state has to be tracked
+//! on the sending side to be able to restore it (e.g. after a reconnect or when
+//! switching between applications).
+//! With the `serde` feature enabled the state can be serialized.
+
+use crate::midilib::LaunchMessage;
+use crate::utils::MessageType;
+use crate::{Color, MatPos};
+
+/// Number of rows of tracked lights (8 rows of the matrix plus the control button row)
+const STATE_ROWS: usize = 9;
+
+/// Number of columns of tracked lights (8 square buttons plus the scene button column)
+const STATE_COLS: usize = 9;
+
+/// Grid mode the Launchpad starts in (XY mode)
+const DEFAULT_GRID_MODE: u8 = 0x01;
+
+/// Velocity flag: write the color to the other buffer as well
+const FLAG_COPY: u8 = 0x04;
+
+/// Velocity flag: clear the other buffer's copy of the light
+const FLAG_CLEAR: u8 = 0x08;
+
+/// Buffer setting bit: buffer 1 is displayed
+const SETTING_DISPLAY: u8 = 0x01;
+
+/// Buffer setting bit: buffer 1 is updated
+const SETTING_UPDATE: u8 = 0x04;
+
+/// Buffer setting bit: the displayed buffer is flipped continually (flashing)
+const SETTING_FLASH: u8 = 0x08;
+
+/// Buffer setting bit: copy the displayed buffer to the updated buffer
+const SETTING_COPY: u8 = 0x10;
+
+/// Status byte of a rapid update message (two lights per message)
+pub(crate) const RAPID_UPDATE: u8 = 0x92;
+
+/// Number of lights written by a complete sequence of rapid update messages
+pub(crate) const RAPID_LEDS: usize = 80;
+
+/// Position of the light set by the rapid update at an index of the sequence:
+/// the 8x8 grid row by row, then the scene buttons from top to bottom,
+/// then the control buttons from left to right
+pub(crate) fn rapid_position(index: usize) -> MatPos {
+    let index = (index % RAPID_LEDS) as u8;
+    match index {
+        0..=63 => MatPos::new(index / 8, index % 8),
+        64..=71 => MatPos::new(index - 64, 8),
+        _ => MatPos::new(8, index - 72),
+    }
+}
+
+/// Lights of a single buffer, row 8 being the control button row
+type LedBuffer = [[u8; STATE_COLS]; STATE_ROWS];
+
+/// Snapshot of the state of a Launchpad: the contents of both buffers,
+/// grid mode, buffer mode (including flashing) and duty cycle
+#[derive(Debug, Clone, PartialEq, Eq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub struct DeviceState {
+    /// velocity bytes (without flags) of all lights in buffer 0 and buffer 1
+    buffers: [LedBuffer; 2],
+    /// grid mode byte (see GridMode)
+    grid_mode: u8,
+    /// display, update and flash bits of the buffer setting (see BufferSetting)
+    buffer_setting: u8,
+    /// data1 and data2 of the last duty cycle message, None if never set
+    duty_cycle: Option<(u8, u8)>,
+    /// index of the next light set by a rapid update (see rapid_position)
+    #[cfg_attr(feature = "serde", serde(skip))]
+    rapid_cursor: usize,
+}
+impl Default for DeviceState {
+    fn default() -> Self {
+        DeviceState {
+            buffers: [[[0; STATE_COLS]; STATE_ROWS]; 2],
+            grid_mode: DEFAULT_GRID_MODE,
+            buffer_setting: 0,
+            duty_cycle: None,
+            rapid_cursor: 0,
+        }
+    }
+}
+impl DeviceState {
+    /// Color of the light at a position (row 8 being the control button row) in
+    /// the displayed buffer, None if the position is not on the Launchpad
+    pub fn color(&self, pos: MatPos) -> Option<Color> {
+        self.buffer_color(self.display_buffer(), pos)
+    }
+
+    /// Color of the light at a position in the supplied buffer (0 or 1),
+    /// None if the buffer or position does not exist
+    pub fn buffer_color(&self, buffer: usize, pos: MatPos) -> Option<Color> {
+        let row = self.buffers.get(buffer)?.get(pos.row as usize)?;
+        let vel = row.get(pos.col as usize)?;
+        Some(Color::from(*vel))
+    }
+
+    /// Grid mode byte as sent to the Launchpad (see GridMode)
+    pub fn grid_mode(&self) -> u8 {
+        self.grid_mode
+    }
+
+    /// Display, update and flash bits of the buffer setting sent to the Launchpad (see BufferSetting)
+    pub fn buffer_setting(&self) -> u8 {
+        self.buffer_setting
+    }
+
+    /// Index of the displayed buffer
+    pub fn display_buffer(&self) -> usize {
+        (self.buffer_setting & SETTING_DISPLAY) as usize
+    }
+
+    /// Index of the buffer light messages are written to
+    pub fn update_buffer(&self) -> usize {
+        ((self.buffer_setting & SETTING_UPDATE) >> 2) as usize
+    }
+
+    /// Return if the Launchpad flips its buffers continually
+    pub fn is_flashing(&self) -> bool {
+        self.buffer_setting & SETTING_FLASH != 0
+    }
+
+    /// data1 and data2 of the duty cycle message last sent, None if never set
+    pub fn duty_cycle(&self) -> Option<(u8, u8)> {
+        self.duty_cycle
+    }
+
+    /// Index of the next light set by a rapid update (see rapid_position)
+    pub(crate) fn rapid_cursor(&self) -> usize {
+        self.rapid_cursor
+    }
+
+    /// Write a velocity byte to the update buffer, applying its copy and clear flags
+    fn set_led(&mut self, row: usize, col: usize, vel: u8) {
+        let update = self.update_buffer();
+        let other = 1 - update;
+
+        self.buffers[update][row][col] = vel & 0x33;
+        if vel & FLAG_COPY != 0 {
+            self.buffers[other][row][col] = vel & 0x33;
+        } else if vel & FLAG_CLEAR != 0 {
+            self.buffers[other][row][col] = 0;
+        }
+    }
+
+    /// Update the state according to a message sent to the Launchpad.
+    /// Lights are tracked by their XY mode keys, light messages sent
+    /// in drum rack mode are not tracked. The position of rapid updates
+    /// starts over with every grid mode message
+    pub(crate) fn record(&mut self, msg: &LaunchMessage) {
+        if msg.status == RAPID_UPDATE {
+            for vel in [msg.data1, msg.data2] {
+                let pos = rapid_position(self.rapid_cursor);
+                self.set_led(pos.row as usize, pos.col as usize, vel);
+                self.rapid_cursor = (self.rapid_cursor + 1) % RAPID_LEDS;
+            }
+        } else if msg.status == MessageType::On as u8 || msg.status == MessageType::Off as u8 {
+            if self.grid_mode != DEFAULT_GRID_MODE {
+                return;
+            }
+
+            let pos = MatPos::from(msg.clone());
+            let mut vel = msg.data2;
+            if msg.status == MessageType::Off as u8 {
+                vel &= FLAG_COPY | FLAG_CLEAR;
+            }
+            if (pos.row as usize) < STATE_ROWS - 1 && (pos.col as usize) < STATE_COLS {
+                self.set_led(pos.row as usize, pos.col as usize, vel);
+            }
+        } else if msg.status == MessageType::Ctl as u8 {
+            match (msg.data1, msg.data2) {
+                (0x00, 0x00) => *self = DeviceState::default(),
+                // the LED test resets all other data as well and lights all LEDs
+                // amber (red and green at 1, 2 or 3) in both buffers
+                (0x00, 0x7D..=0x7F) => {
+                    let level = msg.data2 - 0x7C;
+                    *self = DeviceState::default();
+                    self.buffers = [[[level << 4 | level; STATE_COLS]; STATE_ROWS]; 2];
+                    self.buffers[0][STATE_ROWS - 1][STATE_COLS - 1] = 0;
+                    self.buffers[1][STATE_ROWS - 1][STATE_COLS - 1] = 0;
+                }
+                (0x00, 0x01..=0x02) => {
+                    self.grid_mode = msg.data2;
+                    self.rapid_cursor = 0;
+                }
+                (0x00, 0x20..=0x3F) => {
+                    self.buffer_setting =
+                        msg.data2 & (SETTING_DISPLAY | SETTING_UPDATE | SETTING_FLASH);
+                    if msg.data2 & SETTING_COPY != 0 {
+                        self.buffers[self.update_buffer()] = self.buffers[self.display_buffer()];
+                    }
+                }
+                (0x1E..=0x1F, _) => self.duty_cycle = Some((msg.data1, msg.data2)),
+                (0x68..=0x6F, _) => {
+                    self.set_led(STATE_ROWS - 1, (msg.data1 - 0x68) as usize, msg.data2)
+                }
+                _ => {}
+            }
+        }
+    }
+
+    /// Messages bringing a Launchpad into this state
+    pub(crate) fn messages(&self) -> Vec<LaunchMessage> {
+        let mut msgs: Vec<LaunchMessage> = Vec::with_capacity(2 * STATE_ROWS * STATE_COLS + 6);
+
+        // reset leaves the Launchpad in XY mode with single buffering, all lights off
+        msgs.push(LaunchMessage {
+            status: MessageType::Ctl as u8,
+            data1: 0x00,
+            data2: 0x00,
+        });
+
+        // fill each buffer while it is the update buffer, writing without flags
+        for (buffer, leds) in self.buffers.iter().enumerate() {
+            msgs.push(LaunchMessage {
+                status: MessageType::Ctl as u8,
+                data1: 0x00,
+                data2: 0x20 | (buffer as u8 * SETTING_UPDATE),
+            });
+
+            for (row, cols) in leds.iter().enumerate().take(STATE_ROWS - 1) {
+                for (col, vel) in cols.iter().enumerate() {
+                    if *vel != 0 {
+                        msgs.push(LaunchMessage {
+                            status: MessageType::On as u8,
+                            data1: (0x10 * row + col) as u8,
+                            data2: *vel,
+                        });
+                    }
+                }
+            }
+
+            for (col, vel) in leds[STATE_ROWS - 1].iter().enumerate().take(8) {
+                if *vel != 0 {
+                    msgs.push(LaunchMessage {
+                        status: MessageType::Ctl as u8,
+                        data1: 0x68 + col as u8,
+                        data2: *vel,
+                    });
+                }
+            }
+        }
+
+        if self.grid_mode != DEFAULT_GRID_MODE {
+            msgs.push(LaunchMessage {
+                status: MessageType::Ctl as u8,
+                data1: 0x00,
+                data2: self.grid_mode,
+            });
+        }
+
+        if let Some((data1, data2)) = self.duty_cycle {
+            msgs.push(LaunchMessage {
+                status: MessageType::Ctl as u8,
+                data1,
+                data2,
+            });
+        }
+
+        msgs.push(LaunchMessage {
+            status: MessageType::Ctl as u8,
+            data1: 0x00,
+            data2: 0x20 | self.buffer_setting,
+        });
+
+        msgs
+    }
+}
@@ -0,0 +1,460 @@
+/* Copyright (C) 2023 Lucas Haupt
+
+This program is distributed under the terms of the 
+GNU Lesser General Public License v3.0, 
+see COPYING.LESSER file for license information
+*/
+
+//! # Drum pads
+//!
+//! Drum mode: the 8x8 grid plays drum notes, laid out in 4x4 blocks of 16 notes
+//! (like a drum rack) or as one 8x8 block. The pads of the Launchpad Mini are not
+//! velocity sensitive, so the velocity comes from the row of the pad or from an accent
+//! button held while hitting. Notes of a choke group cut each other off (e.g. open and
+//! closed hi-hat). Hit pads flash, either using the flashing mode of the Launchpad or
+//! lit for a while. Notes are sent to a midi output.
+
+use std::collections::HashMap;
+use std::time::{Duration, Instant};
+
+use crate::launch_device::LaunchDevice;
+use crate::mapping::{VELOCITY_FULL, VELOCITY_MEDIUM};
+use crate::midilib::{Input, LaunchMessage, MidiInterfaceError, Output};
+use crate::sequencer::{Clock, SystemClock};
+use crate::utils::{check_channel, light_message, note_message, release_notes, PadEvent};
+use crate::{Color, MatPos, GRID_SIZE, SCENE_COL};
+
+/// Buffer setting turning on the flashing mode (buffer 0 updated and displayed)
+const FLASH_MODE: u8 = 0x28;
+
+/// Velocity flags writing a light to both buffers (steady in flashing mode)
+const FLAGS_STEADY: u8 = 0x0C;
+
+/// Velocity flag writing a light to the updated buffer only (flashing in flashing mode)
+const FLAGS_FLASHING: u8 = 0x08;
+
+/// Arrangement of the drum notes, notes rise from left to right and from bottom to top
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, Default)]
+pub enum DrumBlocks {
+    /// four blocks of 4x4 pads with 16 notes each: lower left block first,
+    /// then lower right, upper left and upper right
+    #[default]
+    FourByFour,
+    /// one block of 64 notes, row by row
+    EightByEight,
+}
+
+/// Source of the velocity of a hit
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum DrumVelocity {
+    /// fixed velocity per row of the grid (from top to bottom)
+    PerRow([u8; 8]),
+    /// normal velocity, accent velocity while the accent button
+    /// (a scene or control button) is held
+    Accent {
+        normal: u8,
+        accent: u8,
+        button: MatPos,
+    },
+}
+impl DrumVelocity {
+    /// Check the velocities (1 to 127) and the accent button
+    /// Returns a message naming the bad value
+    fn validate(&self) -> Result<(), String> {
+        let velocities: Vec<u8> = match *self {
+            DrumVelocity::PerRow(rows) => rows.to_vec(),
+            DrumVelocity::Accent {
+                normal,
+                accent,
+                button,
+            } => {
+                if !button.is_valid() || button.is_grid() {
+                    return Err(format!(
+                        "accent button ({}, {}) is no scene or control button",
+                        button.row, button.col
+                    ));
+                }
+                vec![normal, accent]
+            }
+        };
+        match velocities.into_iter().find(|v| *v == 0 || *v > 0x7F) {
+            Some(v) => Err(format!("velocity {} out of range (1 to 127)", v)),
+            None => Ok(()),
+        }
+    }
+}
+
+/// Lighting of hit pads
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum HitFlash {
+    /// hits are not shown
+    Off,
+    /// the pad flashes for a while, using the flashing mode of the Launchpad
+    /// (turned on by draw and turned off again when switching to another lighting;
+    /// with double buffering the pad is lit steadily instead)
+    Hardware(Duration),
+    /// the pad is lit in the hit color for a while
+    Timed(Duration),
+}
+
+/// Drum pads on the 8x8 grid sending notes to a midi output
+pub struct DrumPads<O: Output, C: Clock = SystemClock> {
+    out_port: O,
+    clock: C,
+    blocks: DrumBlocks,
+    /// note of the lower left pad
+    base_note: u8,
+    /// midi channel (0 to 15)
+    channel: u8,
+    velocity: DrumVelocity,
+    pub flash: HitFlash,
+    /// colors of the pads, alternating between the 4x4 blocks
+    pub pad_colors: [Color; 2],
+    /// color of hit pads
+    pub hit_color: Color,
+    /// color of the accent button while held
+    pub accent_color: Color,
+    /// color of the accent button while released
+    pub accent_idle_color: Color,
+    /// choke group of each note in one
+    chokes: HashMap<u8, u8>,
+    /// pads held and the note each one started (choked notes are removed)
+    held: HashMap<MatPos, u8>,
+    accent_held: bool,
+    /// pads flashing and the time their flash ends
+    flashes: HashMap<MatPos, Instant>,
+    /// lights as drawn on the Launchpad (color and flashing), None if not drawn yet
+    shown: Option<HashMap<MatPos, (Color, bool)>>,
+    /// buffer setting before draw turned on the flashing mode, None if it did not
+    restore_setting: Option<u8>,
+}
+impl<O: Output, C: Clock> DrumPads<O, C> {
+    /// Create drum pads in 4x4 blocks starting with note 36 (bass drum in General MIDI)
+    /// on channel 9 (drums in General MIDI), the lowest scene button being the accent
+    /// button and hits lit for 150 ms
+    pub fn new(out_port: O, clock: C) -> DrumPads<O, C> {
+        DrumPads {
+            out_port,
+            clock,
+            blocks: DrumBlocks::default(),
+            base_note: 36,
+            channel: 9,
+            velocity: DrumVelocity::Accent {
+                normal: VELOCITY_MEDIUM,
+                accent: VELOCITY_FULL,
+                button: MatPos::new(GRID_SIZE - 1, SCENE_COL),
+            },
+            flash: HitFlash::Timed(Duration::from_millis(150)),
+            pad_colors: [Color::DimGreen, Color::DimYellow],
+            hit_color: Color::Red,
+            accent_color: Color::Orange,
+            accent_idle_color: Color::DimRed,
+            chokes: HashMap::new(),
+            held: HashMap::new(),
+            accent_held: false,
+            flashes: HashMap::new(),
+            shown: None,
+            restore_setting: None,
+        }
+    }
+
+    pub fn clock(&self) -> &C {
+        &self.clock
+    }
+
+    /// Output port the notes are sent to
+    pub fn output(&mut self) -> &mut O {
+        &mut self.out_port
+    }
+
+    pub fn blocks(&self) -> DrumBlocks {
+        self.blocks
+    }
+
+    pub fn set_blocks(&mut self, blocks: DrumBlocks) {
+        self.blocks = blocks;
+    }
+
+    /// Note of the lower left pad
+    pub fn base_note(&self) -> u8 {
+        self.base_note
+    }
+
+    /// Set the note of the lower left pad (pads beyond note 127 play nothing)
+    /// Returns Error, if it is not within 0 to 127
+    pub fn set_base_note(&mut self, note: u8) -> Result<(), MidiInterfaceError> {
+        if note > 0x7F {
+            return Err(MidiInterfaceError::Invalid(format!(
+                "note {} out of range (0 to 127)",
+                note
+            )));
+        }
+        self.base_note = note;
+        Ok(())
+    }
+
+    pub fn channel(&self) -> u8 {
+        self.channel
+    }
+
+    /// Set the midi channel the notes are sent on, notes held are released
+    /// on the previous channel first
+    /// Returns Error, if it is not within 0 to 15 or sending fails
+    pub fn set_channel(&mut self, channel: u8) -> Result<(), MidiInterfaceError> {
+        check_channel(channel)?;
+        self.release_all()?;
+        self.channel = channel;
+        Ok(())
+    }
+
+    pub fn velocity(&self) -> DrumVelocity {
+        self.velocity
+    }
+
+    /// Set the source of the velocity of hits
+    /// Returns Error, if a velocity is out of range or the accent button is a pad of the grid
+    pub fn set_velocity(&mut self, velocity: DrumVelocity) -> Result<(), MidiInterfaceError> {
+        velocity.validate().map_err(MidiInterfaceError::Invalid)?;
+        self.velocity = velocity;
+        self.accent_held = false;
+        Ok(())
+    }
+
+    /// Choke group of a note, None if it is in none
+    pub fn choke_group(&self, note: u8) -> Option<u8> {
+        self.chokes.get(&note).copied()
+    }
+
+    /// Put a note into a choke group (None removes it from its group):
+    /// hitting a note of a group ends the other notes of the group still held
+    pub fn set_choke_group(&mut self, note: u8, group: Option<u8>) {
+        match group {
+            Some(group) => self.chokes.insert(note, group),
+            None => self.chokes.remove(&note),
+        };
+    }
+
+    /// Note played by a pad of the grid, None for other buttons
+    /// and pads beyond note 127
+    pub fn note(&self, pos: MatPos) -> Option<u8> {
+        if !pos.is_grid() {
+            return None;
+        }
+        let row = GRID_SIZE - 1 - pos.row;
+        let index = match self.blocks {
+            DrumBlocks::EightByEight => row * 8 + pos.col,
+            DrumBlocks::FourByFour => {
+                let block = 2 * (row / 4) + pos.col / 4;
+                block * 16 + (row % 4) * 4 + pos.col % 4
+            }
+        };
+        Some(self.base_note as u16 + index as u16)
+            .filter(|n| *n <= 0x7F)
+            .map(|n| n as u8)
+    }
+
+    /// Velocity of a hit of a pad
+    fn hit_velocity(&self, pos: MatPos) -> u8 {
+        match self.velocity {
+            DrumVelocity::PerRow(rows) => rows[pos.row as usize],
+            DrumVelocity::Accent { normal, accent, .. } => {
+                if self.accent_held {
+                    accent
+                } else {
+                    normal
+                }
+            }
+        }
+    }
+
+    /// Send a note on or off message
+    fn send(&mut self, on: bool, note: u8, velocity: u8) -> Result<(), MidiInterfaceError> {
+        self.out_port
+            .write_message(note_message(self.channel, note, on, velocity))
+    }
+
+    /// Handle a button event: pads play their note while held, the accent button
+    /// raises the velocity of the hits while held
+    /// Returns Error, if sending fails
+    pub fn handle(&mut self, event: PadEvent) -> Result<(), MidiInterfaceError> {
+        let pos = event.pos;
+        if let DrumVelocity::Accent { button, .. } = self.velocity {
+            if pos == button {
+                self.accent_held = event.pressed;
+                return Ok(());
+            }
+        }
+
+        if !event.pressed {
+            if let Some(note) = self.held.remove(&pos) {
+                if !self.held.values().any(|n| *n == note) {
+                    self.send(false, note, 0)?;
+                }
+            }
+            return Ok(());
+        }
+
+        let note = match self.note(pos) {
+            Some(note) => note,
+            None => return Ok(()),
+        };
+
+        // end the other notes of the choke group
+        if let Some(group) = self.choke_group(note) {
+            let mut choked: Vec<u8> = self
+                .held
+                .values()
+                .copied()
+                .filter(|n| *n != note && self.choke_group(*n) == Some(group))
+                .collect();
+            choked.sort_unstable();
+            choked.dedup();
+            self.held.retain(|_, n| !choked.contains(n));
+            for n in choked {
+                self.send(false, n, 0)?;
+            }
+        }
+
+        let velocity = self.hit_velocity(pos);
+        self.send(true, note, velocity)?;
+        self.held.insert(pos, note);
+        match self.flash {
+            HitFlash::Hardware(duration) | HitFlash::Timed(duration) => {
+                self.flashes.insert(pos, self.clock.now() + duration);
+            }
+            HitFlash::Off => {}
+        }
+        Ok(())
+    }
+
+    /// Send note off for all notes held (e.g. before switching to another mode)
+    /// Returns Error, if sending fails
+    pub fn release_all(&mut self) -> Result<(), MidiInterfaceError> {
+        release_notes(&mut self.out_port, self.channel, &mut self.held)
+    }
+
+    /// Lights of the pads and the accent button with their flashing state
+    /// at the current time of the clock
+    fn lights(&self) -> HashMap<MatPos, (Color, bool)> {
+        let now = self.clock.now();
+        let mut res = HashMap::new();
+        for row in 0..GRID_SIZE {
+            for col in 0..GRID_SIZE {
+                let pos = MatPos::new(row, col);
+                if self.note(pos).is_none() {
+                    continue;
+                }
+                let flashing = self.flashes.get(&pos).is_some_and(|until| *until > now);
+                let light = match (flashing, self.flash) {
+                    (true, HitFlash::Hardware(_)) => (self.hit_color, true),
+                    (true, _) => (self.hit_color, false),
+                    (false, _) => {
+                        let block = match self.blocks {
+                            DrumBlocks::FourByFour => (row / 4 + col / 4) as usize % 2,
+                            DrumBlocks::EightByEight => 0,
+                        };
+                        (self.pad_colors[block], false)
+                    }
+                };
+                res.insert(pos, light);
+            }
+        }
+
+        if let DrumVelocity::Accent { button, .. } = self.velocity {
+            let color = if self.accent_held {
+                self.accent_color
+            } else {
+                self.accent_idle_color
+            };
+            res.insert(button, (color, false));
+        }
+        res
+    }
+
+    /// Lights of the pads (hit pads in the hit color) and the accent button
+    pub fn render(&self) -> Vec<(MatPos, Color)> {
+        self.lights()
+            .into_iter()
+            .map(|(pos, (color, _))| (pos, color))
+            .collect()
+    }
+
+    /// Update the lights of the Launchpad, only changed lights are sent (lights no longer
+    /// drawn are turned off).
+    /// With hardware flashes the flashing mode is turned on first (unless the Launchpad
+    /// is double buffered), the previous buffer setting is restored once the flashes
+    /// are switched to another lighting
+    /// Returns Error, if action fails
+    pub fn draw<I: Input, L: Output>(
+        &mut self,
+        dev: &mut LaunchDevice<I, L>,
+    ) -> Result<(), MidiInterfaceError> {
+        let now = self.clock.now();
+        self.flashes.retain(|_, until| *until > now);
+
+        let hardware = matches!(self.flash, HitFlash::Hardware(_)) && !dev.is_double_buffered();
+        if hardware && !dev.state().is_flashing() {
+            self.restore_setting = Some(dev.state().buffer_setting());
+            dev.send_ctl_msg(0x00, FLASH_MODE)?;
+            self.shown = None;
+        } else if !hardware {
+            if let Some(setting) = self.restore_setting.take() {
+                dev.send_ctl_msg(0x00, 0x20 | setting)?;
+                self.shown = None;
+            }
+        }
+
+        let lights = self.lights();
+        let mut changes: Vec<(MatPos, (Color, bool))> = lights
+            .iter()
+            .filter(|(pos, light)| match &self.shown {
+                Some(shown) => shown.get(pos) != Some(light),
+                None => true,
+            })
+            .map(|(pos, light)| (*pos, *light))
+            .collect();
+        // lights no longer drawn (e.g. a previous accent button) are turned off
+        if let Some(shown) = &self.shown {
+            changes.extend(
+                shown
+                    .keys()
+                    .filter(|pos| !lights.contains_key(pos))
+                    .map(|pos| (*pos, (Color::Black, false))),
+            );
+        }
+
+        let mut msgs: Vec<LaunchMessage> = Vec::new();
+        for (pos, (color, flashing)) in changes {
+            let mut msg = light_message(pos, color);
+            if hardware {
+                msg.data2 |= if flashing {
+                    FLAGS_FLASHING
+                } else {
+                    FLAGS_STEADY
+                };
+            }
+            msgs.push(msg);
+        }
+        if !msgs.is_empty() {
+            dev.send_messages(msgs)?;
+        }
+        self.shown = Some(lights);
+        Ok(())
+    }
+
+    /// Read up to n messages from the Launchpad, play the hits and update the lights.
+    /// Call regularly, so flashes end in time
+    /// Returns Error, if reading, sending or drawing fails
+    pub fn process<I: Input, L: Output>(
+        &mut self,
+        dev: &mut LaunchDevice<I, L>,
+        n: usize,
+    ) -> Result<(), MidiInterfaceError> {
+        if dev.poll()? {
+            for event in dev.read_events(n)? {
+                self.handle(event)?;
+            }
+        }
+        self.draw(dev)
+    }
+}
@@ -0,0 +1,197 @@
+/* Copyright (C) 2023 Lucas Haupt
+
+This program is distributed under the terms of the 
+GNU Lesser General Public License v3.0, 
+see COPYING.LESSER file for license information
+*/
+
+//! # Feedback
+//!
+//! LED feedback from incoming midi (e.g. a DAW reporting mute states or volumes):
+//! a feedback router listens on a separate input and sets the lights of the Launchpad
+//! according to rules, like "note 36 with velocity > 0 lights (7, 0) green"
+//! or "CC 20 sets the height of a fader in column 0".
+
+use crate::launch_device::LaunchDevice;
+use crate::midilib::{Input, LaunchMessage, MidiInterfaceError, Output};
+use crate::utils::light_message;
+use crate::widgets::{Fader, FaderOrientation, Widget, WidgetValue, FADER_LEN};
+use crate::{Color, MatPos};
+
+/// Incoming message a rule reacts to
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum FeedbackSource {
+    /// note on/off of a note, the value being the velocity (0 for note off)
+    Note { channel: Option<u8>, note: u8 },
+    /// control change of a controller, the value being the controller value
+    Cc { channel: Option<u8>, controller: u8 },
+}
+impl FeedbackSource {
+    /// Value of a message matching the source, None if it does not match
+    fn value(&self, msg: &LaunchMessage) -> Option<u8> {
+        let kind = msg.status & 0xF0;
+        let channel = msg.status & 0x0F;
+        let (source_channel, matches, value) = match *self {
+            FeedbackSource::Note {
+                channel: source_channel,
+                note,
+            } => match kind {
+                0x90 => (source_channel, msg.data1 == note, msg.data2),
+                0x80 => (source_channel, msg.data1 == note, 0),
+                _ => return None,
+            },
+            FeedbackSource::Cc {
+                channel: source_channel,
+                controller,
+            } => (
+                source_channel,
+                kind == 0xB0 && msg.data1 == controller,
+                msg.data2,
+            ),
+        };
+
+        match source_channel {
+            _ if !matches => None,
+            Some(c) if c != channel => None,
+            _ => Some(value),
+        }
+    }
+}
+
+/// Lights set by a rule
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum FeedbackTarget {
+    /// a button lit in the on color for values > 0, in the off color for 0
+    Light { pos: MatPos, on: Color, off: Color },
+    /// a button lit in the color of the value taken as velocity byte (see Color)
+    Velocity { pos: MatPos },
+    /// a fader (see widgets.rs) with a height proportional to the value (127 lighting all pads)
+    Fader {
+        orientation: FaderOrientation,
+        index: u8,
+        on: Color,
+        off: Color,
+    },
+}
+impl FeedbackTarget {
+    /// Lights for a value of the source
+    fn render(&self, value: u8) -> Vec<(MatPos, Color)> {
+        match *self {
+            FeedbackTarget::Light { pos, on, off } => {
+                vec![(pos, if value > 0 { on } else { off })]
+            }
+            FeedbackTarget::Velocity { pos } => vec![(pos, Color::from(value))],
+            FeedbackTarget::Fader {
+                orientation,
+                index,
+                on,
+                off,
+            } => {
+                let mut fader = Fader::new(orientation, index, on, off);
+                let level = (value.min(0x7F) as u16 * FADER_LEN as u16 + 63) / 127;
+                // the level is at most FADER_LEN, which the fader accepts
+                let _ = fader.set_value(WidgetValue::Level(level as u8));
+                fader.render()
+            }
+        }
+    }
+}
+
+/// Rule mapping an incoming message to lights
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub struct FeedbackRule {
+    pub source: FeedbackSource,
+    pub target: FeedbackTarget,
+}
+impl FeedbackRule {
+    pub fn new(source: FeedbackSource, target: FeedbackTarget) -> FeedbackRule {
+        FeedbackRule { source, target }
+    }
+}
+
+/// Sets the lights of a Launchpad according to the messages received on another input
+pub struct FeedbackRouter<I: Input> {
+    in_port: I,
+    rules: Vec<FeedbackRule>,
+}
+impl<I: Input> FeedbackRouter<I> {
+    /// Create a router listening on the supplied input port (e.g. a virtual port of a DAW)
+    pub fn new(in_port: I) -> FeedbackRouter<I> {
+        FeedbackRouter {
+            in_port,
+            rules: Vec::new(),
+        }
+    }
+
+    /// Add a rule, all rules matching a message are applied in the order they were added
+    /// Returns Error, if the rule has a channel > 15 or a position not on the Launchpad
+    pub fn add_rule(&mut self, rule: FeedbackRule) -> Result<(), MidiInterfaceError> {
+        let channel = match rule.source {
+            FeedbackSource::Note { channel, .. } | FeedbackSource::Cc { channel, .. } => channel,
+        };
+        if let Some(c) = channel.filter(|c| *c > 15) {
+            return Err(MidiInterfaceError::Invalid(format!(
+                "channel {} out of range (0 to 15)",
+                c
+            )));
+        }
+
+        let valid = match rule.target {
+            FeedbackTarget::Light { pos, .. } | FeedbackTarget::Velocity { pos } => {
+                pos.is_valid()
+            }
+            FeedbackTarget::Fader { index, .. } => index < 9,
+        };
+        if !valid {
+            return Err(MidiInterfaceError::Invalid(format!(
+                "{:?} is not on the Launchpad",
+                rule.target
+            )));
+        }
+
+        self.rules.push(rule);
+        Ok(())
+    }
+
+    pub fn rules(&self) -> &[FeedbackRule] {
+        &self.rules
+    }
+
+    /// Remove all rules
+    pub fn clear_rules(&mut self) {
+        self.rules.clear();
+    }
+
+    /// Lights to set for a received message
+    pub fn handle(&self, msg: &LaunchMessage) -> Vec<(MatPos, Color)> {
+        self.rules
+            .iter()
+            .filter_map(|rule| rule.source.value(msg).map(|v| rule.target.render(v)))
+            .flatten()
+            .collect()
+    }
+
+    /// Read up to n messages from the input of the router and set the lights
+    /// of the Launchpad accordingly, returns the number of messages read
+    /// Returns Error, if reading or sending fails
+    pub fn process<DI: Input, O: Output>(
+        &mut self,
+        dev: &mut LaunchDevice<DI, O>,
+        n: usize,
+    ) -> Result<usize, MidiInterfaceError> {
+        if !self.in_port.poll()? {
+            return Ok(0);
+        }
+        let msgs = self.in_port.read_n(n)?.unwrap_or_default();
+
+        let lights: Vec<LaunchMessage> = msgs
+            .iter()
+            .flat_map(|msg| self.handle(msg))
+            .map(|(pos, color)| light_message(pos, color))
+            .collect();
+        if !lights.is_empty() {
+            dev.send_messages(lights)?;
+        }
+        Ok(msgs.len())
+    }
+}
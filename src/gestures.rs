@@ -0,0 +1,220 @@
+/* Copyright (C) 2023 Lucas Haupt
+
+This program is distributed under the terms of the 
+GNU Lesser General Public License v3.0, 
+see COPYING.LESSER file for license information
+*/
+
+//! # Gestures
+//!
+//! Gesture layer on top of the button presses and releases of a Launchpad.
+//! Recognizes long presses, double taps, presses while holding other buttons
+//! (e.g. shift + pad) and chords of buttons pressed together.
+//! Time is passed in explicitly (feed and tick), so recognition does not depend
+//! on when the events are read and can be tested without waiting.
+
+use std::collections::{HashMap, HashSet};
+use std::time::{Duration, Instant};
+
+use crate::launch_device::LaunchDevice;
+use crate::midilib::{Input, MidiInterfaceError, Output};
+use crate::utils::PadEvent;
+use crate::MatPos;
+
+/// Thresholds for recognizing gestures
+#[derive(Debug, Clone, Copy)]
+pub struct GestureConfig {
+    /// minimum time a button has to be held for a long press
+    pub long_press: Duration,
+    /// maximum time between the presses of a double tap
+    pub double_tap: Duration,
+    /// maximum time between the first and last press of a chord,
+    /// buttons held longer than this act as modifiers for further presses
+    pub chord_window: Duration,
+}
+impl Default for GestureConfig {
+    fn default() -> Self {
+        GestureConfig {
+            long_press: Duration::from_millis(500),
+            double_tap: Duration::from_millis(300),
+            chord_window: Duration::from_millis(50),
+        }
+    }
+}
+
+/// A recognized gesture
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Gesture {
+    /// a button has been held longer than the long press threshold (reported while still held)
+    LongPress { pos: MatPos, held: Duration },
+    /// a button has been tapped twice within the double tap threshold
+    DoubleTap { pos: MatPos, interval: Duration },
+    /// a button has been pressed while other buttons were held (e.g. shift + pad)
+    HoldPress {
+        held: HashSet<MatPos>,
+        pos: MatPos,
+        held_for: Duration,
+    },
+    /// several buttons have been pressed within the chord window
+    Chord {
+        pads: HashSet<MatPos>,
+        spread: Duration,
+    },
+}
+
+/// A button currently held down
+struct HeldPad {
+    since: Instant,
+    long_reported: bool,
+    used_as_modifier: bool,
+}
+
+/// Recognizes gestures from the button presses and releases fed into it
+pub struct GestureDetector {
+    config: GestureConfig,
+    held: HashMap<MatPos, HeldPad>,
+    last_tap: HashMap<MatPos, Instant>,
+    chord: Vec<(MatPos, Instant)>,
+}
+impl GestureDetector {
+    pub fn new(config: GestureConfig) -> GestureDetector {
+        GestureDetector {
+            config,
+            held: HashMap::new(),
+            last_tap: HashMap::new(),
+            chord: Vec::new(),
+        }
+    }
+
+    /// Buttons currently held down
+    pub fn held(&self) -> impl Iterator<Item = &MatPos> {
+        self.held.keys()
+    }
+
+    /// Feed a button press or release that happened at the supplied time,
+    /// returns the gestures completed by it
+    pub fn feed(&mut self, event: PadEvent, at: Instant) -> Vec<Gesture> {
+        let mut res = self.tick(at);
+        let pos = event.pos;
+
+        if !event.pressed {
+            if let Some(pad) = self.held.remove(&pos) {
+                if pad.long_reported || pad.used_as_modifier {
+                    self.last_tap.remove(&pos);
+                }
+            }
+            return res;
+        }
+
+        // buttons held longer than the chord window (and not part of the current chord) are modifiers
+        let modifiers: Vec<(MatPos, Instant)> = self
+            .held
+            .iter()
+            .filter(|(p, pad)| {
+                pad.since + self.config.chord_window <= at
+                    && !self.chord.iter().any(|(c, _)| c == *p)
+            })
+            .map(|(p, pad)| (*p, pad.since))
+            .collect();
+
+        if modifiers.is_empty() {
+            self.chord.push((pos, at));
+
+            match self.last_tap.remove(&pos) {
+                Some(last) if at - last <= self.config.double_tap => {
+                    res.push(Gesture::DoubleTap {
+                        pos,
+                        interval: at - last,
+                    });
+                }
+                _ => {
+                    self.last_tap.insert(pos, at);
+                }
+            }
+        } else {
+            let earliest = modifiers
+                .iter()
+                .map(|(_, since)| *since)
+                .min()
+                .unwrap_or(at);
+            for (p, _) in modifiers.iter() {
+                if let Some(pad) = self.held.get_mut(p) {
+                    pad.used_as_modifier = true;
+                }
+            }
+            res.push(Gesture::HoldPress {
+                held: modifiers.into_iter().map(|(p, _)| p).collect(),
+                pos,
+                held_for: at - earliest,
+            });
+        }
+
+        self.held.insert(
+            pos,
+            HeldPad {
+                since: at,
+                long_reported: false,
+                used_as_modifier: false,
+            },
+        );
+
+        res
+    }
+
+    /// Advance the time without a button event (to report long presses and chords
+    /// in time), returns the gestures completed up to the supplied time
+    pub fn tick(&mut self, now: Instant) -> Vec<Gesture> {
+        let mut res = Vec::new();
+
+        if let Some((_, first)) = self.chord.first().copied() {
+            if first + self.config.chord_window < now {
+                let chord = std::mem::take(&mut self.chord);
+                // a pad tapped twice within the window counts once
+                let pads: HashSet<MatPos> = chord.iter().map(|(p, _)| *p).collect();
+                if pads.len() > 1 {
+                    let last = chord.iter().map(|(_, t)| *t).max().unwrap_or(first);
+                    res.push(Gesture::Chord {
+                        spread: last - first,
+                        pads,
+                    });
+                }
+            }
+        }
+
+        for (pos, pad) in self.held.iter_mut() {
+            if pad.long_reported || pad.used_as_modifier {
+                continue;
+            }
+            let held = now.saturating_duration_since(pad.since);
+            if held >= self.config.long_press {
+                pad.long_reported = true;
+                res.push(Gesture::LongPress { pos: *pos, held });
+            }
+        }
+
+        // taps older than the double tap threshold can not be completed anymore
+        let double_tap = self.config.double_tap;
+        self.last_tap
+            .retain(|_, t| now.saturating_duration_since(*t) <= double_tap);
+
+        res
+    }
+
+    /// Read up to n messages from the Launchpad, feed them with the time they were received
+    /// and return the recognized gestures
+    /// Returns Error, if reading fails
+    pub fn process<I: Input, O: Output>(
+        &mut self,
+        dev: &LaunchDevice<I, O>,
+        n: usize,
+    ) -> Result<Vec<Gesture>, MidiInterfaceError> {
+        let mut res = Vec::new();
+        if dev.poll()? {
+            for (event, at) in dev.read_timed_events(n)? {
+                res.extend(self.feed(event, at));
+            }
+        }
+        res.extend(self.tick(Instant::now()));
+        Ok(res)
+    }
+}
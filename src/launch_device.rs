@@ -5,10 +5,12 @@ GNU Lesser General Public License v3.0,
 see COPYING.LESSER file for license information
 */
 
+use crate::device_state::{rapid_position, DeviceState, RAPID_UPDATE};
 use crate::Color;
 use crate::MatPos;
-use crate::utils::{BufferSetting, GridMode, Key, MessageType};
-use crate::midilib::{Input, LaunchMessage, MidiInterfaceError, Output};
+use crate::utils::{light_message, BufferSetting, GridMode, Key, LedTestBrightness, MessageType, Orientation, PadEvent};
+use crate::midilib::{Input, LaunchMessage, MidiInterfaceError, Output, TimedMessage};
+use std::time::Instant;
 use cartesian::*;
 
 /// Number of Scene Launch button column
@@ -32,9 +34,8 @@ const STATUS_CONTROL_BUTTON_ROW: [u8; 8] = [0x68, 0x69, 0x6A, 0x6B, 0x6C, 0x6D,
 
 /// This is the main struct for communicating with a LaunchpadMini
 pub struct LaunchDevice<I: Input, O: Output> {
-    in_port: I,
-    out_port: O,
-    buffer_setting: u8,
+    input: LaunchInput<I>,
+    output: LaunchOutput<O>,
 }
 impl<'a, I, O> LaunchDevice<I, O>
 where
@@ -49,12 +50,277 @@ where
         out_port: O,
     ) -> LaunchDevice<I, O> {
         LaunchDevice {
+            input: LaunchInput::new(in_port),
+            output: LaunchOutput::new(out_port),
+        }
+    }
+
+    /// Split the device into its input half (reading button events) and its output half
+    /// (lights, control messages and the tracked state), e.g. for a listener thread
+    /// and a render thread. The halves can be rejoined with join()
+    pub fn split(self) -> (LaunchInput<I>, LaunchOutput<O>) {
+        (self.input, self.output)
+    }
+
+    /// Rejoin the halves of a split device
+    pub fn join(input: LaunchInput<I>, output: LaunchOutput<O>) -> LaunchDevice<I, O> {
+        LaunchDevice { input, output }
+    }
+
+    /// Orientation of the Launchpad (see set_orientation)
+    pub fn orientation(&self) -> Orientation {
+        self.output.orientation()
+    }
+
+    /// Set the orientation of the Launchpad (rotation, mirroring and handling of the round
+    /// buttons): positions of lights sent and buttons read are the positions as seen
+    /// (XY mode layout). Lights already sent stay where they are until redrawn
+    /// (e.g. with restore(&capture()))
+    pub fn set_orientation(&mut self, orientation: Orientation) {
+        self.input.set_orientation(orientation);
+        self.output.set_orientation(orientation);
+    }
+
+    /// Return the last known state of the Launchpad (tracked from the messages sent to it)
+    pub fn state(&self) -> &DeviceState {
+        self.output.state()
+    }
+
+    /// Take a snapshot of the state of the Launchpad (both buffers, grid mode,
+    /// buffer mode, flashing and duty cycle) to restore it later
+    pub fn capture(&self) -> DeviceState {
+        self.output.capture()
+    }
+
+    /// Bring the Launchpad into a previously captured state (resets the Launchpad first)
+    /// Returns Error, if action fails
+    pub fn restore(&mut self, state: &DeviceState) -> Result<(), MidiInterfaceError> {
+        self.output.restore(state)
+    }
+
+    /// Input port of the Launchpad, messages read from it directly
+    /// are not transformed by the orientation
+    pub fn input(&self) -> &I {
+        self.input.input()
+    }
+
+    /// Output port of the Launchpad (e.g. a RateLimiter)
+    pub fn output(&self) -> &O {
+        self.output.output()
+    }
+
+    /// Access the output port of the Launchpad, messages written to it directly
+    /// are not tracked in the state
+    pub fn output_mut(&mut self) -> &mut O {
+        self.output.output_mut()
+    }
+
+    /// Send a note msg to the Launchpad, turning lights on and of (and return Error, if action fails)
+    pub fn send_note_msg(&mut self, on: bool, key: u8, vel: u8) -> Result<(), MidiInterfaceError> {
+        self.output.send_note_msg(on, key, vel)
+    }
+
+    /// Send messages to the Launchpad when their timestamps are due, blocking until the last
+    /// one is sent (see Output::write_messages_timed), the state is updated at once
+    /// Returns Error, if action fails
+    pub fn send_timed_messages(
+        &mut self,
+        msgs: Vec<TimedMessage>,
+    ) -> Result<(), MidiInterfaceError> {
+        self.output.send_timed_messages(msgs)
+    }
+
+    /// Send multiple messages to the Launchpad (and return an Error, if action fails)
+    pub fn send_messages(&mut self, msgs: Vec<LaunchMessage>) -> Result<(), MidiInterfaceError> {
+        self.output.send_messages(msgs)
+    }
+
+    /// Send a control message to the Launchpad (and return an Error, if action fails)
+    pub fn send_ctl_msg(&mut self, data1: u8, data2: u8) -> Result<(), MidiInterfaceError> {
+        self.output.send_ctl_msg(data1, data2)
+    }
+
+    /// Turn (almost) all lights on the Launchpad off, leave out control button row (first row with round buttons)
+    /// (return an Error, if action fails)
+    pub fn blackout(&mut self) -> Result<(), MidiInterfaceError> {
+        self.output.blackout()
+    }
+
+    /// Turn all lights on the Launchpad off
+    /// Returns Error, if action fails
+    pub fn full_blackout(&mut self) -> Result<(), MidiInterfaceError> {
+        self.output.full_blackout()
+    }
+
+    /// Set the color/light at a position on the Launchpad Matrix
+    /// Returns Error, if action fails
+    pub fn set_position(
+        &mut self,
+        row: u8,
+        col: u8,
+        color: Color,
+    ) -> Result<(), MidiInterfaceError> {
+        self.output.set_position(row, col, color)
+    }
+
+    /// Set all buttons to one color
+    /// Returns Error, if action fails
+    pub fn set_all(&mut self, color: Color) -> Result<(), MidiInterfaceError> {
+        self.output.set_all(color)
+    }
+
+    /// Select the GridMode of the Launchpad (XY-Mode or DrumRack-Mode)
+    /// Returns Error, if action fails
+    pub fn select_mode(&mut self, mode: GridMode) -> Result<(), MidiInterfaceError> {
+        self.output.select_mode(mode)
+    }
+
+    /// Return if Launchpad is double buffered
+    pub fn is_double_buffered(&self) -> bool {
+        self.output.is_double_buffered()
+    }
+
+    /// Takes a 8x9 (row, col) matrix of Colors and sets the lights according to the matrix
+    /// Returns Error, if action fails
+    pub fn set_matrix(&mut self, mat: &[[Color; 9]; 8]) -> Result<(), MidiInterfaceError> {
+        self.output.set_matrix(mat)
+    }
+
+    /// Set lights of the first row on the Launchpad (round control buttons)
+    /// Returns Error, if action fails
+    pub fn set_first_row(&mut self, color: Color) -> Result<(), MidiInterfaceError> {
+        self.output.set_first_row(color)
+    }
+
+    /// Reset the state of the Launchpad
+    /// Returns Error, if action fails
+    pub fn reset(&mut self) -> Result<(), MidiInterfaceError> {
+        self.output.reset()
+    }
+
+    /// Turn on all lights of the Launchpad with the supplied brightness (resets the Launchpad).
+    /// The state records all lights amber at that brightness, so restore() lights them again
+    /// Returns Error, if action fails
+    pub fn led_test(&mut self, brightness: LedTestBrightness) -> Result<(), MidiInterfaceError> {
+        self.output.led_test(brightness)
+    }
+
+    /// Set the buffer mode of the Launchpad (double buffering possible)
+    /// The buffer modes are described in the BufferSetting struct
+    /// Returns Error, if action fails
+    pub fn set_buffer_mode(
+        &mut self,
+        setting: BufferSetting,
+        copy: bool,
+    ) -> Result<(), MidiInterfaceError> {
+        self.output.set_buffer_mode(setting, copy)
+    }
+
+    /// Disable double buffering if activated, do nothing if not activated
+    /// Returns Error, if action fails
+    pub fn disable_double_buffering(&mut self) -> Result<(), MidiInterfaceError> {
+        self.output.disable_double_buffering()
+    }
+
+    /// Swaps the active buffer and copies the current state to the new buffer,
+    /// if copy equals true
+    /// Returns Error, if action fails
+    pub fn swap_buffers(&mut self, copy: bool) -> Result<(), MidiInterfaceError> {
+        self.output.swap_buffers(copy)
+    }
+
+    /// Swap the active buffer without copying
+    /// Returns Error, if action fails
+    pub fn hard_swap(&mut self) -> Result<(), MidiInterfaceError> {
+        self.output.hard_swap()
+    }
+
+    /// Set the refresh cycle of the Launchpad LEDs
+    /// numerator is supposed to be in \[1; 16\]
+    /// denominator is supposed to be in \[3; 18\]
+    /// Returns Error, if action fails
+    pub fn set_duty_cycle(
+        &mut self,
+        numerator: u8,
+        denominator: u8,
+    ) -> Result<(), MidiInterfaceError> {
+        self.output.set_duty_cycle(numerator, denominator)
+    }
+
+    /// Returns if messages from Launchpad are available or
+    /// an MidiInterfaceError, if polling fails
+    pub fn poll(&self) -> Result<bool, MidiInterfaceError> {
+        self.input.poll()
+    }
+
+    /// Read a single midi message, gives an Error if action fails
+    pub fn read_single_msg(&self) -> Result<Option<LaunchMessage>, MidiInterfaceError> {
+        self.input.read_single_msg()
+    }
+
+    /// Read a number of midi messages, return Error if action fails
+    pub fn read_n_msgs(&self, n: usize) -> Result<Option<Vec<LaunchMessage>>, MidiInterfaceError> {
+        self.input.read_n_msgs(n)
+    }
+
+    /// Read a number of midi messages and decode them into button presses and releases,
+    /// return Error if action fails
+    pub fn read_events(&self, n: usize) -> Result<Vec<PadEvent>, MidiInterfaceError> {
+        self.input.read_events(n)
+    }
+
+    /// Read a number of midi messages with the time they were received,
+    /// return Error if action fails
+    pub fn read_n_timed(&self, n: usize) -> Result<Option<Vec<TimedMessage>>, MidiInterfaceError> {
+        self.input.read_n_timed(n)
+    }
+
+    /// Read a number of midi messages and decode them into button presses and releases
+    /// with the time they were received (the time of reading, if the backend has no timestamps),
+    /// return Error if action fails
+    pub fn read_timed_events(
+        &self,
+        n: usize,
+    ) -> Result<Vec<(PadEvent, Instant)>, MidiInterfaceError> {
+        self.input.read_timed_events(n)
+    }
+}
+
+/// Input half of a LaunchDevice (see LaunchDevice::split), reading the button events
+pub struct LaunchInput<I: Input> {
+    in_port: I,
+    orientation: Orientation,
+}
+impl<I: Input> LaunchInput<I> {
+    /// Create the input half on the input port of a Launchpad Mini
+    pub fn new(in_port: I) -> LaunchInput<I> {
+        LaunchInput {
             in_port,
-            out_port,
-            buffer_setting: 0,
+            orientation: Orientation::default(),
         }
     }
 
+    /// Orientation the button positions read are transformed with
+    pub fn orientation(&self) -> Orientation {
+        self.orientation
+    }
+
+    /// Set the orientation of the Launchpad, button messages read are moved
+    /// to the positions as seen (XY mode layout)
+    pub fn set_orientation(&mut self, orientation: Orientation) {
+        self.orientation = orientation;
+    }
+
+    /// Input port of the Launchpad
+    pub fn input(&self) -> &I {
+        &self.in_port
+    }
+
+    /// Return the input port
+    pub fn into_inner(self) -> I {
+        self.in_port
+    }
+
     /// Returns if messages from Launchpad are available or
     /// an MidiInterfaceError, if polling fails
     pub fn poll(&self) -> Result<bool, MidiInterfaceError> {
@@ -63,7 +329,7 @@ where
 
     /// Read a single midi message, gives an Error if action fails
     pub fn read_single_msg(&self) -> Result<Option<LaunchMessage>, MidiInterfaceError> {
-        let opt = self.in_port.read_n(1)?;
+        let opt = self.read_n_msgs(1)?;
         match opt {
             None => Ok(None),
             Some(msg) => match msg.first() {
@@ -78,7 +344,161 @@ where
 
     /// Read a number of midi messages, return Error if action fails
     pub fn read_n_msgs(&self, n: usize) -> Result<Option<Vec<LaunchMessage>>, MidiInterfaceError> {
-        self.in_port.read_n(n)
+        let msgs = self.in_port.read_n(n)?;
+        if self.orientation.is_identity() {
+            return Ok(msgs);
+        }
+        Ok(msgs.map(|msgs| {
+            msgs.iter()
+                .map(|msg| self.orientation.message_to_logical(msg))
+                .collect()
+        }))
+    }
+
+    /// Read a number of midi messages and decode them into button presses and releases,
+    /// return Error if action fails
+    pub fn read_events(&self, n: usize) -> Result<Vec<PadEvent>, MidiInterfaceError> {
+        let msgs = self.read_n_msgs(n)?.unwrap_or_default();
+        Ok(msgs.into_iter().map(PadEvent::from).collect())
+    }
+
+    /// Read a number of midi messages with the time they were received,
+    /// return Error if action fails
+    pub fn read_n_timed(&self, n: usize) -> Result<Option<Vec<TimedMessage>>, MidiInterfaceError> {
+        let msgs = self.in_port.read_n_timed(n)?;
+        if self.orientation.is_identity() {
+            return Ok(msgs);
+        }
+        Ok(msgs.map(|msgs| {
+            msgs.into_iter()
+                .map(|timed| TimedMessage {
+                    msg: self.orientation.message_to_logical(&timed.msg),
+                    at: timed.at,
+                })
+                .collect()
+        }))
+    }
+
+    /// Read a number of midi messages and decode them into button presses and releases
+    /// with the time they were received (the time of reading, if the backend has no timestamps),
+    /// return Error if action fails
+    pub fn read_timed_events(
+        &self,
+        n: usize,
+    ) -> Result<Vec<(PadEvent, Instant)>, MidiInterfaceError> {
+        let now = Instant::now();
+        let msgs = self.read_n_timed(n)?.unwrap_or_default();
+        Ok(msgs
+            .into_iter()
+            .map(|timed| (PadEvent::from(timed.msg), timed.at.unwrap_or(now)))
+            .collect())
+    }
+}
+
+/// Output half of a LaunchDevice (see LaunchDevice::split), setting the lights
+/// and keeping track of the state of the Launchpad
+pub struct LaunchOutput<O: Output> {
+    out_port: O,
+    state: DeviceState,
+    orientation: Orientation,
+}
+impl<O: Output> LaunchOutput<O> {
+    /// Create the output half on the output port of a Launchpad Mini
+    /// (the state is unknown, so the Launchpad is assumed to be reset)
+    pub fn new(out_port: O) -> LaunchOutput<O> {
+        LaunchOutput {
+            out_port,
+            state: DeviceState::default(),
+            orientation: Orientation::default(),
+        }
+    }
+
+    /// Orientation the light positions sent are transformed with
+    pub fn orientation(&self) -> Orientation {
+        self.orientation
+    }
+
+    /// Set the orientation of the Launchpad, lights are sent to the buttons at the positions
+    /// as seen (XY mode layout). The state keeps the positions as seen, lights already sent
+    /// stay where they are until redrawn (e.g. with restore(&capture()))
+    pub fn set_orientation(&mut self, orientation: Orientation) {
+        self.orientation = orientation;
+    }
+
+    /// Return the output port
+    pub fn into_inner(self) -> O {
+        self.out_port
+    }
+
+    /// Return the last known state of the Launchpad (tracked from the messages sent to it)
+    pub fn state(&self) -> &DeviceState {
+        &self.state
+    }
+
+    /// Take a snapshot of the state of the Launchpad (both buffers, grid mode,
+    /// buffer mode, flashing and duty cycle) to restore it later
+    pub fn capture(&self) -> DeviceState {
+        self.state.clone()
+    }
+
+    /// Bring the Launchpad into a previously captured state (resets the Launchpad first)
+    /// Returns Error, if action fails
+    pub fn restore(&mut self, state: &DeviceState) -> Result<(), MidiInterfaceError> {
+        self.write_all(state.messages())
+    }
+
+    /// Output port of the Launchpad (e.g. a RateLimiter)
+    pub fn output(&self) -> &O {
+        &self.out_port
+    }
+
+    /// Access the output port of the Launchpad, messages written to it directly
+    /// are not tracked in the state
+    pub fn output_mut(&mut self) -> &mut O {
+        &mut self.out_port
+    }
+
+    /// Messages for the device sending a message addressed to the positions as seen
+    /// (rapid updates are sent as single light messages, as their order is fixed),
+    /// has to be called before the message is recorded
+    fn physical(&self, msg: &LaunchMessage) -> Vec<LaunchMessage> {
+        if msg.status != RAPID_UPDATE {
+            return vec![self.orientation.message_to_physical(msg)];
+        }
+        let cursor = self.state.rapid_cursor();
+        [msg.data1, msg.data2]
+            .into_iter()
+            .enumerate()
+            .map(|(i, vel)| {
+                let light = light_message(rapid_position(cursor + i), Color::Black);
+                self.orientation.message_to_physical(&LaunchMessage { data2: vel, ..light })
+            })
+            .collect()
+    }
+
+    /// Write a message to the output port, keeping track of the state of the Launchpad
+    fn write(&mut self, msg: LaunchMessage) -> Result<(), MidiInterfaceError> {
+        if !self.orientation.is_identity() {
+            return self.write_all(vec![msg]);
+        }
+        self.state.record(&msg);
+        self.out_port.write_message(msg)
+    }
+
+    /// Write multiple messages to the output port, keeping track of the state of the Launchpad
+    fn write_all(&mut self, msgs: Vec<LaunchMessage>) -> Result<(), MidiInterfaceError> {
+        if !self.orientation.is_identity() {
+            let mut res: Vec<LaunchMessage> = Vec::with_capacity(msgs.len());
+            for msg in msgs.iter() {
+                res.extend(self.physical(msg));
+                self.state.record(msg);
+            }
+            return self.out_port.write_messages(res);
+        }
+        for msg in msgs.iter() {
+            self.state.record(msg);
+        }
+        self.out_port.write_messages(msgs)
     }
 
     /// Send a note msg to the Launchpad, turning lights on and of (and return Error, if action fails)
@@ -94,7 +514,7 @@ where
             mtype = MessageType::On as u8;
         }
 
-        self.out_port.write_message(LaunchMessage {
+        self.write(LaunchMessage {
             status: mtype,
             data1: key,
             data2: vel,
@@ -103,15 +523,34 @@ where
         Ok(())
     }
 
+    /// Send messages to the Launchpad when their timestamps are due, blocking until the last
+    /// one is sent (see Output::write_messages_timed), the state is updated at once
+    /// Returns Error, if action fails
+    pub fn send_timed_messages(
+        &mut self,
+        msgs: Vec<TimedMessage>,
+    ) -> Result<(), MidiInterfaceError> {
+        let mut res: Vec<TimedMessage> = Vec::with_capacity(msgs.len());
+        for timed in msgs {
+            res.extend(
+                self.physical(&timed.msg)
+                    .into_iter()
+                    .map(|msg| TimedMessage::new(msg, timed.at)),
+            );
+            self.state.record(&timed.msg);
+        }
+        self.out_port.write_messages_timed(res)
+    }
+
     /// Send multiple messages to the Launchpad (and return an Error, if action fails)
     pub fn send_messages(&mut self, msgs: Vec<LaunchMessage>) -> Result<(), MidiInterfaceError> {
-        self.out_port.write_messages(msgs)?;
+        self.write_all(msgs)?;
         Ok(())
     }
 
     /// Send a control message to the Launchpad (and return an Error, if action fails)
     pub fn send_ctl_msg(&mut self, data1: u8, data2: u8) -> Result<(), MidiInterfaceError> {
-        self.out_port.write_message(LaunchMessage {
+        self.write(LaunchMessage {
             status: 0xb0,
             data1,
             data2,
@@ -144,7 +583,7 @@ where
         col: u8,
         color: Color,
     ) -> Result<(), MidiInterfaceError> {
-        self.out_port.write_message(LaunchMessage {
+        self.write(LaunchMessage {
             status: 0x90,
             data1: Key::from(MatPos::new(row, col)),
             data2: color as u8,
@@ -165,7 +604,7 @@ where
             });
         }
 
-        self.out_port.write_messages(msg)?;
+        self.write_all(msg)?;
         Ok(())
     }
 
@@ -178,7 +617,7 @@ where
 
     /// Return if Launchpad is double buffered
     pub fn is_double_buffered(&self) -> bool {
-        let buffered = 0x0F & self.buffer_setting;
+        let buffered = 0x0F & self.state.buffer_setting();
         buffered == BufferSetting::OneActive as u8 || buffered == BufferSetting::ZeroActive as u8
     }
 
@@ -197,7 +636,7 @@ where
             }
         }
 
-        self.out_port.write_messages(res)?;
+        self.write_all(res)?;
         Ok(())
     }
 
@@ -213,7 +652,7 @@ where
             });
         }
 
-        self.out_port.write_messages(msg)?;
+        self.write_all(msg)?;
         Ok(())
     }
 
@@ -224,6 +663,14 @@ where
         Ok(())
     }
 
+    /// Turn on all lights of the Launchpad with the supplied brightness (resets the Launchpad).
+    /// The state records all lights amber at that brightness, so restore() lights them again
+    /// Returns Error, if action fails
+    pub fn led_test(&mut self, brightness: LedTestBrightness) -> Result<(), MidiInterfaceError> {
+        self.send_ctl_msg(0x00, brightness as u8)?;
+        Ok(())
+    }
+
     /// Set the buffer mode of the Launchpad (double buffering possible)
     /// The buffer modes are described in the BufferSetting struct
     /// Returns Error, if action fails
@@ -232,14 +679,13 @@ where
         setting: BufferSetting,
         copy: bool,
     ) -> Result<(), MidiInterfaceError> {
+        let mut buffer_setting: u8 = 0x20;
         if copy {
-            self.buffer_setting = 0x30;
-        } else {
-            self.buffer_setting = 0x20;
+            buffer_setting = 0x30;
         }
 
-        self.buffer_setting |= setting as u8;
-        self.send_ctl_msg(0x00, self.buffer_setting)?;
+        buffer_setting |= setting as u8;
+        self.send_ctl_msg(0x00, buffer_setting)?;
         Ok(())
     }
 
@@ -254,7 +700,7 @@ where
     /// if copy equals true
     /// Returns Error, if action fails
     pub fn swap_buffers(&mut self, copy: bool) -> Result<(), MidiInterfaceError> {
-        let setting = self.buffer_setting & 0x0F;
+        let setting = self.state.buffer_setting() & 0x0F;
 
         if setting == BufferSetting::OneActive as u8 {
             self.set_buffer_mode(BufferSetting::ZeroActive, copy)?;
@@ -304,7 +750,7 @@ where
             data2 = 0x10 * (numerator - 1) + (denominator - 3);
         }
 
-        self.out_port.write_message(LaunchMessage {
+        self.write(LaunchMessage {
             status: 0xb0,
             data1,
             data2,
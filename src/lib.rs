@@ -15,17 +15,65 @@ see COPYING.LESSER file for license information
 mod utils;
 mod launch_device;
 mod midilib;
+mod units;
+mod composite;
+mod device_state;
+mod supervisor;
+mod gestures;
+mod swipes;
+mod widgets;
+mod pages;
+mod mapping;
+mod feedback;
+mod sequencer;
+mod clock_sync;
+mod scheduler;
+mod rate_limit;
+mod shared_device;
+mod note_layout;
+mod drum_pads;
 
 pub use launch_device::*;
 pub use midilib::*;
+pub use units::*;
+pub use composite::*;
+pub use device_state::*;
+pub use supervisor::*;
+pub use gestures::*;
+pub use swipes::*;
+pub use widgets::*;
+pub use pages::*;
+pub use mapping::*;
+pub use feedback::*;
+pub use sequencer::*;
+pub use clock_sync::*;
+pub use scheduler::*;
+pub use rate_limit::*;
+pub use shared_device::*;
+pub use note_layout::*;
+pub use drum_pads::*;
+
+#[cfg(feature = "config")]
+mod config;
+#[cfg(feature = "config")]
+pub use config::*;
 
 /// The types and implementations in this module do have to be implemented
 mod pm_impl;
 pub use pm_impl::{MidiImpl, InputPort, OutputPort};
 pub use pm_impl::*;
 
+/// In-memory backend for developing and testing without hardware
+mod mock_impl;
+pub use mock_impl::{MockInput, MockMidi, MockOutput, MockSurface};
+
+#[cfg(feature = "simulator")]
+pub mod simulator;
+
 pub use utils::Color;
 pub use utils::MatPos;
+pub use utils::{Region, GRID_SIZE, SCENE_COL, TOP_ROW};
+pub use utils::{LedTestBrightness, Orientation, PadEvent, Rotation, StripPolicy};
 
 pub const BUFFER_SIZE: usize = 1024;
 
@@ -49,3 +97,27 @@ pub fn new_launch_device_from_midi_interface<'a>(ctx: &'a impl MidiInterface<'a,
 
     LaunchDevice::new(in_p, out_p)
 }
+
+/// A LaunchDevice together with the identifier of the unit it is connected to
+pub type UnitLaunchDevice<I, O> = (UnitId, LaunchDevice<I, O>);
+
+/// construct one LaunchDevice per connected Launchpad Mini unit from a midi backend context,
+/// each paired with the stable identifier of its unit (sorted by identifier)
+/// Returns Error, if the device list can not be read or the ports of a unit can not be opened
+pub fn new_launch_devices_from_midi_interface<'a, I, O>(
+    ctx: &'a impl MidiInterface<'a, MidiInput = I, MidiOutput = O>,
+) -> Result<Vec<UnitLaunchDevice<I, O>>, MidiInterfaceError>
+where
+    I: Input + 'a,
+    O: Output + 'a,
+{
+    let units = ctx.get_units(LAUNCHPAD_MINI_NAME)?;
+    let mut devices = Vec::with_capacity(units.len());
+
+    for unit in units {
+        let (in_p, out_p) = ctx.get_unit_in_out(&unit)?;
+        devices.push((unit.id, LaunchDevice::new(in_p, out_p)));
+    }
+
+    Ok(devices)
+}
@@ -0,0 +1,340 @@
+/* Copyright (C) 2023 Lucas Haupt
+
+This program is distributed under the terms of the 
+GNU Lesser General Public License v3.0, 
+see COPYING.LESSER file for license information
+*/
+
+//! # Mapping
+//!
+//! MIDI controller mode: button presses on the Launchpad are turned into note,
+//! control change or program change messages sent to another output (e.g. a synth
+//! or a virtual port). The Launchpad Mini is not velocity sensitive, so every
+//! mapping sends a fixed velocity. Mapped buttons can light up to show their state.
+
+use std::collections::{HashMap, HashSet};
+
+use crate::launch_device::LaunchDevice;
+use crate::midilib::{Input, LaunchMessage, MidiInterfaceError, Output};
+use crate::utils::{check_channel, light_message, note_message, PadEvent};
+use crate::{Color, MatPos};
+
+/// Full velocity
+pub const VELOCITY_FULL: u8 = 0x7F;
+
+/// Medium velocity
+pub const VELOCITY_MEDIUM: u8 = 0x5F;
+
+/// Soft velocity
+pub const VELOCITY_SOFT: u8 = 0x3F;
+
+/// Status byte of a control change message (channel 0)
+const CONTROL_CHANGE: u8 = 0xB0;
+
+/// Status byte of a program change message (channel 0)
+const PROGRAM_CHANGE: u8 = 0xC0;
+
+/// Behaviour of a button mapped to a control change
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum CcMode {
+    /// send the on value when pressed and the off value when released
+    Momentary { on: u8, off: u8 },
+    /// send the on and off value alternately with every press
+    Toggle { on: u8, off: u8 },
+}
+
+/// Message(s) sent for a button
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum MidiAction {
+    /// note on when pressed, note off when released
+    Note { channel: u8, note: u8, velocity: u8 },
+    /// control change
+    Cc {
+        channel: u8,
+        controller: u8,
+        mode: CcMode,
+    },
+    /// program change when pressed
+    ProgramChange { channel: u8, program: u8 },
+}
+impl MidiAction {
+    /// Check channel (0 to 15) and data bytes (0 to 127) of the action of a button
+    /// Returns Error, naming the bad value
+    fn validate(&self, pos: MatPos) -> Result<(), MidiInterfaceError> {
+        let (channel, data): (u8, Vec<(&str, u8)>) = match *self {
+            MidiAction::Note {
+                channel,
+                note,
+                velocity,
+            } => (channel, vec![("note", note), ("velocity", velocity)]),
+            MidiAction::Cc {
+                channel,
+                controller,
+                mode: CcMode::Momentary { on, off } | CcMode::Toggle { on, off },
+            } => (
+                channel,
+                vec![("controller", controller), ("on", on), ("off", off)],
+            ),
+            MidiAction::ProgramChange { channel, program } => (channel, vec![("program", program)]),
+        };
+
+        check_channel(channel)?;
+        match data.into_iter().find(|(_, value)| *value > 0x7F) {
+            Some((name, value)) => Err(MidiInterfaceError::Invalid(format!(
+                "({}, {}): {} {} out of range (0 to 127)",
+                pos.row, pos.col, name, value
+            ))),
+            None => Ok(()),
+        }
+    }
+}
+
+/// Lights of a mapped button: the on color while the mapping is active
+/// (note or momentary control held, toggle on, program last selected on its channel),
+/// the off color otherwise
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub struct LedFeedback {
+    pub on: Color,
+    pub off: Color,
+}
+impl LedFeedback {
+    pub fn new(on: Color, off: Color) -> LedFeedback {
+        LedFeedback { on, off }
+    }
+}
+
+/// A button mapped to a MIDI action
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub struct Mapping {
+    pub action: MidiAction,
+    /// None leaves the light of the button untouched
+    pub feedback: Option<LedFeedback>,
+}
+
+/// Turns button presses into MIDI messages on another output
+pub struct MidiMapper<O: Output> {
+    out_port: O,
+    mappings: HashMap<MatPos, Mapping>,
+    /// buttons held and the action each one started (released even if remapped meanwhile)
+    held: HashMap<MatPos, MidiAction>,
+    toggled: HashSet<MatPos>,
+    /// last program selected per channel
+    programs: HashMap<u8, u8>,
+}
+impl<O: Output> MidiMapper<O> {
+    /// Create a mapper sending to the supplied output port
+    pub fn new(out_port: O) -> MidiMapper<O> {
+        MidiMapper {
+            out_port,
+            mappings: HashMap::new(),
+            held: HashMap::new(),
+            toggled: HashSet::new(),
+            programs: HashMap::new(),
+        }
+    }
+
+    /// Map a button to an action, replacing its previous mapping
+    /// (a note or momentary control held on the button is released first)
+    /// Returns Error, if channel or data bytes of the action are out of range or sending fails
+    pub fn map(
+        &mut self,
+        pos: MatPos,
+        action: MidiAction,
+        feedback: Option<LedFeedback>,
+    ) -> Result<(), MidiInterfaceError> {
+        action.validate(pos)?;
+        self.release(pos)?;
+        self.toggled.remove(&pos);
+        self.mappings.insert(pos, Mapping { action, feedback });
+        Ok(())
+    }
+
+    /// Remove the mapping of a button, a note or momentary control held on it is released
+    /// Returns Error, if sending fails
+    pub fn unmap(&mut self, pos: MatPos) -> Result<Option<Mapping>, MidiInterfaceError> {
+        self.release(pos)?;
+        self.toggled.remove(&pos);
+        Ok(self.mappings.remove(&pos))
+    }
+
+    /// Send the release of the action started by a held button (note off or
+    /// the off value of a momentary control) and forget the button,
+    /// returns if the button was held
+    /// Returns Error, if sending fails
+    fn release(&mut self, pos: MatPos) -> Result<bool, MidiInterfaceError> {
+        let msg = match self.held.remove(&pos) {
+            Some(MidiAction::Note { channel, note, .. }) => {
+                note_message(channel, note, false, 0x00)
+            }
+            Some(MidiAction::Cc {
+                channel,
+                controller,
+                mode: CcMode::Momentary { off, .. },
+            }) => LaunchMessage {
+                status: CONTROL_CHANGE | channel,
+                data1: controller,
+                data2: off,
+            },
+            Some(_) => return Ok(true),
+            None => return Ok(false),
+        };
+        self.out_port.write_message(msg)?;
+        Ok(true)
+    }
+
+    pub fn mapping(&self, pos: MatPos) -> Option<&Mapping> {
+        self.mappings.get(&pos)
+    }
+
+    /// Output port the messages are sent to
+    pub fn output(&mut self) -> &mut O {
+        &mut self.out_port
+    }
+
+    /// Return if the mapping of a button is active (see LedFeedback)
+    pub fn is_active(&self, pos: MatPos) -> bool {
+        match self.mappings.get(&pos).map(|m| m.action) {
+            Some(MidiAction::Cc {
+                mode: CcMode::Toggle { .. },
+                ..
+            }) => self.toggled.contains(&pos),
+            Some(MidiAction::ProgramChange { channel, program }) => {
+                self.programs.get(&channel) == Some(&program)
+            }
+            Some(_) => self.held.contains_key(&pos),
+            None => false,
+        }
+    }
+
+    /// Handle a button event: send the messages of the mapping to the output,
+    /// returns the buttons whose light changed (unmapped buttons are ignored)
+    /// Returns Error, if sending fails
+    pub fn handle(&mut self, event: PadEvent) -> Result<Vec<MatPos>, MidiInterfaceError> {
+        let pos = event.pos;
+        let mapping = match self.mappings.get(&pos) {
+            Some(mapping) => *mapping,
+            None => return Ok(Vec::new()),
+        };
+
+        if !event.pressed {
+            let held = self.release(pos)?;
+            return Ok(if held { vec![pos] } else { Vec::new() });
+        }
+        self.held.insert(pos, mapping.action);
+
+        let mut changed = vec![pos];
+        let msg = match mapping.action {
+            MidiAction::Note {
+                channel,
+                note,
+                velocity,
+            } => note_message(channel, note, true, velocity),
+            MidiAction::Cc {
+                channel,
+                controller,
+                mode: CcMode::Momentary { on, .. },
+            } => LaunchMessage {
+                status: CONTROL_CHANGE | channel,
+                data1: controller,
+                data2: on,
+            },
+            MidiAction::Cc {
+                channel,
+                controller,
+                mode: CcMode::Toggle { on, off },
+            } => {
+                let on = if self.toggled.remove(&pos) {
+                    off
+                } else {
+                    self.toggled.insert(pos);
+                    on
+                };
+                LaunchMessage {
+                    status: CONTROL_CHANGE | channel,
+                    data1: controller,
+                    data2: on,
+                }
+            }
+            MidiAction::ProgramChange { channel, program } => {
+                if let Some(previous) = self.programs.insert(channel, program) {
+                    changed.extend(self.mappings.iter().filter_map(|(p, m)| match m.action {
+                        MidiAction::ProgramChange {
+                            channel: c,
+                            program: prg,
+                        } if c == channel && prg == previous => Some(*p),
+                        _ => None,
+                    }));
+                }
+                LaunchMessage {
+                    status: PROGRAM_CHANGE | channel,
+                    data1: program,
+                    data2: 0x00,
+                }
+            }
+        };
+        self.out_port.write_message(msg)?;
+        Ok(changed)
+    }
+
+    /// Lights of the mapped buttons with LED feedback
+    pub fn render(&self) -> Vec<(MatPos, Color)> {
+        self.mappings
+            .iter()
+            .filter_map(|(pos, m)| self.light(*pos, m))
+            .collect()
+    }
+
+    /// Light of a mapped button, None if it has no LED feedback
+    fn light(&self, pos: MatPos, mapping: &Mapping) -> Option<(MatPos, Color)> {
+        let feedback = mapping.feedback?;
+        if self.is_active(pos) {
+            Some((pos, feedback.on))
+        } else {
+            Some((pos, feedback.off))
+        }
+    }
+
+    /// Draw the lights of all mapped buttons on the Launchpad
+    /// Returns Error, if action fails
+    pub fn draw<I: Input, L: Output>(
+        &self,
+        dev: &mut LaunchDevice<I, L>,
+    ) -> Result<(), MidiInterfaceError> {
+        let msgs: Vec<LaunchMessage> = self
+            .render()
+            .into_iter()
+            .map(|(pos, color)| light_message(pos, color))
+            .collect();
+        dev.send_messages(msgs)
+    }
+
+    /// Read up to n messages from the Launchpad, send the mapped MIDI messages
+    /// and update the lights of the buttons
+    /// Returns Error, if reading or sending fails
+    pub fn process<I: Input, L: Output>(
+        &mut self,
+        dev: &mut LaunchDevice<I, L>,
+        n: usize,
+    ) -> Result<(), MidiInterfaceError> {
+        if !dev.poll()? {
+            return Ok(());
+        }
+
+        let mut changed: Vec<MatPos> = Vec::new();
+        for event in dev.read_events(n)? {
+            changed.extend(self.handle(event)?);
+        }
+
+        let mut msgs: Vec<LaunchMessage> = Vec::new();
+        for pos in changed {
+            let light = self.mappings.get(&pos).and_then(|m| self.light(pos, m));
+            if let Some((pos, color)) = light {
+                msgs.push(light_message(pos, color));
+            }
+        }
+        if !msgs.is_empty() {
+            dev.send_messages(msgs)?;
+        }
+        Ok(())
+    }
+}
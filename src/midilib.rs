@@ -11,7 +11,9 @@ see COPYING.LESSER file for license information
 
 use std::error::Error;
 use std::fmt::{Debug, Display, Formatter};
+use std::time::Instant;
 
+use crate::units::{find_units, DeviceUnit};
 
 /// MidiInterface adapter for different midi backends,
 /// has to comply with the Input and Output trait
@@ -43,6 +45,23 @@ pub trait MidiInterface<'a> {
 
     /// Return default output
     fn get_default_output(&'a self) -> Result<Self::MidiOutput, MidiInterfaceError>;
+
+    /// Request all physical units whose port names contain the supplied name,
+    /// with the input and output port of each unit paired up (see units.rs)
+    fn get_units(&self, name: &str) -> Result<Vec<DeviceUnit>, MidiInterfaceError> {
+        Ok(find_units(&self.get_devices()?, name))
+    }
+
+    /// Get the input and output of the supplied unit or return Error,
+    /// if one of the ports can not be opened
+    fn get_unit_in_out(
+        &'a self,
+        unit: &DeviceUnit,
+    ) -> Result<(Self::MidiInput, Self::MidiOutput), MidiInterfaceError> {
+        let in_p = self.get_input(Identifier::Number(unit.input.id))?;
+        let out_p = self.get_output(Identifier::Number(unit.output.id))?;
+        Ok((in_p, out_p))
+    }
 }
 
 /// Trait representing an Output compatible with LaunchDevice and MidiInterface
@@ -52,6 +71,51 @@ pub trait Output {
 
     /// Write multiple messages to output port
     fn write_messages(&mut self, msg: Vec<LaunchMessage>) -> Result<(), MidiInterfaceError>;
+
+    /// Write multiple messages with timestamps to output port: messages without timestamp
+    /// are written at once, the others when they are due. Messages are written in order
+    /// and the call blocks until the last one is written (backends scheduling messages
+    /// themselves may return earlier)
+    fn write_messages_timed(&mut self, msgs: Vec<TimedMessage>) -> Result<(), MidiInterfaceError> {
+        let mut due: Vec<LaunchMessage> = Vec::new();
+        for timed in msgs {
+            let now = Instant::now();
+            if let Some(at) = timed.at.filter(|at| *at > now) {
+                if !due.is_empty() {
+                    self.write_messages(std::mem::take(&mut due))?;
+                }
+                std::thread::sleep(at - now);
+            }
+            due.push(timed.msg);
+        }
+        if !due.is_empty() {
+            self.write_messages(due)?;
+        }
+        Ok(())
+    }
+
+    /// Write a real-time message (clock, start, stop, ...) to output port
+    fn write_realtime(&mut self, msg: RealTimeMessage) -> Result<(), MidiInterfaceError> {
+        self.write_message(msg.into())
+    }
+
+    /// Write a system exclusive message (starting with 0xF0, ending with 0xF7) to output port
+    /// Returns Error, if the message is malformed or the backend does not support sysex
+    fn write_sysex(&mut self, msg: &[u8]) -> Result<(), MidiInterfaceError> {
+        check_sysex(msg)?;
+        Err(MidiInterfaceError::Unimplemented(
+            "sysex not supported by midi backend".to_string(),
+        ))
+    }
+
+    /// Write a message of any kind to output port (short messages take the
+    /// LaunchMessage path, sysex messages are written with write_sysex)
+    fn write_midi(&mut self, msg: MidiMessage) -> Result<(), MidiInterfaceError> {
+        match msg {
+            MidiMessage::SysEx(bytes) => self.write_sysex(&bytes),
+            msg => self.write_message(LaunchMessage::try_from(msg)?),
+        }
+    }
 }
 
 /// Trait representing an Input compatible with LaunchDevice and MidiInterface
@@ -61,10 +125,105 @@ pub trait Input {
 
     /// Read n messages from input port
     fn read_n(&self, count: usize) -> Result<Option<Vec<LaunchMessage>>, MidiInterfaceError>;
+
+    /// Read n messages from input port with the time they were received
+    /// (None for backends without timestamps)
+    fn read_n_timed(&self, count: usize) -> Result<Option<Vec<TimedMessage>>, MidiInterfaceError> {
+        Ok(self
+            .read_n(count)?
+            .map(|msgs| msgs.into_iter().map(TimedMessage::from).collect()))
+    }
+
+    /// Read n messages from input port and return the real-time messages among them,
+    /// other messages are dropped (e.g. for an input only used for clock sync)
+    fn read_realtime(&self, count: usize) -> Result<Vec<RealTimeMessage>, MidiInterfaceError> {
+        Ok(self
+            .read_n(count)?
+            .unwrap_or_default()
+            .iter()
+            .filter_map(RealTimeMessage::from_message)
+            .collect())
+    }
+
+    /// Read n messages of any kind from input port
+    /// (backends not supporting sysex only return short messages)
+    fn read_midi(&self, count: usize) -> Result<Option<Vec<MidiMessage>>, MidiInterfaceError> {
+        Ok(self
+            .read_n(count)?
+            .map(|msgs| msgs.into_iter().map(MidiMessage::from).collect()))
+    }
+
+    /// Read n messages from input port and return the sysex messages among them,
+    /// other messages are dropped (e.g. while waiting for a device inquiry reply)
+    fn read_sysex(&self, count: usize) -> Result<Vec<Vec<u8>>, MidiInterfaceError> {
+        Ok(self
+            .read_midi(count)?
+            .unwrap_or_default()
+            .into_iter()
+            .filter_map(|msg| match msg {
+                MidiMessage::SysEx(bytes) => Some(bytes),
+                _ => None,
+            })
+            .collect())
+    }
+}
+
+/// Boxed outputs (e.g. `Box<dyn Output>`) forward to the boxed output
+impl<T: Output + ?Sized> Output for Box<T> {
+    fn write_message(&mut self, msg: LaunchMessage) -> Result<(), MidiInterfaceError> {
+        (**self).write_message(msg)
+    }
+
+    fn write_messages(&mut self, msg: Vec<LaunchMessage>) -> Result<(), MidiInterfaceError> {
+        (**self).write_messages(msg)
+    }
+
+    fn write_messages_timed(&mut self, msgs: Vec<TimedMessage>) -> Result<(), MidiInterfaceError> {
+        (**self).write_messages_timed(msgs)
+    }
+
+    fn write_realtime(&mut self, msg: RealTimeMessage) -> Result<(), MidiInterfaceError> {
+        (**self).write_realtime(msg)
+    }
+
+    fn write_sysex(&mut self, msg: &[u8]) -> Result<(), MidiInterfaceError> {
+        (**self).write_sysex(msg)
+    }
+
+    fn write_midi(&mut self, msg: MidiMessage) -> Result<(), MidiInterfaceError> {
+        (**self).write_midi(msg)
+    }
+}
+
+/// Boxed inputs (e.g. `Box<dyn Input>`) forward to the boxed input
+impl<T: Input + ?Sized> Input for Box<T> {
+    fn poll(&self) -> Result<bool, MidiInterfaceError> {
+        (**self).poll()
+    }
+
+    fn read_n(&self, count: usize) -> Result<Option<Vec<LaunchMessage>>, MidiInterfaceError> {
+        (**self).read_n(count)
+    }
+
+    fn read_n_timed(&self, count: usize) -> Result<Option<Vec<TimedMessage>>, MidiInterfaceError> {
+        (**self).read_n_timed(count)
+    }
+
+    fn read_realtime(&self, count: usize) -> Result<Vec<RealTimeMessage>, MidiInterfaceError> {
+        (**self).read_realtime(count)
+    }
+
+    fn read_midi(&self, count: usize) -> Result<Option<Vec<MidiMessage>>, MidiInterfaceError> {
+        (**self).read_midi(count)
+    }
+
+    fn read_sysex(&self, count: usize) -> Result<Vec<Vec<u8>>, MidiInterfaceError> {
+        (**self).read_sysex(count)
+    }
 }
 
 /// Direction being either input or output device type
-#[derive(Debug, PartialEq, Eq)]
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
 pub enum Direction {
     /// incoming midi messages
     Input,
@@ -73,7 +232,7 @@ pub enum Direction {
 }
 
 /// Contains info about a midi device
-#[derive(Debug)]
+#[derive(Debug, Clone)]
 pub struct DeviceInfo {
     /// unique identifier
     pub id: i32,
@@ -92,7 +251,7 @@ impl DeviceInfo {
 }
 
 /// Struct for a MidiMessage for communication with Launchpad
-#[derive(Debug, Clone)]
+#[derive(Debug, Clone, PartialEq, Eq, Hash)]
 pub struct LaunchMessage {
     /// status byte (either control message 0x, )
     pub status: u8,
@@ -100,6 +259,313 @@ pub struct LaunchMessage {
     pub data2: u8,
 }
 
+/// MIDI real-time and song position messages used for clock sync
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum RealTimeMessage {
+    /// timing clock (0xF8), sent 24 times per quarter note
+    Clock,
+    /// start playing from the beginning (0xFA)
+    Start,
+    /// continue playing from the current position (0xFB)
+    Continue,
+    /// stop playing (0xFC)
+    Stop,
+    /// song position (0xF2) in 16th notes (6 clocks) from the beginning, 14 bit
+    SongPosition(u16),
+}
+impl RealTimeMessage {
+    /// Real-time message of a received message, None if it is none
+    pub fn from_message(msg: &LaunchMessage) -> Option<RealTimeMessage> {
+        match msg.status {
+            0xF8 => Some(RealTimeMessage::Clock),
+            0xFA => Some(RealTimeMessage::Start),
+            0xFB => Some(RealTimeMessage::Continue),
+            0xFC => Some(RealTimeMessage::Stop),
+            0xF2 => Some(RealTimeMessage::SongPosition(
+                (msg.data1 & 0x7F) as u16 | ((msg.data2 & 0x7F) as u16) << 7,
+            )),
+            _ => None,
+        }
+    }
+}
+impl From<RealTimeMessage> for LaunchMessage {
+    fn from(value: RealTimeMessage) -> Self {
+        let (status, data1, data2) = match value {
+            RealTimeMessage::Clock => (0xF8, 0x00, 0x00),
+            RealTimeMessage::Start => (0xFA, 0x00, 0x00),
+            RealTimeMessage::Continue => (0xFB, 0x00, 0x00),
+            RealTimeMessage::Stop => (0xFC, 0x00, 0x00),
+            RealTimeMessage::SongPosition(pos) => {
+                (0xF2, (pos & 0x7F) as u8, ((pos >> 7) & 0x7F) as u8)
+            }
+        };
+        LaunchMessage {
+            status,
+            data1,
+            data2,
+        }
+    }
+}
+
+/// LaunchMessage with the time it was received or is to be sent
+#[derive(Debug, Clone, PartialEq, Eq, Hash)]
+pub struct TimedMessage {
+    pub msg: LaunchMessage,
+    /// None if unknown (received) or as soon as possible (sent)
+    pub at: Option<Instant>,
+}
+impl TimedMessage {
+    pub fn new(msg: LaunchMessage, at: Option<Instant>) -> TimedMessage {
+        TimedMessage { msg, at }
+    }
+}
+impl From<LaunchMessage> for TimedMessage {
+    fn from(msg: LaunchMessage) -> Self {
+        TimedMessage { msg, at: None }
+    }
+}
+
+/// Universal device inquiry (any device, answered by a sysex identity reply)
+pub const DEVICE_INQUIRY: [u8; 6] = [0xF0, 0x7E, 0x7F, 0x06, 0x01, 0xF7];
+
+/// Check that a sysex message starts with 0xF0, ends with 0xF7
+/// and has only data bytes (0 to 127) in between
+/// Returns Error naming the problem otherwise
+pub fn check_sysex(msg: &[u8]) -> Result<(), MidiInterfaceError> {
+    let problem = match msg {
+        [0xF0, data @ .., 0xF7] => match data.iter().position(|b| *b > 0x7F) {
+            Some(i) => format!("byte {} (0x{:02X}) is no data byte", i + 1, data[i]),
+            None => return Ok(()),
+        },
+        [0xF0, ..] => "missing 0xF7 at the end".to_string(),
+        _ => "missing 0xF0 at the start".to_string(),
+    };
+    Err(MidiInterfaceError::Invalid(format!(
+        "malformed sysex: {}",
+        problem
+    )))
+}
+
+/// Midi message of any kind, LaunchMessage being the fast path for short messages
+#[derive(Debug, Clone, PartialEq, Eq, Hash)]
+pub enum MidiMessage {
+    NoteOff {
+        channel: u8,
+        note: u8,
+        velocity: u8,
+    },
+    NoteOn {
+        channel: u8,
+        note: u8,
+        velocity: u8,
+    },
+    PolyPressure {
+        channel: u8,
+        note: u8,
+        pressure: u8,
+    },
+    ControlChange {
+        channel: u8,
+        controller: u8,
+        value: u8,
+    },
+    ProgramChange {
+        channel: u8,
+        program: u8,
+    },
+    ChannelPressure {
+        channel: u8,
+        pressure: u8,
+    },
+    /// pitch bend, 14 bit (0x2000 being the center)
+    PitchBend {
+        channel: u8,
+        value: u16,
+    },
+    /// MTC quarter frame (0xF1)
+    QuarterFrame(u8),
+    /// song select (0xF3)
+    SongSelect(u8),
+    /// tune request (0xF6)
+    TuneRequest,
+    /// clock, start, continue, stop and song position
+    RealTime(RealTimeMessage),
+    /// active sensing (0xFE)
+    ActiveSensing,
+    /// system reset (0xFF)
+    SystemReset,
+    /// system exclusive message, including 0xF0 at the start and 0xF7 at the end
+    SysEx(Vec<u8>),
+    /// message with an undefined status byte
+    Other(LaunchMessage),
+}
+impl MidiMessage {
+    /// Bytes of the message as sent over the wire
+    /// Returns Error, if a channel or data byte is out of range
+    pub fn to_bytes(&self) -> Result<Vec<u8>, MidiInterfaceError> {
+        if let MidiMessage::SysEx(bytes) = self {
+            return Ok(bytes.clone());
+        }
+        let short = self.to_short()?;
+        let bytes = [short.status, short.data1, short.data2];
+        Ok(bytes[..short_message_len(short.status)].to_vec())
+    }
+
+    /// Parse a complete message (one short message or one sysex message)
+    /// Returns Error, if the bytes are no complete message
+    pub fn from_bytes(bytes: &[u8]) -> Result<MidiMessage, MidiInterfaceError> {
+        match bytes.first() {
+            Some(0xF0) => {
+                check_sysex(bytes)?;
+                Ok(MidiMessage::SysEx(bytes.to_vec()))
+            }
+            Some(status) if *status > 0x7F && bytes.len() == short_message_len(*status) => {
+                Ok(MidiMessage::from(LaunchMessage {
+                    status: *status,
+                    data1: bytes.get(1).copied().unwrap_or(0x00),
+                    data2: bytes.get(2).copied().unwrap_or(0x00),
+                }))
+            }
+            _ => Err(MidiInterfaceError::Invalid(format!(
+                "{:02X?} is no complete midi message",
+                bytes
+            ))),
+        }
+    }
+}
+
+/// Length in bytes of a short message with a status byte
+fn short_message_len(status: u8) -> usize {
+    match status {
+        0xC0..=0xDF | 0xF1 | 0xF3 => 2,
+        0x80..=0xEF | 0xF2 => 3,
+        _ => 1,
+    }
+}
+
+impl From<LaunchMessage> for MidiMessage {
+    fn from(msg: LaunchMessage) -> Self {
+        let channel = msg.status & 0x0F;
+        let (data1, data2) = (msg.data1, msg.data2);
+        match msg.status {
+            0x80..=0x8F => MidiMessage::NoteOff {
+                channel,
+                note: data1,
+                velocity: data2,
+            },
+            0x90..=0x9F => MidiMessage::NoteOn {
+                channel,
+                note: data1,
+                velocity: data2,
+            },
+            0xA0..=0xAF => MidiMessage::PolyPressure {
+                channel,
+                note: data1,
+                pressure: data2,
+            },
+            0xB0..=0xBF => MidiMessage::ControlChange {
+                channel,
+                controller: data1,
+                value: data2,
+            },
+            0xC0..=0xCF => MidiMessage::ProgramChange {
+                channel,
+                program: data1,
+            },
+            0xD0..=0xDF => MidiMessage::ChannelPressure {
+                channel,
+                pressure: data1,
+            },
+            0xE0..=0xEF => MidiMessage::PitchBend {
+                channel,
+                value: (data1 & 0x7F) as u16 | ((data2 & 0x7F) as u16) << 7,
+            },
+            0xF1 => MidiMessage::QuarterFrame(data1),
+            0xF3 => MidiMessage::SongSelect(data1),
+            0xF6 => MidiMessage::TuneRequest,
+            0xFE => MidiMessage::ActiveSensing,
+            0xFF => MidiMessage::SystemReset,
+            _ => match RealTimeMessage::from_message(&msg) {
+                Some(rt) => MidiMessage::RealTime(rt),
+                None => MidiMessage::Other(msg),
+            },
+        }
+    }
+}
+
+impl MidiMessage {
+    /// Short message of the message
+    /// Returns Error, for sysex messages and for a channel above 15 or a data byte above 127
+    fn to_short(&self) -> Result<LaunchMessage, MidiInterfaceError> {
+        let (status, channel, data1, data2) = match *self {
+            MidiMessage::NoteOff {
+                channel,
+                note,
+                velocity,
+            } => (0x80, channel, note, velocity),
+            MidiMessage::NoteOn {
+                channel,
+                note,
+                velocity,
+            } => (0x90, channel, note, velocity),
+            MidiMessage::PolyPressure {
+                channel,
+                note,
+                pressure,
+            } => (0xA0, channel, note, pressure),
+            MidiMessage::ControlChange {
+                channel,
+                controller,
+                value,
+            } => (0xB0, channel, controller, value),
+            MidiMessage::ProgramChange { channel, program } => (0xC0, channel, program, 0x00),
+            MidiMessage::ChannelPressure { channel, pressure } => (0xD0, channel, pressure, 0x00),
+            MidiMessage::PitchBend { value, .. }
+            | MidiMessage::RealTime(RealTimeMessage::SongPosition(value))
+                if value > 0x3FFF =>
+            {
+                return Err(MidiInterfaceError::Invalid(format!(
+                    "{:?}: 14 bit value out of range (0 to 16383)",
+                    self
+                )));
+            }
+            MidiMessage::PitchBend { channel, value } => {
+                (0xE0, channel, (value & 0x7F) as u8, (value >> 7) as u8)
+            }
+            MidiMessage::QuarterFrame(data) => (0xF1, 0, data, 0x00),
+            MidiMessage::SongSelect(song) => (0xF3, 0, song, 0x00),
+            MidiMessage::TuneRequest => (0xF6, 0, 0x00, 0x00),
+            MidiMessage::RealTime(rt) => return Ok(rt.into()),
+            MidiMessage::ActiveSensing => (0xFE, 0, 0x00, 0x00),
+            MidiMessage::SystemReset => (0xFF, 0, 0x00, 0x00),
+            MidiMessage::Other(ref msg) => return Ok(msg.clone()),
+            MidiMessage::SysEx(_) => {
+                return Err(MidiInterfaceError::Invalid(
+                    "sysex message does not fit a LaunchMessage".to_string(),
+                ))
+            }
+        };
+        if channel > 15 || data1 > 0x7F || data2 > 0x7F {
+            return Err(MidiInterfaceError::Invalid(format!(
+                "{:?}: channel (0 to 15) or data byte (0 to 127) out of range",
+                self
+            )));
+        }
+        Ok(LaunchMessage {
+            status: status | channel,
+            data1,
+            data2,
+        })
+    }
+}
+
+impl TryFrom<MidiMessage> for LaunchMessage {
+    type Error = MidiInterfaceError;
+    fn try_from(value: MidiMessage) -> Result<Self, Self::Error> {
+        value.to_short()
+    }
+}
+
 /// device identifier, either being a name (string) or a id (number)
 pub enum Identifier {
     String(String),
@@ -120,7 +586,9 @@ impl From<i32> for Identifier {
 }
 
 /// Error for midi interface to be implemented for midi backend
+/// (more variants may be added, so matches need a wildcard arm)
 #[derive(Debug)]
+#[non_exhaustive]
 pub enum MidiInterfaceError {
     Unknown(String),
     Unimplemented(String),
@@ -129,6 +597,8 @@ pub enum MidiInterfaceError {
     NotAnOutputDevice(String),
     Invalid(String),
     GenericBackendErr(String),
+    /// the device has been unplugged or stopped responding
+    Disconnected(String),
 }
 
 impl Error for MidiInterfaceError {}
@@ -143,7 +613,8 @@ impl Display for MidiInterfaceError {
             MidiInterfaceError::NotAnOutputDevice(msg) => msg.to_string(),
             MidiInterfaceError::Invalid(msg) => msg.to_string(),
             MidiInterfaceError::GenericBackendErr(msg) => msg.to_string(),
+            MidiInterfaceError::Disconnected(msg) => msg.to_string(),
         };
         write!(f, "E midi backend: {}", message)
     }
-}
\ No newline at end of file
+}
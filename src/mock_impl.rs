@@ -0,0 +1,366 @@
+/* Copyright (C) 2023 Lucas Haupt
+
+This program is distributed under the terms of the 
+GNU Lesser General Public License v3.0, 
+see COPYING.LESSER file for license information
+*/
+
+//! # Mock_Impl
+//!
+//! In-memory midi backend simulating connected Launchpads, for developing and
+//! testing without hardware. Every simulated unit has a MockSurface, which is the
+//! "hardware side" of the unit: it injects button presses and keeps track of the
+//! lights the application has set. Surfaces can be moved to other threads
+//! (e.g. a simulator drawing the Launchpad in the terminal).
+
+use std::collections::VecDeque;
+use std::sync::{Arc, Mutex, MutexGuard};
+use std::time::Instant;
+
+use crate::device_state::DeviceState;
+use crate::midilib::{
+    check_sysex, DeviceInfo, Direction, Identifier, Input, LaunchMessage, MidiInterface,
+    MidiInterfaceError, MidiMessage, Output, TimedMessage,
+};
+use crate::units::LAUNCHPAD_MINI_NAME;
+use crate::utils::PadIdentifier;
+use crate::MatPos;
+
+/// Shared state of a simulated unit
+#[derive(Default)]
+struct MockUnit {
+    /// messages waiting to be read by the application, with the time they were queued
+    incoming: VecDeque<(MidiMessage, Instant)>,
+    /// lights as set by the application
+    state: DeviceState,
+    /// messages sent by the application (only if logging is enabled)
+    sent: Vec<LaunchMessage>,
+    /// sysex messages sent by the application (only if logging is enabled)
+    sent_sysex: Vec<Vec<u8>>,
+    /// log sent messages
+    logging: bool,
+    /// unit is plugged in
+    connected: bool,
+}
+
+/// Lock the shared state of a unit (a poisoned lock is taken over)
+fn lock(unit: &Mutex<MockUnit>) -> MutexGuard<'_, MockUnit> {
+    unit.lock().unwrap_or_else(|e| e.into_inner())
+}
+
+/// Error returned by ports of an unplugged unit
+fn disconnected(unit: usize) -> MidiInterfaceError {
+    MidiInterfaceError::Disconnected(format!("mock unit {} is unplugged", unit))
+}
+
+/// Midi backend context simulating a number of Launchpad Mini units.
+/// Clones are contexts on the same simulated units (e.g. a context created after replugging)
+#[derive(Clone)]
+pub struct MockMidi {
+    units: Vec<Arc<Mutex<MockUnit>>>,
+}
+impl Default for MockMidi {
+    fn default() -> Self {
+        MockMidi::new()
+    }
+}
+impl MockMidi {
+    /// Simulate a single connected Launchpad Mini
+    pub fn new() -> MockMidi {
+        MockMidi::with_units(1)
+    }
+
+    /// Simulate a number of connected Launchpad Minis, named like on Windows
+    /// ("Launchpad Mini", "2- Launchpad Mini", ...)
+    pub fn with_units(count: usize) -> MockMidi {
+        let units = (0..count)
+            .map(|_| {
+                Arc::new(Mutex::new(MockUnit {
+                    connected: true,
+                    ..MockUnit::default()
+                }))
+            })
+            .collect();
+        MockMidi { units }
+    }
+
+    /// Return the hardware side of a unit, None if the unit does not exist
+    pub fn surface(&self, unit: usize) -> Option<MockSurface> {
+        let shared = self.units.get(unit)?.clone();
+        Some(MockSurface { unit, shared })
+    }
+
+    /// Port name of a unit
+    fn unit_name(unit: usize) -> String {
+        if unit == 0 {
+            LAUNCHPAD_MINI_NAME.to_string()
+        } else {
+            format!("{}- {}", unit + 1, LAUNCHPAD_MINI_NAME)
+        }
+    }
+
+    /// Find the unit of a device id or name in the supplied direction
+    fn find_unit(&self, identifier: Identifier, dir: Direction) -> Option<usize> {
+        let devs = self.get_devices().ok()?;
+        let dev = devs.into_iter().find(|d| {
+            d.dir == dir
+                && match &identifier {
+                    Identifier::String(name) => d.name == *name,
+                    Identifier::Number(id) => d.id == *id,
+                }
+        })?;
+        Some(dev.id as usize / 2)
+    }
+}
+
+/// Input port of a simulated unit
+pub struct MockInput {
+    unit: usize,
+    shared: Arc<Mutex<MockUnit>>,
+}
+
+/// Output port of a simulated unit
+pub struct MockOutput {
+    unit: usize,
+    shared: Arc<Mutex<MockUnit>>,
+}
+
+impl Input for MockInput {
+    fn poll(&self) -> Result<bool, MidiInterfaceError> {
+        let shared = lock(&self.shared);
+        if !shared.connected {
+            return Err(disconnected(self.unit));
+        }
+        Ok(!shared.incoming.is_empty())
+    }
+
+    fn read_n(&self, count: usize) -> Result<Option<Vec<LaunchMessage>>, MidiInterfaceError> {
+        let mut shared = lock(&self.shared);
+        if !shared.connected {
+            return Err(disconnected(self.unit));
+        }
+        if shared.incoming.is_empty() {
+            return Ok(None);
+        }
+        // sysex messages do not fit a LaunchMessage and are dropped
+        let n = count.min(shared.incoming.len());
+        Ok(Some(
+            shared
+                .incoming
+                .drain(..n)
+                .filter_map(|(msg, _)| LaunchMessage::try_from(msg).ok())
+                .collect(),
+        ))
+    }
+
+    fn read_n_timed(&self, count: usize) -> Result<Option<Vec<TimedMessage>>, MidiInterfaceError> {
+        let mut shared = lock(&self.shared);
+        if !shared.connected {
+            return Err(disconnected(self.unit));
+        }
+        if shared.incoming.is_empty() {
+            return Ok(None);
+        }
+        let n = count.min(shared.incoming.len());
+        Ok(Some(
+            shared
+                .incoming
+                .drain(..n)
+                .filter_map(|(msg, at)| {
+                    let msg = LaunchMessage::try_from(msg).ok()?;
+                    Some(TimedMessage::new(msg, Some(at)))
+                })
+                .collect(),
+        ))
+    }
+
+    fn read_midi(&self, count: usize) -> Result<Option<Vec<MidiMessage>>, MidiInterfaceError> {
+        let mut shared = lock(&self.shared);
+        if !shared.connected {
+            return Err(disconnected(self.unit));
+        }
+        if shared.incoming.is_empty() {
+            return Ok(None);
+        }
+        let n = count.min(shared.incoming.len());
+        Ok(Some(
+            shared.incoming.drain(..n).map(|(msg, _)| msg).collect(),
+        ))
+    }
+}
+
+impl Output for MockOutput {
+    fn write_message(&mut self, msg: LaunchMessage) -> Result<(), MidiInterfaceError> {
+        self.write_messages(vec![msg])
+    }
+
+    fn write_messages(&mut self, msgs: Vec<LaunchMessage>) -> Result<(), MidiInterfaceError> {
+        let mut shared = lock(&self.shared);
+        if !shared.connected {
+            return Err(disconnected(self.unit));
+        }
+        for msg in msgs.iter() {
+            shared.state.record(msg);
+        }
+        if shared.logging {
+            shared.sent.extend(msgs);
+        }
+        Ok(())
+    }
+
+    fn write_sysex(&mut self, msg: &[u8]) -> Result<(), MidiInterfaceError> {
+        check_sysex(msg)?;
+        let mut shared = lock(&self.shared);
+        if !shared.connected {
+            return Err(disconnected(self.unit));
+        }
+        if shared.logging {
+            shared.sent_sysex.push(msg.to_vec());
+        }
+        Ok(())
+    }
+}
+
+impl<'a> MidiInterface<'a> for MockMidi {
+    type MidiInput = MockInput;
+    type MidiOutput = MockOutput;
+
+    /// Inputs have even ids, outputs odd ids (unit = id / 2), unplugged units are left out
+    fn get_devices(&self) -> Result<Vec<DeviceInfo>, MidiInterfaceError> {
+        let mut devs = Vec::with_capacity(self.units.len() * 2);
+        for (i, unit) in self.units.iter().enumerate() {
+            if !lock(unit).connected {
+                continue;
+            }
+            for (offset, dir) in [Direction::Input, Direction::Output]
+                .into_iter()
+                .enumerate()
+            {
+                devs.push(DeviceInfo {
+                    id: (2 * i + offset) as i32,
+                    name: MockMidi::unit_name(i),
+                    dir,
+                });
+            }
+        }
+        Ok(devs)
+    }
+
+    fn get_input(&'a self, identifier: Identifier) -> Result<MockInput, MidiInterfaceError> {
+        match self.find_unit(identifier, Direction::Input) {
+            Some(unit) => Ok(MockInput {
+                unit,
+                shared: self.units[unit].clone(),
+            }),
+            None => Err(MidiInterfaceError::NotAnInputDevice(
+                "no such mock input".to_string(),
+            )),
+        }
+    }
+
+    fn get_output(&'a self, identifier: Identifier) -> Result<MockOutput, MidiInterfaceError> {
+        match self.find_unit(identifier, Direction::Output) {
+            Some(unit) => Ok(MockOutput {
+                unit,
+                shared: self.units[unit].clone(),
+            }),
+            None => Err(MidiInterfaceError::NotAnOutputDevice(
+                "no such mock output".to_string(),
+            )),
+        }
+    }
+
+    fn get_in_out(&'a self, name: &str) -> Result<(MockInput, MockOutput), MidiInterfaceError> {
+        let in_p = self.get_input(Identifier::from(name))?;
+        let out_p = self.get_output(Identifier::from(name))?;
+        Ok((in_p, out_p))
+    }
+
+    fn get_default_input(&'a self) -> Result<MockInput, MidiInterfaceError> {
+        self.get_input(Identifier::Number(0))
+    }
+
+    fn get_default_output(&'a self) -> Result<MockOutput, MidiInterfaceError> {
+        self.get_output(Identifier::Number(1))
+    }
+}
+
+/// Hardware side of a simulated unit
+#[derive(Clone)]
+pub struct MockSurface {
+    unit: usize,
+    shared: Arc<Mutex<MockUnit>>,
+}
+impl MockSurface {
+    /// Index of the simulated unit
+    pub fn unit(&self) -> usize {
+        self.unit
+    }
+
+    /// Queue a raw message for the application to read
+    pub fn push_message(&self, msg: LaunchMessage) {
+        self.push_midi(msg.into());
+    }
+
+    /// Queue a message of any kind (e.g. a sysex reply) for the application to read
+    pub fn push_midi(&self, msg: MidiMessage) {
+        lock(&self.shared).incoming.push_back((msg, Instant::now()));
+    }
+
+    /// Simulate pressing (true) or releasing (false) a button
+    /// (row 8 being the control button row)
+    pub fn set_pressed(&self, pos: MatPos, pressed: bool) {
+        let padid = PadIdentifier::from(pos);
+        self.push_message(LaunchMessage {
+            status: padid.status as u8,
+            data1: padid.key,
+            data2: if pressed { 0x7F } else { 0x00 },
+        });
+    }
+
+    /// Simulate pressing a button
+    pub fn press(&self, pos: MatPos) {
+        self.set_pressed(pos, true);
+    }
+
+    /// Simulate releasing a button
+    pub fn release(&self, pos: MatPos) {
+        self.set_pressed(pos, false);
+    }
+
+    /// Lights as set by the application
+    pub fn state(&self) -> DeviceState {
+        lock(&self.shared).state.clone()
+    }
+
+    /// Start or stop logging the messages sent by the application
+    pub fn set_logging(&self, logging: bool) {
+        lock(&self.shared).logging = logging;
+    }
+
+    /// Return and clear the messages logged since the last call
+    pub fn take_sent(&self) -> Vec<LaunchMessage> {
+        std::mem::take(&mut lock(&self.shared).sent)
+    }
+
+    /// Return and clear the sysex messages logged since the last call
+    pub fn take_sent_sysex(&self) -> Vec<Vec<u8>> {
+        std::mem::take(&mut lock(&self.shared).sent_sysex)
+    }
+
+    /// Simulate plugging the unit in (true) or pulling the cable (false).
+    /// Unplugging drops all pending input and resets the lights
+    pub fn set_connected(&self, connected: bool) {
+        let mut shared = lock(&self.shared);
+        if !connected {
+            shared.incoming.clear();
+            shared.state = DeviceState::default();
+        }
+        shared.connected = connected;
+    }
+
+    /// Return if the unit is plugged in
+    pub fn is_connected(&self) -> bool {
+        lock(&self.shared).connected
+    }
+}
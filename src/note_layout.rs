@@ -0,0 +1,397 @@
+/* Copyright (C) 2023 Lucas Haupt
+
+This program is distributed under the terms of the 
+GNU Lesser General Public License v3.0, 
+see COPYING.LESSER file for license information
+*/
+
+//! # Note layout
+//!
+//! Note mode turning the 8x8 grid into an isomorphic keyboard: every pad plays a note
+//! (lowest notes in the bottom row), laid out chromatically, in key or in fourths.
+//! Root notes and notes of the scale are lit in their own colors, pads of the notes held
+//! light up. Notes are sent to a midi output, the first two control buttons shift
+//! the octave.
+
+use std::collections::HashMap;
+
+use crate::launch_device::LaunchDevice;
+use crate::midilib::{Input, MidiInterfaceError, Output};
+use crate::pages::{frame_changes, frame_of, Frame};
+use crate::utils::{check_channel, note_message, release_notes, PadEvent};
+use crate::{Color, MatPos, GRID_SIZE, TOP_ROW};
+
+/// Highest octave (the note of the lower left pad being 12 * octave + root)
+pub const MAX_OCTAVE: u8 = 9;
+
+/// Column of the control button shifting an octave down
+const OCTAVE_DOWN_COL: u8 = 0;
+
+/// Column of the control button shifting an octave up
+const OCTAVE_UP_COL: u8 = 1;
+
+const OCTAVE_BUTTON_COLOR: Color = Color::MedYellow;
+
+/// Scale the in key layout and the coloring are based on
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+#[cfg_attr(
+    feature = "serde",
+    derive(serde::Serialize, serde::Deserialize),
+    serde(rename_all = "snake_case")
+)]
+pub enum Scale {
+    /// major (ionian)
+    Major,
+    /// natural minor (aeolian)
+    Minor,
+    Dorian,
+    Phrygian,
+    Lydian,
+    Mixolydian,
+    Locrian,
+    MajorPentatonic,
+    MinorPentatonic,
+    /// semitones of the scale as bits (bit 0 being the root, see Scale::custom)
+    Custom(u16),
+}
+impl Scale {
+    /// Scale of the supplied semitones above the root (0 to 11, the root is always included)
+    /// Returns Error, if a semitone is out of range
+    pub fn custom(semitones: &[u8]) -> Result<Scale, MidiInterfaceError> {
+        let mut mask: u16 = 0x001;
+        for s in semitones {
+            if *s > 11 {
+                return Err(MidiInterfaceError::Invalid(format!(
+                    "semitone {} out of range (0 to 11)",
+                    s
+                )));
+            }
+            mask |= 1 << s;
+        }
+        Ok(Scale::Custom(mask))
+    }
+
+    /// Semitones of the scale as bits (bit 0 being the root)
+    fn mask(self) -> u16 {
+        match self {
+            Scale::Major => 0b1010_1011_0101,
+            Scale::Minor => 0b0101_1010_1101,
+            Scale::Dorian => 0b0110_1010_1101,
+            Scale::Phrygian => 0b0101_1010_1011,
+            Scale::Lydian => 0b1010_1101_0101,
+            Scale::Mixolydian => 0b0110_1011_0101,
+            Scale::Locrian => 0b0101_0110_1011,
+            Scale::MajorPentatonic => 0b0010_1001_0101,
+            Scale::MinorPentatonic => 0b0100_1010_1001,
+            Scale::Custom(mask) => (mask & 0x0FFF) | 0x001,
+        }
+    }
+
+    /// Semitones of the scale above the root, ascending (starting with 0)
+    pub fn intervals(self) -> Vec<u8> {
+        let mask = self.mask();
+        (0..12u8).filter(|s| mask & (1 << s) != 0).collect()
+    }
+
+    /// Return if a number of semitones above the root (in any octave) is part of the scale
+    pub fn contains(self, semitones: u8) -> bool {
+        self.mask() & (1 << (semitones % 12)) != 0
+    }
+}
+
+/// Arrangement of the notes on the grid, notes rise from left to right and from bottom to top
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, Default)]
+#[cfg_attr(
+    feature = "serde",
+    derive(serde::Serialize, serde::Deserialize),
+    serde(rename_all = "snake_case")
+)]
+pub enum Layout {
+    /// a semitone per pad, every row continuing the row below (8 semitones per row)
+    Chromatic,
+    /// notes of the scale only, a scale degree per pad and three degrees per row
+    /// (a fourth in seven note scales)
+    #[default]
+    InKey,
+    /// a semitone per pad and a fourth (5 semitones) per row, like a bass guitar
+    Fourths,
+}
+
+/// Isomorphic keyboard on the 8x8 grid sending notes to a midi output
+pub struct NoteLayout<O: Output> {
+    out_port: O,
+    layout: Layout,
+    /// pitch class of the root (0 being C)
+    root: u8,
+    scale: Scale,
+    octave: u8,
+    /// midi channel (0 to 15)
+    channel: u8,
+    velocity: u8,
+    /// color of the pads playing the root
+    pub root_color: Color,
+    /// color of the pads playing other notes of the scale
+    pub scale_color: Color,
+    /// color of the pads playing notes not in the scale
+    pub other_color: Color,
+    /// color of the pads playing a note held
+    pub pressed_color: Color,
+    /// pads held and the note each one started
+    held: HashMap<MatPos, u8>,
+    shown: Option<Frame>,
+}
+impl<O: Output> NoteLayout<O> {
+    /// Create an in key layout in C major, the lower left pad playing note 36,
+    /// sending on channel 0
+    pub fn new(out_port: O) -> NoteLayout<O> {
+        NoteLayout {
+            out_port,
+            layout: Layout::default(),
+            root: 0,
+            scale: Scale::Major,
+            octave: 3,
+            channel: 0,
+            velocity: 0x64,
+            root_color: Color::Red,
+            scale_color: Color::DimGreen,
+            other_color: Color::Black,
+            pressed_color: Color::Green,
+            held: HashMap::new(),
+            shown: None,
+        }
+    }
+
+    /// Output port the notes are sent to
+    pub fn output(&mut self) -> &mut O {
+        &mut self.out_port
+    }
+
+    pub fn layout(&self) -> Layout {
+        self.layout
+    }
+
+    /// Change the layout (notes held keep sounding until released)
+    pub fn set_layout(&mut self, layout: Layout) {
+        self.layout = layout;
+    }
+
+    /// Pitch class of the root (0 being C, 11 being B)
+    pub fn root(&self) -> u8 {
+        self.root
+    }
+
+    /// Set the pitch class of the root
+    /// Returns Error, if it is not within 0 to 11
+    pub fn set_root(&mut self, root: u8) -> Result<(), MidiInterfaceError> {
+        if root > 11 {
+            return Err(MidiInterfaceError::Invalid(format!(
+                "root {} out of range (0 to 11)",
+                root
+            )));
+        }
+        self.root = root;
+        Ok(())
+    }
+
+    pub fn scale(&self) -> Scale {
+        self.scale
+    }
+
+    pub fn set_scale(&mut self, scale: Scale) {
+        self.scale = scale;
+    }
+
+    pub fn octave(&self) -> u8 {
+        self.octave
+    }
+
+    /// Set the octave of the lower left pad (playing 12 * octave + root)
+    /// Returns Error, if it is greater than MAX_OCTAVE
+    pub fn set_octave(&mut self, octave: u8) -> Result<(), MidiInterfaceError> {
+        if octave > MAX_OCTAVE {
+            return Err(MidiInterfaceError::Invalid(format!(
+                "octave {} out of range (0 to {})",
+                octave, MAX_OCTAVE
+            )));
+        }
+        self.octave = octave;
+        Ok(())
+    }
+
+    pub fn channel(&self) -> u8 {
+        self.channel
+    }
+
+    /// Set the midi channel the notes are sent on, notes held are released
+    /// on the previous channel first
+    /// Returns Error, if it is not within 0 to 15 or sending fails
+    pub fn set_channel(&mut self, channel: u8) -> Result<(), MidiInterfaceError> {
+        check_channel(channel)?;
+        self.release_all()?;
+        self.channel = channel;
+        Ok(())
+    }
+
+    pub fn velocity(&self) -> u8 {
+        self.velocity
+    }
+
+    /// Set the velocity the notes are sent with
+    /// Returns Error, if it is not within 1 to 127
+    pub fn set_velocity(&mut self, velocity: u8) -> Result<(), MidiInterfaceError> {
+        if velocity == 0 || velocity > 0x7F {
+            return Err(MidiInterfaceError::Invalid(format!(
+                "velocity {} out of range (1 to 127)",
+                velocity
+            )));
+        }
+        self.velocity = velocity;
+        Ok(())
+    }
+
+    /// Note played by a pad of the grid, None for other buttons
+    /// and pads beyond the midi note range
+    pub fn note(&self, pos: MatPos) -> Option<u8> {
+        if !pos.is_grid() {
+            return None;
+        }
+        let row = (GRID_SIZE - 1 - pos.row) as u32;
+        let col = pos.col as u32;
+        let base = 12 * self.octave as u32 + self.root as u32;
+        let note = match self.layout {
+            Layout::Chromatic => base + row * 8 + col,
+            Layout::Fourths => base + row * 5 + col,
+            Layout::InKey => {
+                let intervals = self.scale.intervals();
+                let degree = (row * 3 + col) as usize;
+                base + 12 * (degree / intervals.len()) as u32
+                    + intervals[degree % intervals.len()] as u32
+            }
+        };
+        u8::try_from(note).ok().filter(|n| *n <= 0x7F)
+    }
+
+    /// Return if a note is a root note
+    pub fn is_root(&self, note: u8) -> bool {
+        note % 12 == self.root
+    }
+
+    /// Return if a note is part of the scale
+    pub fn in_scale(&self, note: u8) -> bool {
+        self.scale.contains((note + 12 - self.root) % 12)
+    }
+
+    /// Return if a note is held on any pad
+    pub fn is_sounding(&self, note: u8) -> bool {
+        self.held.values().any(|n| *n == note)
+    }
+
+    /// Send a note on or off message
+    fn send(&mut self, on: bool, note: u8) -> Result<(), MidiInterfaceError> {
+        self.out_port
+            .write_message(note_message(self.channel, note, on, self.velocity))
+    }
+
+    /// Handle a button event: pads play their note while held (a note played by several
+    /// pads sounds until the last one is released), the first two control buttons shift
+    /// the octave down and up
+    /// Returns Error, if sending fails
+    pub fn handle(&mut self, event: PadEvent) -> Result<(), MidiInterfaceError> {
+        let pos = event.pos;
+        if pos.is_top_row() {
+            if event.pressed {
+                match pos.col {
+                    OCTAVE_DOWN_COL => self.octave = self.octave.saturating_sub(1),
+                    OCTAVE_UP_COL => self.octave = (self.octave + 1).min(MAX_OCTAVE),
+                    _ => {}
+                }
+            }
+            return Ok(());
+        }
+
+        if event.pressed {
+            let note = match self.note(pos) {
+                Some(note) => note,
+                None => return Ok(()),
+            };
+            if !self.is_sounding(note) {
+                self.send(true, note)?;
+            }
+            self.held.insert(pos, note);
+        } else if let Some(note) = self.held.remove(&pos) {
+            if !self.is_sounding(note) {
+                self.send(false, note)?;
+            }
+        }
+        Ok(())
+    }
+
+    /// Send note off for all notes held (e.g. before switching to another mode)
+    /// Returns Error, if sending fails
+    pub fn release_all(&mut self) -> Result<(), MidiInterfaceError> {
+        release_notes(&mut self.out_port, self.channel, &mut self.held)
+    }
+
+    /// Lights of the grid (notes held, root notes and notes of the scale)
+    /// and the octave buttons
+    pub fn render(&self) -> Vec<(MatPos, Color)> {
+        let mut res = Vec::new();
+        for row in 0..GRID_SIZE {
+            for col in 0..GRID_SIZE {
+                let pos = MatPos::new(row, col);
+                let note = match self.note(pos) {
+                    Some(note) => note,
+                    None => continue,
+                };
+                let color = if self.is_sounding(note) {
+                    self.pressed_color
+                } else if self.is_root(note) {
+                    self.root_color
+                } else if self.in_scale(note) {
+                    self.scale_color
+                } else {
+                    self.other_color
+                };
+                res.push((pos, color));
+            }
+        }
+
+        if self.octave > 0 {
+            res.push((MatPos::new(TOP_ROW, OCTAVE_DOWN_COL), OCTAVE_BUTTON_COLOR));
+        }
+        if self.octave < MAX_OCTAVE {
+            res.push((MatPos::new(TOP_ROW, OCTAVE_UP_COL), OCTAVE_BUTTON_COLOR));
+        }
+        res
+    }
+
+    /// Update the lights of the Launchpad, only changed lights are sent
+    /// Returns Error, if action fails
+    pub fn draw<I: Input, L: Output>(
+        &mut self,
+        dev: &mut LaunchDevice<I, L>,
+    ) -> Result<(), MidiInterfaceError> {
+        let frame = frame_of(self.render());
+        let msgs = frame_changes(self.shown.as_ref(), &frame);
+        if !msgs.is_empty() {
+            dev.send_messages(msgs)?;
+        }
+        self.shown = Some(frame);
+        Ok(())
+    }
+
+    /// Read up to n messages from the Launchpad, play the notes and update the lights
+    /// Returns Error, if reading, sending or drawing fails
+    pub fn process<I: Input, L: Output>(
+        &mut self,
+        dev: &mut LaunchDevice<I, L>,
+        n: usize,
+    ) -> Result<(), MidiInterfaceError> {
+        if dev.poll()? {
+            for event in dev.read_events(n)? {
+                self.handle(event)?;
+            }
+        }
+        self.draw(dev)
+    }
+}
@@ -0,0 +1,298 @@
+/* Copyright (C) 2023 Lucas Haupt
+
+This program is distributed under the terms of the 
+GNU Lesser General Public License v3.0, 
+see COPYING.LESSER file for license information
+*/
+
+//! # Pages
+//!
+//! Page manager for apps with several modes sharing the grid. Every page renders
+//! its own lights and handles the button events while it is shown, pages keep their
+//! state while hidden. The round control buttons (or the scene buttons) select the page.
+//! Pages are drawn with double buffering, so switching pages does not flicker
+//! and only changed lights are sent.
+
+use crate::launch_device::LaunchDevice;
+use crate::midilib::{Input, LaunchMessage, MidiInterfaceError, Output};
+use crate::utils::{light_message, BufferSetting, PadEvent};
+use crate::widgets::{WidgetEvent, WidgetSet};
+use crate::{Color, MatPos, SCENE_COL, TOP_ROW};
+
+/// Maximum number of pages (one per navigation button)
+pub const MAX_PAGES: usize = 8;
+
+/// Lights of all buttons, indexed by row and column (row 8 being the control button row)
+pub(crate) type Frame = [[Color; 9]; 9];
+
+/// Frame with the supplied lights set and all other lights off
+/// (positions outside of the Launchpad are ignored)
+pub(crate) fn frame_of(lights: Vec<(MatPos, Color)>) -> Frame {
+    let mut frame = [[Color::Black; 9]; 9];
+    for (pos, color) in lights {
+        if pos.is_valid() {
+            frame[pos.row as usize][pos.col as usize] = color;
+        }
+    }
+    frame
+}
+
+/// Messages setting the lights differing between the frame shown and a new frame
+/// (all lights, if nothing has been shown yet)
+pub(crate) fn frame_changes(shown: Option<&Frame>, frame: &Frame) -> Vec<LaunchMessage> {
+    let mut msgs: Vec<LaunchMessage> = Vec::new();
+    for row in 0..9u8 {
+        for col in 0..9u8 {
+            if row == 8 && col == 8 {
+                continue;
+            }
+            let color = frame[row as usize][col as usize];
+            let changed = match shown {
+                Some(shown) => shown[row as usize][col as usize] != color,
+                None => true,
+            };
+            if changed {
+                msgs.push(light_message(MatPos::new(row, col), color));
+            }
+        }
+    }
+    msgs
+}
+
+/// A screen of a multi-mode app
+pub trait Page {
+    /// Event reported by the page to the app
+    type Event;
+
+    /// Handle a button event while the page is shown, returns the resulting events
+    fn handle(&mut self, event: PadEvent) -> Vec<Self::Event>;
+
+    /// Colors of the buttons of the page, buttons left out are turned off
+    /// (lights of the navigation buttons are drawn by the page manager)
+    fn render(&self) -> Vec<(MatPos, Color)>;
+}
+
+impl Page for WidgetSet {
+    type Event = WidgetEvent;
+
+    fn handle(&mut self, event: PadEvent) -> Vec<WidgetEvent> {
+        WidgetSet::handle(self, event).into_iter().collect()
+    }
+
+    fn render(&self) -> Vec<(MatPos, Color)> {
+        (0..self.len())
+            .filter_map(|i| self.get(i))
+            .flat_map(|w| w.render())
+            .collect()
+    }
+}
+
+/// Buttons selecting the page
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+#[cfg_attr(
+    feature = "serde",
+    derive(serde::Serialize, serde::Deserialize),
+    serde(rename_all = "snake_case")
+)]
+pub enum Navigation {
+    /// round control buttons, from left to right
+    #[default]
+    TopRow,
+    /// scene buttons, from top to bottom
+    SceneColumn,
+}
+impl Navigation {
+    /// Position of the button selecting a page
+    pub(crate) fn button(self, page: usize) -> MatPos {
+        match self {
+            Navigation::TopRow => MatPos::new(TOP_ROW, page as u8),
+            Navigation::SceneColumn => MatPos::new(page as u8, SCENE_COL),
+        }
+    }
+
+    /// Page selected by the button at a position, None if it is no navigation button
+    fn page(self, pos: MatPos) -> Option<usize> {
+        match self {
+            Navigation::TopRow if pos.is_top_row() => Some(pos.col as usize),
+            Navigation::SceneColumn if pos.is_scene() => Some(pos.row as usize),
+            _ => None,
+        }
+    }
+}
+
+/// Event of the page manager
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum PageEvent<E> {
+    /// another page is shown
+    Switched(usize),
+    /// event reported by a page
+    Page { page: usize, event: E },
+}
+
+/// Switches between pages and draws the page shown
+pub struct PageManager<E> {
+    pub navigation: Navigation,
+    /// color of the navigation button of the page shown
+    pub selected_color: Color,
+    /// color of the navigation buttons of the other pages
+    pub page_color: Color,
+    pages: Vec<Box<dyn Page<Event = E>>>,
+    current: usize,
+    /// lights as drawn on the Launchpad, None if the page has not been drawn yet
+    shown: Option<Frame>,
+}
+impl<E> PageManager<E> {
+    pub fn new(navigation: Navigation, selected_color: Color, page_color: Color) -> PageManager<E> {
+        PageManager {
+            navigation,
+            selected_color,
+            page_color,
+            pages: Vec::new(),
+            current: 0,
+            shown: None,
+        }
+    }
+
+    /// Add a page, returns its index
+    /// Returns Error, if there already are MAX_PAGES pages
+    pub fn add(
+        &mut self,
+        page: impl Page<Event = E> + 'static,
+    ) -> Result<usize, MidiInterfaceError> {
+        if self.pages.len() >= MAX_PAGES {
+            return Err(MidiInterfaceError::Invalid(format!(
+                "no more than {} pages possible",
+                MAX_PAGES
+            )));
+        }
+        self.pages.push(Box::new(page));
+        Ok(self.pages.len() - 1)
+    }
+
+    /// Index of the page shown
+    pub fn current(&self) -> usize {
+        self.current
+    }
+
+    pub fn page(&self, page: usize) -> Option<&dyn Page<Event = E>> {
+        self.pages.get(page).map(|p| p.as_ref())
+    }
+
+    /// Access a page, e.g. to change its state from the app
+    /// (changes become visible with the next update)
+    pub fn page_mut(&mut self, page: usize) -> Option<&mut (dyn Page<Event = E> + 'static)> {
+        self.pages.get_mut(page).map(|p| p.as_mut())
+    }
+
+    pub fn len(&self) -> usize {
+        self.pages.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.pages.is_empty()
+    }
+
+    /// Show another page (drawn with the next update)
+    /// Returns Error, if the page does not exist
+    pub fn select(&mut self, page: usize) -> Result<(), MidiInterfaceError> {
+        if page >= self.pages.len() {
+            return Err(MidiInterfaceError::Invalid(format!(
+                "page {} does not exist",
+                page
+            )));
+        }
+        self.current = page;
+        Ok(())
+    }
+
+    /// Handle a button event: navigation buttons switch the page,
+    /// all other buttons are passed to the page shown
+    pub fn handle(&mut self, event: PadEvent) -> Vec<PageEvent<E>> {
+        if let Some(page) = self.navigation.page(event.pos) {
+            if event.pressed && page < self.pages.len() && page != self.current {
+                self.current = page;
+                return vec![PageEvent::Switched(page)];
+            }
+            return Vec::new();
+        }
+
+        let page = self.current;
+        match self.pages.get_mut(page) {
+            Some(p) => p
+                .handle(event)
+                .into_iter()
+                .map(|event| PageEvent::Page { page, event })
+                .collect(),
+            None => Vec::new(),
+        }
+    }
+
+    /// Lights of the page shown together with the navigation buttons
+    fn render(&self) -> Frame {
+        let lights = match self.pages.get(self.current) {
+            Some(page) => page.render(),
+            None => Vec::new(),
+        };
+        let mut frame = frame_of(lights);
+        for page in 0..self.pages.len() {
+            let pos = self.navigation.button(page);
+            frame[pos.row as usize][pos.col as usize] = if page == self.current {
+                self.selected_color
+            } else {
+                self.page_color
+            };
+        }
+        frame
+    }
+
+    /// Draw the page shown, only lights changed since the last update are sent.
+    /// Lights are written to the hidden buffer, which is then displayed
+    /// (double buffering is enabled by the first update)
+    /// Returns Error, if action fails
+    pub fn update<I: Input, O: Output>(
+        &mut self,
+        dev: &mut LaunchDevice<I, O>,
+    ) -> Result<(), MidiInterfaceError> {
+        let frame = self.render();
+        if !dev.is_double_buffered() {
+            dev.set_buffer_mode(BufferSetting::ZeroActive, false)?;
+            self.shown = None;
+        }
+
+        let msgs = frame_changes(self.shown.as_ref(), &frame);
+        if !msgs.is_empty() {
+            dev.send_messages(msgs)?;
+            dev.swap_buffers(true)?;
+        }
+        self.shown = Some(frame);
+        Ok(())
+    }
+
+    /// Draw the page shown completely (e.g. after the Launchpad has been reset)
+    /// Returns Error, if action fails
+    pub fn redraw<I: Input, O: Output>(
+        &mut self,
+        dev: &mut LaunchDevice<I, O>,
+    ) -> Result<(), MidiInterfaceError> {
+        self.shown = None;
+        self.update(dev)
+    }
+
+    /// Read up to n messages from the Launchpad, handle them,
+    /// update the lights and return the resulting events
+    /// Returns Error, if reading or drawing fails
+    pub fn process<I: Input, O: Output>(
+        &mut self,
+        dev: &mut LaunchDevice<I, O>,
+        n: usize,
+    ) -> Result<Vec<PageEvent<E>>, MidiInterfaceError> {
+        let mut res = Vec::new();
+        if dev.poll()? {
+            for event in dev.read_events(n)? {
+                res.extend(self.handle(event));
+            }
+        }
+        self.update(dev)?;
+        Ok(res)
+    }
+}
@@ -10,16 +10,236 @@ see COPYING.LESSER file for license information
 //! Reference implementation for a midi backend
 //! Implementation of the midilib traits for Portmidi library
 
+use std::cell::RefCell;
+use std::os::raw::{c_int, c_void};
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
+
 use crate::midilib::MidiInterfaceError;
-use crate::midilib::{DeviceInfo, Direction, Identifier, LaunchMessage};
+use crate::midilib::{check_sysex, DeviceInfo, Direction, Identifier, LaunchMessage, TimedMessage};
 use crate::{midilib as midi, BUFFER_SIZE};
 use portmidi as pm;
 use portmidi::{MidiEvent, MidiMessage};
 
-/// Type aliases to be implemented for abstraction of midi backend
-pub type InputPort<'a> = portmidi::InputPort<'a>;
-pub type OutputPort<'a> = portmidi::OutputPort<'a>;
-pub type MidiImpl = portmidi::PortMidi;
+/// Latency of output ports in milliseconds: PortMidi only honours the timestamps
+/// of output events (see write_messages_timed) on ports opened with a latency.
+/// Every write goes through the timestamped output of PortMidi then, writes without
+/// timestamp are sent with timestamp 0 (due at once), so they are delayed by at most
+/// the latency
+const OUTPUT_LATENCY: i32 = 1;
+
+/// Functions of the PortMidi library not exposed by the portmidi crate
+/// (opening an output with a latency and a time procedure)
+mod ffi {
+    use std::os::raw::{c_int, c_void};
+
+    /// Procedure returning the current time in milliseconds (PmTimeProcPtr)
+    pub type TimeProc = extern "C" fn(*mut c_void) -> i32;
+
+    /// Event with its timestamp in milliseconds (PmEvent)
+    #[repr(C)]
+    pub struct PmEvent {
+        pub message: i32,
+        pub timestamp: i32,
+    }
+
+    #[link(name = "portmidi")]
+    extern "C" {
+        pub fn Pm_OpenOutput(
+            stream: *mut *mut c_void,
+            output_device: c_int,
+            output_driver_info: *mut c_void,
+            buffer_size: i32,
+            time_proc: Option<TimeProc>,
+            time_info: *mut c_void,
+            latency: i32,
+        ) -> c_int;
+        pub fn Pm_Close(stream: *mut c_void) -> c_int;
+        pub fn Pm_Write(stream: *mut c_void, buffer: *const PmEvent, length: i32) -> c_int;
+        pub fn Pm_WriteShort(stream: *mut c_void, when: i32, msg: i32) -> c_int;
+        pub fn Pm_WriteSysEx(stream: *mut c_void, when: i32, msg: *const u8) -> c_int;
+    }
+}
+
+/// Turn a PortMidi result code into an Error (negative codes are errors)
+fn check(code: c_int) -> Result<(), MidiInterfaceError> {
+    let err = match code {
+        0.. => return Ok(()),
+        -10000 => pm::PmError::PmHostError,
+        -9999 => pm::PmError::PmInvalidDeviceId,
+        -9998 => pm::PmError::PmInsufficientMemory,
+        -9997 => pm::PmError::PmBufferTooSmall,
+        -9996 => pm::PmError::PmBufferOverflow,
+        -9995 => pm::PmError::PmBadPtr,
+        -9994 => pm::PmError::PmBadData,
+        -9993 => pm::PmError::PmInternalError,
+        -9992 => pm::PmError::PmBufferMaxSize,
+        -9989 => {
+            return Err(MidiInterfaceError::GenericBackendErr(
+                "PortMidi: `Name conflict'".to_string(),
+            ))
+        }
+        _ => {
+            return Err(MidiInterfaceError::GenericBackendErr(format!(
+                "PortMidi error {}",
+                code
+            )))
+        }
+    };
+    Err(pm::Error::PortMidi(err).into())
+}
+
+/// Time procedure of the output ports: milliseconds since the start of the context
+/// (time_info points to the start instant of the context)
+extern "C" fn elapsed_ms(time_info: *mut c_void) -> i32 {
+    // SAFETY: time_info is the start instant of the context, which outlives its ports
+    let start = unsafe { &*(time_info as *const Instant) };
+    start.elapsed().as_millis() as i32
+}
+
+/// Short message packed into a PortMidi message (Pm_Message)
+fn pack(msg: &LaunchMessage) -> i32 {
+    msg.status as i32 | (msg.data1 as i32) << 8 | (msg.data2 as i32) << 16
+}
+
+/// PortMidi context: initializes PortMidi on creation and terminates it on drop
+pub struct MidiImpl {
+    pm: pm::PortMidi,
+    /// start of the clock the timestamps of output events refer to
+    start: Instant,
+    /// estimated start of the PortTime clock the timestamps of received events refer to
+    time_base: Mutex<Option<Instant>>,
+}
+impl MidiImpl {
+    /// Initialize PortMidi (only devices connected at this point are found)
+    /// Returns Error, if PortMidi can not be initialized
+    pub fn new() -> Result<MidiImpl, MidiInterfaceError> {
+        Ok(MidiImpl {
+            pm: pm::PortMidi::new()?,
+            start: Instant::now(),
+            time_base: Mutex::new(None),
+        })
+    }
+
+    /// The PortMidi context
+    pub fn portmidi(&self) -> &pm::PortMidi {
+        &self.pm
+    }
+
+    /// Instant of a PortMidi timestamp (milliseconds of the PortTime clock) read now.
+    /// An event is received before it is read, so the PortTime clock can not have started
+    /// later than the time of reading minus the timestamp: the earliest of these bounds
+    /// is taken as start, which gets exact to the millisecond when polling often
+    fn event_instant(&self, timestamp: u32, now: Instant) -> Instant {
+        let since_start = Duration::from_millis(timestamp as u64);
+        let bound = now.checked_sub(since_start).unwrap_or(now);
+        let mut base = self.time_base.lock().unwrap_or_else(|e| e.into_inner());
+        let start = match *base {
+            Some(start) if start <= bound => start,
+            _ => bound,
+        };
+        *base = Some(start);
+        start + since_start
+    }
+
+    /// Open an input port on a device
+    fn open_input(&self, dev: pm::DeviceInfo) -> Result<InputPort<'_>, MidiInterfaceError> {
+        Ok(InputPort {
+            port: self.pm.input_port(dev, BUFFER_SIZE)?,
+            ctx: self,
+            sysex: RefCell::new(None),
+        })
+    }
+
+    /// Open an output port on a device, with a latency and the clock of the context
+    fn open_output(&self, dev: pm::DeviceInfo) -> Result<OutputPort<'_>, MidiInterfaceError> {
+        if dev.is_input() {
+            return Err(MidiInterfaceError::NotAnOutputDevice(format!(
+                "{} is no output",
+                dev.name()
+            )));
+        }
+        let mut stream: *mut c_void = std::ptr::null_mut();
+        // SAFETY: the context (and its start instant) outlives the port borrowing it
+        check(unsafe {
+            ffi::Pm_OpenOutput(
+                &mut stream,
+                dev.id(),
+                std::ptr::null_mut(),
+                BUFFER_SIZE as i32,
+                Some(elapsed_ms),
+                &self.start as *const Instant as *mut c_void,
+                OUTPUT_LATENCY,
+            )
+        })?;
+        Ok(OutputPort {
+            stream,
+            ctx: self,
+            device: dev,
+        })
+    }
+}
+
+/// Input port of PortMidi, keeping an unfinished sysex message until the next read.
+/// Wraps the port of the portmidi crate (this used to be a type alias of it),
+/// which port() returns
+pub struct InputPort<'a> {
+    port: pm::InputPort<'a>,
+    ctx: &'a MidiImpl,
+    /// bytes of a sysex message whose end has not been read yet
+    sysex: RefCell<Option<Vec<u8>>>,
+}
+impl<'a> InputPort<'a> {
+    /// The PortMidi port
+    pub fn port(&self) -> &pm::InputPort<'a> {
+        &self.port
+    }
+}
+
+/// Output port of PortMidi, opened with a latency so timestamps of events are honoured
+/// (see OUTPUT_LATENCY). The portmidi crate only opens outputs without latency, so the
+/// port is opened through the PortMidi library directly and is no longer a type alias
+/// of the port of the portmidi crate
+pub struct OutputPort<'a> {
+    stream: *mut c_void,
+    ctx: &'a MidiImpl,
+    device: pm::DeviceInfo,
+}
+impl OutputPort<'_> {
+    /// Device the port belongs to
+    pub fn device(&self) -> pm::DeviceInfo {
+        self.device.clone()
+    }
+
+    /// PortMidi timestamp of an instant (milliseconds of the clock of the context,
+    /// less the latency added by PortMidi), 0 for at once
+    fn timestamp(&self, at: Option<Instant>) -> i32 {
+        match at {
+            Some(at) => {
+                let ms = at.saturating_duration_since(self.ctx.start).as_millis() as i32;
+                (ms - OUTPUT_LATENCY).max(0)
+            }
+            None => 0,
+        }
+    }
+
+    /// Write events with timestamps
+    fn write_events(&mut self, events: Vec<ffi::PmEvent>) -> Result<(), MidiInterfaceError> {
+        for chunk in events.chunks(BUFFER_SIZE) {
+            // SAFETY: the stream is open while the port exists
+            check(unsafe { ffi::Pm_Write(self.stream, chunk.as_ptr(), chunk.len() as i32) })?;
+        }
+        Ok(())
+    }
+}
+/// PortMidi streams may be used from another thread than the one opening them
+unsafe impl Send for OutputPort<'_> {}
+impl Drop for OutputPort<'_> {
+    fn drop(&mut self) {
+        // SAFETY: the stream is open and not used after this
+        let _ = check(unsafe { ffi::Pm_Close(self.stream) });
+    }
+}
 
 /// Implementation of the Error type MidiInterfaceError
 impl From<pm::types::Error> for MidiInterfaceError {
@@ -33,6 +253,10 @@ impl From<pm::types::Error> for MidiInterfaceError {
                 MidiInterfaceError::NotAnOutputDevice(value.to_string())
             }
             pm::Error::NotAnInputDevice => MidiInterfaceError::NotAnInputDevice(value.to_string()),
+            // the host api fails on ports of devices that have been unplugged
+            pm::Error::PortMidi(pm::PmError::PmHostError) => {
+                MidiInterfaceError::Disconnected(value.to_string())
+            }
             pm::Error::PortMidi(err) => MidiInterfaceError::GenericBackendErr(err.to_string()),
         }
     }
@@ -107,10 +331,10 @@ impl From<LaunchMessage> for MidiEvent {
 /// Implementation of the Input trait (required for LaunchDevice)
 impl midi::Input for InputPort<'_> {
     fn poll(&self) -> Result<bool, MidiInterfaceError> {
-        Ok(self.poll()?)
+        Ok(self.port.poll()?)
     }
     fn read_n(&self, count: usize) -> Result<Option<Vec<LaunchMessage>>, MidiInterfaceError> {
-        let res = self.read_n(count)?;
+        let res = self.port.read_n(count)?;
         let res = match res {
             None => None,
             Some(events) => {
@@ -123,16 +347,114 @@ impl midi::Input for InputPort<'_> {
         };
         Ok(res)
     }
+
+    fn read_n_timed(&self, count: usize) -> Result<Option<Vec<TimedMessage>>, MidiInterfaceError> {
+        let now = Instant::now();
+        Ok(self.port.read_n(count)?.map(|events| {
+            events
+                .into_iter()
+                .map(|ev| {
+                    let at = self.ctx.event_instant(ev.timestamp, now);
+                    TimedMessage::new(LaunchMessage::from(ev.message), Some(at))
+                })
+                .collect()
+        }))
+    }
+
+    /// PortMidi delivers sysex messages in events of 4 bytes each (real-time messages
+    /// may be interleaved), these are joined to complete sysex messages. A sysex message
+    /// not complete yet is kept and continued by the next read, so the read never waits
+    fn read_midi(
+        &self,
+        count: usize,
+    ) -> Result<Option<Vec<midi::MidiMessage>>, MidiInterfaceError> {
+        let events = match self.port.read_n(count)? {
+            Some(events) => events,
+            None => return Ok(None),
+        };
+        let mut res: Vec<midi::MidiMessage> = Vec::new();
+        let mut sysex = self.sysex.borrow_mut();
+
+        for ev in events {
+            let msg = ev.message;
+            let realtime = msg.status >= 0xF8;
+            // a status byte other than real-time or 0xF7 ends an unfinished sysex
+            let interrupted = msg.status > 0x7F && msg.status != 0xF7 && !realtime;
+            if sysex.is_some() && interrupted {
+                *sysex = None;
+            }
+
+            match sysex.as_mut() {
+                None if msg.status == 0xF0 => *sysex = Some(Vec::new()),
+                None => {
+                    res.push(LaunchMessage::from(msg).into());
+                    continue;
+                }
+                Some(_) if realtime => {
+                    res.push(LaunchMessage::from(msg).into());
+                    continue;
+                }
+                Some(_) => {}
+            }
+
+            let buf = sysex.get_or_insert_with(Vec::new);
+            for byte in [msg.status, msg.data1, msg.data2, msg.data3] {
+                buf.push(byte);
+                if byte == 0xF7 {
+                    break;
+                }
+            }
+            if buf.last() == Some(&0xF7) {
+                if let Some(bytes) = sysex.take() {
+                    res.push(midi::MidiMessage::SysEx(bytes));
+                }
+            }
+        }
+
+        Ok(Some(res))
+    }
 }
 
 /// Implementation of the Output trait (required for LaunchDevice)
 impl midi::Output for OutputPort<'_> {
     fn write_message(&mut self, msg: LaunchMessage) -> Result<(), MidiInterfaceError> {
-        Ok(self.write_message(MidiMessage::from(msg))?)
+        // SAFETY: the stream is open while the port exists
+        check(unsafe { ffi::Pm_WriteShort(self.stream, 0, pack(&msg)) })
     }
 
     fn write_messages(&mut self, msgs: Vec<LaunchMessage>) -> Result<(), MidiInterfaceError> {
-        Ok(self.write_events(msgs)?)
+        let events = msgs
+            .iter()
+            .map(|msg| ffi::PmEvent {
+                message: pack(msg),
+                timestamp: 0,
+            })
+            .collect();
+        self.write_events(events)
+    }
+
+    /// The messages are handed to PortMidi with their timestamps at once, PortMidi
+    /// writes them when they are due (the call does not block). Timestamps are kept
+    /// in order, so messages without timestamp follow the messages before them
+    fn write_messages_timed(&mut self, msgs: Vec<TimedMessage>) -> Result<(), MidiInterfaceError> {
+        let mut last = 0;
+        let events = msgs
+            .iter()
+            .map(|timed| {
+                last = last.max(self.timestamp(timed.at));
+                ffi::PmEvent {
+                    message: pack(&timed.msg),
+                    timestamp: last,
+                }
+            })
+            .collect();
+        self.write_events(events)
+    }
+
+    fn write_sysex(&mut self, msg: &[u8]) -> Result<(), MidiInterfaceError> {
+        check_sysex(msg)?;
+        // SAFETY: the stream is open while the port exists, the message ends with 0xF7
+        check(unsafe { ffi::Pm_WriteSysEx(self.stream, 0, msg.as_ptr()) })
     }
 }
 
@@ -142,12 +464,12 @@ impl<'a> midi::MidiInterface<'a> for MidiImpl {
     type MidiOutput = OutputPort<'a>;
 
     fn get_devices(&self) -> Result<Vec<DeviceInfo>, MidiInterfaceError> {
-        let res = self.devices()?;
+        let res = self.pm.devices()?;
         Ok(res.into_iter().map(DeviceInfo::from).collect())
     }
 
     fn get_input(&'a self, identifier: Identifier) -> Result<InputPort<'a>, MidiInterfaceError> {
-        let devs: Vec<pm::DeviceInfo> = self.devices()?;
+        let devs: Vec<pm::DeviceInfo> = self.pm.devices()?;
         let input: pm::DeviceInfo = match identifier {
             Identifier::String(name) => {
                 let filt_devs = devs
@@ -181,11 +503,11 @@ impl<'a> midi::MidiInterface<'a> for MidiImpl {
             }
         };
 
-        Ok(self.input_port(input, BUFFER_SIZE)?)
+        self.open_input(input)
     }
 
     fn get_output(&'a self, identifier: Identifier) -> Result<OutputPort<'a>, MidiInterfaceError> {
-        let devs: Vec<pm::DeviceInfo> = self.devices()?;
+        let devs: Vec<pm::DeviceInfo> = self.pm.devices()?;
         let output: pm::DeviceInfo = match identifier {
             Identifier::String(name) => {
                 let filt_devs = devs
@@ -219,14 +541,14 @@ impl<'a> midi::MidiInterface<'a> for MidiImpl {
             }
         };
 
-        Ok(self.output_port(output, BUFFER_SIZE)?)
+        self.open_output(output)
     }
 
     fn get_in_out(
         &'a self,
         name: &str,
     ) -> Result<(Self::MidiInput, Self::MidiOutput), MidiInterfaceError> {
-        let devs: Vec<pm::DeviceInfo> = self.devices()?;
+        let devs: Vec<pm::DeviceInfo> = self.pm.devices()?;
         let devs: Vec<pm::DeviceInfo> = devs.into_iter().filter(|d| d.name() == name).collect();
 
         let in_p: Vec<&pm::DeviceInfo> = devs.iter().filter(|d| d.is_input()).collect();
@@ -251,23 +573,21 @@ impl<'a> midi::MidiInterface<'a> for MidiImpl {
             Some(d) => d.to_owned().to_owned(),
         };
 
-        let in_p = self.input_port(in_p, BUFFER_SIZE)?;
-        let out_p = self.output_port(out_p, BUFFER_SIZE)?;
+        let in_p = self.open_input(in_p)?;
+        let out_p = self.open_output(out_p)?;
 
         Ok((in_p, out_p))
     }
 
     fn get_default_input(&'a self) -> Result<Self::MidiInput, MidiInterfaceError> {
-        let id = self.default_input_device_id()?;
-        let dev = self.device(id)?;
-        let dev = self.input_port(dev, BUFFER_SIZE)?;
-        Ok(dev)
+        let id = self.pm.default_input_device_id()?;
+        let dev = self.pm.device(id)?;
+        self.open_input(dev)
     }
 
     fn get_default_output(&'a self) -> Result<Self::MidiOutput, MidiInterfaceError> {
-        let id = self.default_output_device_id()?;
-        let dev = self.device(id)?;
-        let dev = self.output_port(dev, BUFFER_SIZE)?;
-        Ok(dev)
+        let id = self.pm.default_output_device_id()?;
+        let dev = self.pm.device(id)?;
+        self.open_output(dev)
     }
-}
\ No newline at end of file
+}
@@ -0,0 +1,306 @@
+/* Copyright (C) 2023 Lucas Haupt
+
+This program is distributed under the terms of the 
+GNU Lesser General Public License v3.0, 
+see COPYING.LESSER file for license information
+*/
+
+//! # Rate limit
+//!
+//! Output stage keeping the messages sent to a Launchpad within a budget of messages
+//! per second, the Launchpad Mini drops or delays light changes when flooded
+//! (e.g. by full frames at 60 fps). Messages beyond the budget are held back and sent
+//! later, a light changed again before its previous change was sent is only sent once.
+//! Frames changing more than half of the lights are sent as rapid updates (two lights
+//! per message) when possible. Frames not sent completely right away are counted
+//! as dropped.
+
+use std::time::Instant;
+
+use crate::device_state::{rapid_position, DeviceState, RAPID_LEDS, RAPID_UPDATE};
+use crate::midilib::{LaunchMessage, MidiInterfaceError, Output};
+use crate::sequencer::{Clock, SystemClock};
+use crate::utils::{led_flags, led_key};
+use crate::MatPos;
+
+/// Number of messages of a complete rapid update (grid mode message resetting
+/// the position and two lights per message)
+const RAPID_FRAME_LEN: usize = 1 + RAPID_LEDS / 2;
+
+/// Velocity flag: write the color to the other buffer as well
+const FLAG_COPY: u8 = 0x04;
+
+/// Velocity flag: clear the other buffer's copy of the light
+const FLAG_CLEAR: u8 = 0x08;
+
+/// Budget of messages sent to the output
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[cfg_attr(
+    feature = "serde",
+    derive(serde::Serialize, serde::Deserialize),
+    serde(rename_all = "snake_case")
+)]
+pub struct RateLimit {
+    /// messages per second sent on average
+    pub messages_per_second: u32,
+    /// messages sent at once after a pause (e.g. one full frame)
+    pub burst: u32,
+}
+impl Default for RateLimit {
+    fn default() -> Self {
+        RateLimit {
+            messages_per_second: 400,
+            burst: 100,
+        }
+    }
+}
+
+/// Check that budget and burst allow sending at all
+fn check_limit(limit: RateLimit) -> Result<(), MidiInterfaceError> {
+    if limit.messages_per_second == 0 || limit.burst == 0 {
+        return Err(MidiInterfaceError::Invalid(
+            "rate limit and burst have to be at least 1".to_string(),
+        ));
+    }
+    Ok(())
+}
+
+/// Counters of a rate limiter
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct RateLimitStats {
+    /// messages written to the output
+    pub sent: u64,
+    /// light changes never sent, because the light was changed again before
+    pub coalesced: u64,
+    /// frames (writes) which could not be sent completely within the budget
+    pub dropped_frames: u64,
+    /// frames sent as rapid update
+    pub rapid_frames: u64,
+}
+
+/// Output stage limiting the messages per second written to another output,
+/// use it as output of a LaunchDevice
+pub struct RateLimiter<O: Output, C: Clock = SystemClock> {
+    out_port: O,
+    clock: C,
+    limit: RateLimit,
+    /// messages allowed to be sent right now
+    tokens: f64,
+    last_refill: Instant,
+    /// messages held back, in order
+    pending: Vec<LaunchMessage>,
+    /// state of the Launchpad with all messages sent so far
+    state: DeviceState,
+    stats: RateLimitStats,
+}
+impl<O: Output, C: Clock> RateLimiter<O, C> {
+    /// Create a limiter writing to the supplied output, starting with a full burst
+    /// Returns Error, if the budget or burst is 0
+    pub fn new(
+        out_port: O,
+        clock: C,
+        limit: RateLimit,
+    ) -> Result<RateLimiter<O, C>, MidiInterfaceError> {
+        check_limit(limit)?;
+        let last_refill = clock.now();
+        Ok(RateLimiter {
+            out_port,
+            clock,
+            limit,
+            tokens: limit.burst as f64,
+            last_refill,
+            pending: Vec::new(),
+            state: DeviceState::default(),
+            stats: RateLimitStats::default(),
+        })
+    }
+
+    pub fn clock(&self) -> &C {
+        &self.clock
+    }
+
+    /// Output the messages are written to
+    pub fn output(&mut self) -> &mut O {
+        &mut self.out_port
+    }
+
+    pub fn limit(&self) -> RateLimit {
+        self.limit
+    }
+
+    /// Change the budget, messages allowed right now are capped by the new burst
+    /// Returns Error, if the budget or burst is 0
+    pub fn set_limit(&mut self, limit: RateLimit) -> Result<(), MidiInterfaceError> {
+        check_limit(limit)?;
+        self.refill();
+        self.limit = limit;
+        self.tokens = self.tokens.min(limit.burst as f64);
+        Ok(())
+    }
+
+    /// Number of messages held back
+    pub fn pending(&self) -> usize {
+        self.pending.len()
+    }
+
+    pub fn stats(&self) -> RateLimitStats {
+        self.stats
+    }
+
+    /// Return the counters and set them to 0
+    pub fn take_stats(&mut self) -> RateLimitStats {
+        std::mem::take(&mut self.stats)
+    }
+
+    /// Add the messages allowed since the last refill
+    fn refill(&mut self) {
+        let now = self.clock.now();
+        let elapsed = now
+            .saturating_duration_since(self.last_refill)
+            .as_secs_f64();
+        self.tokens = (self.tokens + elapsed * self.limit.messages_per_second as f64)
+            .min(self.limit.burst as f64);
+        self.last_refill = now;
+    }
+
+    /// Queue a message, dropping a held back change of the same light with the same
+    /// flags (only back to the last message other than a light change, to keep the order)
+    fn push(&mut self, msg: LaunchMessage) {
+        if let Some(key) = led_key(&msg) {
+            let superseded = self
+                .pending
+                .iter()
+                .rev()
+                .take_while(|p| led_key(p).is_some())
+                .position(|p| led_key(p) == Some(key) && led_flags(p) == led_flags(&msg));
+            if let Some(i) = superseded {
+                self.pending.remove(self.pending.len() - 1 - i);
+                self.stats.coalesced += 1;
+            }
+        }
+        self.pending.push(msg);
+    }
+
+    /// Rapid update setting all lights as the held back messages would,
+    /// None if the held back messages are not all light changes or if the Launchpad
+    /// is not in XY mode
+    fn rapid_frame(&self) -> Option<Vec<LaunchMessage>> {
+        if self.pending.iter().any(|msg| led_key(msg).is_none()) {
+            return None;
+        }
+        let mut state = self.state.clone();
+        for msg in self.pending.iter() {
+            state.record(msg);
+        }
+        if state.grid_mode() != 0x01 {
+            return None;
+        }
+
+        // light changes only, so the update buffer stays the same
+        let update = state.update_buffer();
+        let other = 1 - update;
+        let mut vels: Vec<u8> = Vec::with_capacity(RAPID_LEDS);
+        for i in 0..RAPID_LEDS {
+            let pos: MatPos = rapid_position(i);
+            let color = state.buffer_color(update, pos)?;
+            let other_color = state.buffer_color(other, pos)?;
+            // flags reproducing the other buffer (see DeviceState)
+            let flags = if Some(other_color) == self.state.buffer_color(other, pos) {
+                0x00
+            } else if other_color == color {
+                FLAG_COPY
+            } else {
+                FLAG_CLEAR
+            };
+            vels.push(color as u8 | flags);
+        }
+
+        let mut msgs = vec![LaunchMessage {
+            status: 0xB0,
+            data1: 0x00,
+            data2: state.grid_mode(),
+        }];
+        msgs.extend(vels.chunks(2).map(|pair| LaunchMessage {
+            status: RAPID_UPDATE,
+            data1: pair[0],
+            data2: pair[1],
+        }));
+        Some(msgs)
+    }
+
+    /// Send the held back messages the budget allows, returns the number of messages
+    /// still held back. Call regularly while messages are held back
+    /// Returns Error, if writing fails
+    pub fn flush(&mut self) -> Result<usize, MidiInterfaceError> {
+        self.refill();
+        if self.pending.is_empty() {
+            return Ok(0);
+        }
+
+        // a rapid update is cheaper than sending the messages, wait for the budget to allow it
+        let rapid = if self.pending.len() > RAPID_FRAME_LEN {
+            self.rapid_frame()
+        } else {
+            None
+        };
+        if let Some(frame) = rapid {
+            if self.tokens >= RAPID_FRAME_LEN as f64 {
+                for msg in self.pending.drain(..) {
+                    self.state.record(&msg);
+                }
+                self.out_port.write_messages(frame)?;
+                self.tokens -= RAPID_FRAME_LEN as f64;
+                self.stats.sent += RAPID_FRAME_LEN as u64;
+                self.stats.rapid_frames += 1;
+                return Ok(0);
+            }
+            return Ok(self.pending.len());
+        }
+
+        let n = (self.tokens.max(0.0) as usize).min(self.pending.len());
+        if n > 0 {
+            let msgs: Vec<LaunchMessage> = self.pending.drain(..n).collect();
+            for msg in msgs.iter() {
+                self.state.record(msg);
+            }
+            self.out_port.write_messages(msgs)?;
+            self.tokens -= n as f64;
+            self.stats.sent += n as u64;
+        }
+        Ok(self.pending.len())
+    }
+}
+
+impl<O: Output, C: Clock> Output for RateLimiter<O, C> {
+    fn write_message(&mut self, msg: LaunchMessage) -> Result<(), MidiInterfaceError> {
+        self.write_messages(vec![msg])
+    }
+
+    /// Queue the messages of a frame and send what the budget allows
+    fn write_messages(&mut self, msgs: Vec<LaunchMessage>) -> Result<(), MidiInterfaceError> {
+        if msgs.is_empty() {
+            return Ok(());
+        }
+        for msg in msgs {
+            self.push(msg);
+        }
+        if self.flush()? > 0 {
+            self.stats.dropped_frames += 1;
+        }
+        Ok(())
+    }
+
+    /// Sysex messages are not limited, held back messages are sent first
+    /// (regardless of the budget) to keep the order
+    fn write_sysex(&mut self, msg: &[u8]) -> Result<(), MidiInterfaceError> {
+        let msgs: Vec<LaunchMessage> = self.pending.drain(..).collect();
+        if !msgs.is_empty() {
+            for msg in msgs.iter() {
+                self.state.record(msg);
+            }
+            self.stats.sent += msgs.len() as u64;
+            self.out_port.write_messages(msgs)?;
+        }
+        self.out_port.write_sysex(msg)
+    }
+}
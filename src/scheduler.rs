@@ -0,0 +1,251 @@
+/* Copyright (C) 2023 Lucas Haupt
+
+This program is distributed under the terms of the 
+GNU Lesser General Public License v3.0, 
+see COPYING.LESSER file for license information
+*/
+
+//! # Scheduler
+//!
+//! Timed output queue: messages (LED changes or any midi messages) are scheduled
+//! for a point in time and sent by a dedicated thread when due, e.g. to turn a pad off
+//! 200 ms after it was pressed or to change lights exactly on the beat.
+//! Scheduled entries can be cancelled until they are sent. Entries due at the same
+//! moment are sent with one write, superseded changes of the same LED are dropped.
+//!
+//! Messages are sent through a LaunchOutput, so scheduled LED changes are part of its
+//! state (capture, restore). The sending thread needs an output port of `'static`
+//! lifetime: PortMidi ports borrow their context, so with PortMidi the scheduler writes
+//! to a SharedDevice (which keeps the state of the Launchpad up to date as well).
+
+use std::collections::{BTreeMap, HashSet};
+use std::sync::{Arc, Condvar, Mutex, MutexGuard};
+use std::thread::JoinHandle;
+use std::time::{Duration, Instant};
+
+use crate::device_state::DeviceState;
+use crate::launch_device::LaunchOutput;
+use crate::midilib::{LaunchMessage, MidiInterfaceError, MidiMessage, Output};
+use crate::utils::{led_flags, led_key, light_message};
+use crate::{Color, MatPos};
+
+/// Entries due within this window after the earliest entry due are sent together
+const COALESCE_WINDOW: Duration = Duration::from_millis(1);
+
+/// Handle of a scheduled entry, for cancelling it
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, PartialOrd, Ord)]
+pub struct ScheduleId(u64);
+
+/// Entries and settings shared with the sending thread
+struct Queue {
+    /// entries by due time and id (ids increase, so entries due at the same time keep their order)
+    entries: BTreeMap<(Instant, ScheduleId), Vec<MidiMessage>>,
+    next_id: u64,
+    running: bool,
+    coalesce_leds: bool,
+    errors: Vec<MidiInterfaceError>,
+    /// state of the Launchpad after the last write
+    state: DeviceState,
+}
+
+type Shared = Arc<(Mutex<Queue>, Condvar)>;
+
+/// Lock the queue (a poisoned lock is taken over)
+fn lock(shared: &Shared) -> MutexGuard<'_, Queue> {
+    shared.0.lock().unwrap_or_else(|e| e.into_inner())
+}
+
+/// Drop LED changes superseded by a later change of the same LED with the same flags
+/// (only up to the next message other than an LED change, which might switch the buffers)
+fn coalesce(msgs: Vec<MidiMessage>) -> Vec<MidiMessage> {
+    let mut seen: HashSet<((u8, u8), u8)> = HashSet::new();
+    let mut res: Vec<MidiMessage> = msgs
+        .into_iter()
+        .rev()
+        .filter(|msg| {
+            let short = LaunchMessage::try_from(msg.clone()).ok();
+            match short.and_then(|m| Some((led_key(&m)?, led_flags(&m)))) {
+                Some(led) => seen.insert(led),
+                None => {
+                    seen.clear();
+                    true
+                }
+            }
+        })
+        .collect();
+    res.reverse();
+    res
+}
+
+/// Write messages, short messages in runs with one write each
+fn send<O: Output>(
+    out: &mut LaunchOutput<O>,
+    msgs: Vec<MidiMessage>,
+) -> Result<(), MidiInterfaceError> {
+    let mut short: Vec<LaunchMessage> = Vec::new();
+    for msg in msgs {
+        match msg {
+            MidiMessage::SysEx(bytes) => {
+                if !short.is_empty() {
+                    out.send_messages(std::mem::take(&mut short))?;
+                }
+                out.output_mut().write_sysex(&bytes)?;
+            }
+            msg => short.push(LaunchMessage::try_from(msg)?),
+        }
+    }
+    if !short.is_empty() {
+        out.send_messages(short)?;
+    }
+    Ok(())
+}
+
+/// Body of the sending thread, returns the output when the scheduler is stopped
+fn run<O: Output>(shared: Shared, mut out: LaunchOutput<O>) -> LaunchOutput<O> {
+    let mut queue = lock(&shared);
+    while queue.running {
+        let first = match queue.entries.keys().next() {
+            Some((at, _)) => *at,
+            None => {
+                queue = shared.1.wait(queue).unwrap_or_else(|e| e.into_inner());
+                continue;
+            }
+        };
+        let now = Instant::now();
+        if first > now {
+            queue = shared
+                .1
+                .wait_timeout(queue, first - now)
+                .unwrap_or_else(|e| e.into_inner())
+                .0;
+            continue;
+        }
+
+        let mut msgs: Vec<MidiMessage> = Vec::new();
+        while let Some(entry) = queue
+            .entries
+            .first_entry()
+            .filter(|e| e.key().0 <= first + COALESCE_WINDOW)
+        {
+            msgs.extend(entry.remove());
+        }
+        if queue.coalesce_leds {
+            msgs = coalesce(msgs);
+        }
+
+        drop(queue);
+        let res = send(&mut out, msgs);
+        queue = lock(&shared);
+        queue.state = out.capture();
+        if let Err(e) = res {
+            queue.errors.push(e);
+        }
+    }
+    out
+}
+
+/// Sends scheduled messages to the output half of a Launchpad from a dedicated thread
+pub struct Scheduler<O: Output + Send + 'static> {
+    shared: Shared,
+    thread: Option<JoinHandle<LaunchOutput<O>>>,
+}
+impl<O: Output + Send + 'static> Scheduler<O> {
+    /// Start a scheduler sending through the supplied output half (e.g. of a split
+    /// LaunchDevice or `LaunchOutput::new(shared_device)`), superseded LED changes
+    /// are dropped (see set_led_coalescing)
+    pub fn new(out: LaunchOutput<O>) -> Scheduler<O> {
+        let shared: Shared = Arc::new((
+            Mutex::new(Queue {
+                entries: BTreeMap::new(),
+                next_id: 0,
+                running: true,
+                coalesce_leds: true,
+                errors: Vec::new(),
+                state: out.capture(),
+            }),
+            Condvar::new(),
+        ));
+        let thread_shared = shared.clone();
+        let thread = std::thread::spawn(move || run(thread_shared, out));
+        Scheduler {
+            shared,
+            thread: Some(thread),
+        }
+    }
+
+    /// Drop note and CC messages changing an LED which is changed again by a message
+    /// sent with the same write (on by default). Turn off for outputs other than
+    /// a Launchpad, so retriggered notes are not lost
+    pub fn set_led_coalescing(&self, on: bool) {
+        lock(&self.shared).coalesce_leds = on;
+    }
+
+    /// Schedule messages of any kind to be sent at a time (at once, if the time has passed)
+    pub fn schedule_midi(&self, at: Instant, msgs: Vec<MidiMessage>) -> ScheduleId {
+        let mut queue = lock(&self.shared);
+        let id = ScheduleId(queue.next_id);
+        queue.next_id += 1;
+        queue.entries.insert((at, id), msgs);
+        self.shared.1.notify_one();
+        id
+    }
+
+    /// Schedule messages to be sent at a time (at once, if the time has passed)
+    pub fn schedule(&self, at: Instant, msgs: Vec<LaunchMessage>) -> ScheduleId {
+        self.schedule_midi(at, msgs.into_iter().map(MidiMessage::from).collect())
+    }
+
+    /// Schedule messages to be sent after a delay
+    pub fn schedule_in(&self, delay: Duration, msgs: Vec<LaunchMessage>) -> ScheduleId {
+        self.schedule(Instant::now() + delay, msgs)
+    }
+
+    /// Schedule setting the light of a button (row 8 being the control button row)
+    pub fn schedule_light(&self, at: Instant, pos: MatPos, color: Color) -> ScheduleId {
+        self.schedule(at, vec![light_message(pos, color)])
+    }
+
+    /// Cancel a scheduled entry, returns false if it has already been sent or cancelled
+    pub fn cancel(&self, id: ScheduleId) -> bool {
+        let mut queue = lock(&self.shared);
+        let key = queue.entries.keys().find(|(_, i)| *i == id).copied();
+        key.is_some_and(|key| queue.entries.remove(&key).is_some())
+    }
+
+    /// Cancel all scheduled entries
+    pub fn cancel_all(&self) {
+        lock(&self.shared).entries.clear();
+    }
+
+    /// Number of entries waiting to be sent
+    pub fn pending(&self) -> usize {
+        lock(&self.shared).entries.len()
+    }
+
+    /// State of the Launchpad including the scheduled messages sent so far
+    pub fn state(&self) -> DeviceState {
+        lock(&self.shared).state.clone()
+    }
+
+    /// Return and clear the errors of writes failed since the last call
+    pub fn take_errors(&self) -> Vec<MidiInterfaceError> {
+        std::mem::take(&mut lock(&self.shared).errors)
+    }
+
+    /// Stop the sending thread and return the output half with its state
+    /// (e.g. to join it with the input half again), pending entries are dropped
+    pub fn stop(mut self) -> Option<LaunchOutput<O>> {
+        self.shutdown()
+    }
+
+    fn shutdown(&mut self) -> Option<LaunchOutput<O>> {
+        lock(&self.shared).running = false;
+        self.shared.1.notify_one();
+        self.thread.take().and_then(|t| t.join().ok())
+    }
+}
+impl<O: Output + Send + 'static> Drop for Scheduler<O> {
+    fn drop(&mut self) {
+        self.shutdown();
+    }
+}
@@ -0,0 +1,689 @@
+/* Copyright (C) 2023 Lucas Haupt
+
+This program is distributed under the terms of the 
+GNU Lesser General Public License v3.0, 
+see COPYING.LESSER file for license information
+*/
+
+//! # Sequencer
+//!
+//! Step sequencer on the 8x8 grid: every row is a track playing one note, every column
+//! a step (16th note). Pressing a pad toggles a step, the scene buttons mute tracks and
+//! the left/right control buttons (third and fourth round button) page through
+//! patterns longer than 8 steps. Notes are sent to a midi output.
+//!
+//! Step times are computed from the start time instead of adding up step durations,
+//! so the sequencer does not drift however long it runs. Time is read from a Clock,
+//! which can be replaced (e.g. by a ManualClock in tests). Alternatively the sequencer
+//! follows an external MIDI clock (see clock_sync.rs), playing a step every 6 clocks.
+
+use std::cell::Cell;
+use std::time::{Duration, Instant};
+
+use crate::clock_sync::CLOCKS_PER_SIXTEENTH;
+use crate::launch_device::LaunchDevice;
+use crate::midilib::{Input, LaunchMessage, MidiInterfaceError, Output, RealTimeMessage};
+use crate::pages::{frame_changes, frame_of, Frame};
+use crate::utils::{check_channel, note_message, PadEvent};
+use crate::{Color, MatPos};
+
+/// Number of tracks (rows of the grid)
+pub const TRACKS: usize = 8;
+
+/// Number of steps shown at once (columns of the grid)
+pub const STEPS_PER_PAGE: usize = 8;
+
+/// Maximum pattern length in steps
+pub const MAX_STEPS: usize = 64;
+
+/// Column of the control button showing the previous page
+const PREV_PAGE_COL: u8 = 2;
+
+/// Column of the control button showing the next page
+const NEXT_PAGE_COL: u8 = 3;
+
+const STEP_COLOR: Color = Color::Green;
+const PLAYHEAD_COLOR: Color = Color::DimYellow;
+const PLAYHEAD_STEP_COLOR: Color = Color::Yellow;
+const MUTED_COLOR: Color = Color::Red;
+const UNMUTED_COLOR: Color = Color::DimGreen;
+const PAGE_BUTTON_COLOR: Color = Color::MedYellow;
+
+/// Source of the current time
+pub trait Clock {
+    fn now(&self) -> Instant;
+}
+
+impl<C: Clock> Clock for &C {
+    fn now(&self) -> Instant {
+        (**self).now()
+    }
+}
+
+/// Clock of the system (monotonic)
+#[derive(Debug, Clone, Copy, Default)]
+pub struct SystemClock;
+impl Clock for SystemClock {
+    fn now(&self) -> Instant {
+        Instant::now()
+    }
+}
+
+/// Clock only moving when advanced, for testing timing without waiting
+#[derive(Debug, Clone)]
+pub struct ManualClock {
+    now: Cell<Instant>,
+}
+impl Default for ManualClock {
+    fn default() -> Self {
+        ManualClock::new()
+    }
+}
+impl ManualClock {
+    /// Create a clock standing at the current time
+    pub fn new() -> ManualClock {
+        ManualClock {
+            now: Cell::new(Instant::now()),
+        }
+    }
+
+    /// Move the clock forward
+    pub fn advance(&self, by: Duration) {
+        self.now.set(self.now.get() + by);
+    }
+
+    pub fn set(&self, now: Instant) {
+        self.now.set(now);
+    }
+}
+impl Clock for ManualClock {
+    fn now(&self) -> Instant {
+        self.now.get()
+    }
+}
+
+/// A row of the sequencer playing one note
+#[derive(Debug, Clone)]
+pub struct Track {
+    pub note: u8,
+    /// midi channel (0 to 15)
+    channel: u8,
+    pub velocity: u8,
+    pub muted: bool,
+    steps: [bool; MAX_STEPS],
+}
+impl Track {
+    /// Track without steps set
+    /// Returns Error, if the channel is not within 0 to 15
+    pub fn new(note: u8, channel: u8, velocity: u8) -> Result<Track, MidiInterfaceError> {
+        check_channel(channel)?;
+        Ok(Track {
+            note,
+            channel,
+            velocity,
+            muted: false,
+            steps: [false; MAX_STEPS],
+        })
+    }
+
+    pub fn channel(&self) -> u8 {
+        self.channel
+    }
+
+    /// Set the midi channel the note is sent on (a note sounding is turned off
+    /// on its previous channel)
+    /// Returns Error, if it is not within 0 to 15
+    pub fn set_channel(&mut self, channel: u8) -> Result<(), MidiInterfaceError> {
+        check_channel(channel)?;
+        self.channel = channel;
+        Ok(())
+    }
+
+    /// Return if a step is set (false for steps beyond MAX_STEPS)
+    pub fn step(&self, step: usize) -> bool {
+        self.steps.get(step).copied().unwrap_or(false)
+    }
+
+    /// Set or clear a step (steps beyond MAX_STEPS are ignored)
+    pub fn set_step(&mut self, step: usize, on: bool) {
+        if let Some(s) = self.steps.get_mut(step) {
+            *s = on;
+        }
+    }
+
+    /// Clear all steps
+    pub fn clear(&mut self) {
+        self.steps = [false; MAX_STEPS];
+    }
+}
+
+/// Point in time a step count refers to, step times are computed relative to it
+#[derive(Debug, Clone, Copy)]
+struct Anchor {
+    at: Instant,
+    step: u64,
+}
+
+/// Step sequencer with 8 tracks sending notes to a midi output
+pub struct Sequencer<O: Output, C: Clock = SystemClock> {
+    out_port: O,
+    clock: C,
+    tracks: Vec<Track>,
+    length: usize,
+    bpm: f64,
+    swing: u8,
+    page: usize,
+    /// set while playing from the clock
+    anchor: Option<Anchor>,
+    /// set while playing from an external midi clock
+    synced: bool,
+    /// number of the next external clock, counted from the beginning of the song
+    song_clock: u64,
+    /// number of the next step to play (counted from the start)
+    next_step: u64,
+    /// pattern step played last
+    position: Option<usize>,
+    /// notes to turn off with the next step (channel, note)
+    sounding: Vec<(u8, u8)>,
+    shown: Option<Frame>,
+}
+impl<O: Output, C: Clock> Sequencer<O, C> {
+    /// Create a stopped sequencer (120 BPM, no swing, 16 steps) with tracks playing
+    /// the notes 36 to 43 (drums in General MIDI) on channel 0
+    pub fn new(out_port: O, clock: C) -> Sequencer<O, C> {
+        Sequencer {
+            out_port,
+            clock,
+            tracks: (0..TRACKS as u8)
+                .map(|i| Track {
+                    note: 36 + i,
+                    channel: 0,
+                    velocity: 0x64,
+                    muted: false,
+                    steps: [false; MAX_STEPS],
+                })
+                .collect(),
+            length: 16,
+            bpm: 120.0,
+            swing: 50,
+            page: 0,
+            anchor: None,
+            synced: false,
+            song_clock: 0,
+            next_step: 0,
+            position: None,
+            sounding: Vec::new(),
+            shown: None,
+        }
+    }
+
+    pub fn clock(&self) -> &C {
+        &self.clock
+    }
+
+    /// Output port the notes are sent to
+    pub fn output(&mut self) -> &mut O {
+        &mut self.out_port
+    }
+
+    pub fn track(&self, track: usize) -> Option<&Track> {
+        self.tracks.get(track)
+    }
+
+    pub fn track_mut(&mut self, track: usize) -> Option<&mut Track> {
+        self.tracks.get_mut(track)
+    }
+
+    pub fn bpm(&self) -> f64 {
+        self.bpm
+    }
+
+    /// Set the tempo in beats (quarter notes) per minute, takes effect with the next step
+    /// Returns Error, if the tempo is not within 1 to 999 BPM
+    pub fn set_bpm(&mut self, bpm: f64) -> Result<(), MidiInterfaceError> {
+        if !(1.0..=999.0).contains(&bpm) {
+            return Err(MidiInterfaceError::Invalid(format!(
+                "tempo {} BPM out of range (1 to 999)",
+                bpm
+            )));
+        }
+        // continue from the next step with the new tempo
+        if let Some(anchor) = self.anchor {
+            let at = anchor.at + self.steps_duration(self.next_step - anchor.step);
+            self.anchor = Some(Anchor {
+                at,
+                step: self.next_step,
+            });
+        }
+        self.bpm = bpm;
+        Ok(())
+    }
+
+    pub fn swing(&self) -> u8 {
+        self.swing
+    }
+
+    /// Set the swing in percent: the share of a pair of steps taken by its first step,
+    /// 50 being straight, 66 triplet feel, 75 the maximum
+    /// Returns Error, if the swing is not within 50 to 75
+    pub fn set_swing(&mut self, swing: u8) -> Result<(), MidiInterfaceError> {
+        if !(50..=75).contains(&swing) {
+            return Err(MidiInterfaceError::Invalid(format!(
+                "swing {}% out of range (50 to 75)",
+                swing
+            )));
+        }
+        self.swing = swing;
+        Ok(())
+    }
+
+    /// Pattern length in steps
+    pub fn length(&self) -> usize {
+        self.length
+    }
+
+    /// Set the pattern length in steps
+    /// Returns Error, if the length is not within 1 to MAX_STEPS
+    pub fn set_length(&mut self, length: usize) -> Result<(), MidiInterfaceError> {
+        if !(1..=MAX_STEPS).contains(&length) {
+            return Err(MidiInterfaceError::Invalid(format!(
+                "pattern length {} out of range (1 to {})",
+                length, MAX_STEPS
+            )));
+        }
+        self.length = length;
+        self.page = self.page.min(self.pages() - 1);
+        Ok(())
+    }
+
+    /// Number of pages of the pattern
+    pub fn pages(&self) -> usize {
+        self.length.div_ceil(STEPS_PER_PAGE)
+    }
+
+    /// Page shown on the grid
+    pub fn page(&self) -> usize {
+        self.page
+    }
+
+    /// Show another page (pages beyond the pattern length are ignored)
+    pub fn set_page(&mut self, page: usize) {
+        if page < self.pages() {
+            self.page = page;
+        }
+    }
+
+    pub fn is_playing(&self) -> bool {
+        self.anchor.is_some() || self.synced
+    }
+
+    /// Pattern step played last, None if stopped
+    pub fn position(&self) -> Option<usize> {
+        self.position
+    }
+
+    /// Duration of a step (a 16th note) without swing
+    fn step_duration(&self) -> Duration {
+        Duration::from_secs_f64(15.0 / self.bpm)
+    }
+
+    /// Duration of a number of steps without swing
+    fn steps_duration(&self, steps: u64) -> Duration {
+        Duration::from_secs_f64(steps as f64 * 15.0 / self.bpm)
+    }
+
+    /// Time of a step counted from the start (None if stopped),
+    /// the second step of every pair is delayed by the swing
+    fn step_at(&self, step: u64) -> Option<Instant> {
+        let anchor = self.anchor?;
+        let mut at = anchor.at + self.steps_duration(step - anchor.step);
+        if step % 2 == 1 {
+            at += self
+                .step_duration()
+                .mul_f64((self.swing as f64 - 50.0) / 50.0);
+        }
+        Some(at)
+    }
+
+    /// Time the next step is due, None if stopped or following an external clock
+    pub fn next_step_at(&self) -> Option<Instant> {
+        self.step_at(self.next_step)
+    }
+
+    /// Start playing from the first step (the first step is played by the next tick)
+    pub fn start(&mut self) {
+        self.anchor = Some(Anchor {
+            at: self.clock.now(),
+            step: 0,
+        });
+        self.next_step = 0;
+        self.synced = false;
+    }
+
+    /// Stop playing and turn off the notes sounding
+    /// Returns Error, if sending fails
+    pub fn stop(&mut self) -> Result<(), MidiInterfaceError> {
+        self.anchor = None;
+        self.synced = false;
+        self.position = None;
+        self.notes_off()
+    }
+
+    fn notes_off(&mut self) -> Result<(), MidiInterfaceError> {
+        let msgs: Vec<LaunchMessage> = self
+            .sounding
+            .drain(..)
+            .map(|(channel, note)| note_message(channel, note, false, 0x00))
+            .collect();
+        if !msgs.is_empty() {
+            self.out_port.write_messages(msgs)?;
+        }
+        Ok(())
+    }
+
+    /// Play the step due at the current time of the clock, returns the pattern step played.
+    /// If several steps are due (e.g. after a stall), only the last one is played
+    /// Returns Error, if sending fails
+    pub fn tick(&mut self) -> Result<Option<usize>, MidiInterfaceError> {
+        let now = self.clock.now();
+        match self.next_step_at() {
+            Some(at) if at <= now => {}
+            _ => return Ok(None),
+        }
+        let mut step = self.next_step;
+        while self.step_at(step + 1).is_some_and(|at| at <= now) {
+            step += 1;
+        }
+
+        self.play(step).map(Some)
+    }
+
+    /// Turn off the notes sounding and play a step counted from the start,
+    /// returns the pattern step played
+    fn play(&mut self, step: u64) -> Result<usize, MidiInterfaceError> {
+        self.notes_off()?;
+        let pos = (step % self.length as u64) as usize;
+        let mut msgs: Vec<LaunchMessage> = Vec::new();
+        for track in self.tracks.iter().filter(|t| !t.muted && t.step(pos)) {
+            msgs.push(note_message(
+                track.channel,
+                track.note,
+                true,
+                track.velocity,
+            ));
+            self.sounding.push((track.channel, track.note));
+        }
+        if !msgs.is_empty() {
+            self.out_port.write_messages(msgs)?;
+        }
+
+        self.position = Some(pos);
+        self.next_step = step + 1;
+        Ok(pos)
+    }
+
+    /// Follow an external midi clock: start, continue, stop and song position
+    /// control playback, every 6th clock plays a step (the second step of every pair
+    /// is delayed by the swing, rounded to whole clocks). Returns the pattern step played.
+    /// While following, tick does not play steps
+    /// Returns Error, if sending fails
+    pub fn follow(&mut self, msg: RealTimeMessage) -> Result<Option<usize>, MidiInterfaceError> {
+        match msg {
+            RealTimeMessage::Start => {
+                self.anchor = None;
+                self.synced = true;
+                self.song_clock = 0;
+            }
+            RealTimeMessage::Continue => {
+                self.anchor = None;
+                self.synced = true;
+            }
+            RealTimeMessage::Stop => self.stop()?,
+            RealTimeMessage::SongPosition(pos) => {
+                self.song_clock = pos as u64 * CLOCKS_PER_SIXTEENTH;
+            }
+            RealTimeMessage::Clock if self.synced => {
+                let clock = self.song_clock;
+                self.song_clock += 1;
+
+                let pair = 2 * CLOCKS_PER_SIXTEENTH;
+                let delay = ((self.swing as u64 - 50) * CLOCKS_PER_SIXTEENTH + 25) / 50;
+                let step = if clock.is_multiple_of(pair) {
+                    clock / CLOCKS_PER_SIXTEENTH
+                } else if clock % pair == CLOCKS_PER_SIXTEENTH + delay {
+                    (clock - delay) / CLOCKS_PER_SIXTEENTH
+                } else {
+                    return Ok(None);
+                };
+                return self.play(step).map(Some);
+            }
+            RealTimeMessage::Clock => {}
+        }
+        Ok(None)
+    }
+
+    /// Handle a button event: pads toggle steps of the page shown, scene buttons
+    /// mute tracks, the left/right control buttons change the page
+    pub fn handle(&mut self, event: PadEvent) {
+        if !event.pressed {
+            return;
+        }
+        let pos = event.pos;
+        match (pos.row, pos.col) {
+            (8, PREV_PAGE_COL) => self.page = self.page.saturating_sub(1),
+            (8, NEXT_PAGE_COL) => self.set_page(self.page + 1),
+            (8, _) => {}
+            (row, 8) => {
+                let track = &mut self.tracks[row as usize];
+                track.muted = !track.muted;
+            }
+            (row, col) => {
+                let step = self.page * STEPS_PER_PAGE + col as usize;
+                if step < self.length {
+                    let track = &mut self.tracks[row as usize];
+                    let on = !track.step(step);
+                    track.set_step(step, on);
+                }
+            }
+        }
+    }
+
+    /// Lights of the grid: steps of the page shown, the playhead, mute states
+    /// and the page buttons
+    pub fn render(&self) -> Vec<(MatPos, Color)> {
+        let mut res = Vec::new();
+        let first = self.page * STEPS_PER_PAGE;
+
+        for (row, track) in self.tracks.iter().enumerate() {
+            for col in 0..STEPS_PER_PAGE {
+                let step = first + col;
+                if step >= self.length {
+                    continue;
+                }
+                let color = match (track.step(step), self.position == Some(step)) {
+                    (true, true) => PLAYHEAD_STEP_COLOR,
+                    (false, true) => PLAYHEAD_COLOR,
+                    (true, false) => STEP_COLOR,
+                    (false, false) => continue,
+                };
+                res.push((MatPos::new(row as u8, col as u8), color));
+            }
+            let mute = if track.muted {
+                MUTED_COLOR
+            } else {
+                UNMUTED_COLOR
+            };
+            res.push((MatPos::new(row as u8, 8), mute));
+        }
+
+        if self.page > 0 {
+            res.push((MatPos::new(8, PREV_PAGE_COL), PAGE_BUTTON_COLOR));
+        }
+        if self.page + 1 < self.pages() {
+            res.push((MatPos::new(8, NEXT_PAGE_COL), PAGE_BUTTON_COLOR));
+        }
+        res
+    }
+
+    /// Update the lights of the Launchpad, only changed lights are sent
+    /// Returns Error, if action fails
+    pub fn draw<I: Input, L: Output>(
+        &mut self,
+        dev: &mut LaunchDevice<I, L>,
+    ) -> Result<(), MidiInterfaceError> {
+        let frame = frame_of(self.render());
+        let msgs = frame_changes(self.shown.as_ref(), &frame);
+        if !msgs.is_empty() {
+            dev.send_messages(msgs)?;
+        }
+        self.shown = Some(frame);
+        Ok(())
+    }
+
+    /// Read up to n messages from the Launchpad, handle them, play the step due
+    /// and update the lights, returns the pattern step played
+    /// Returns Error, if reading, sending or drawing fails
+    pub fn process<I: Input, L: Output>(
+        &mut self,
+        dev: &mut LaunchDevice<I, L>,
+        n: usize,
+    ) -> Result<Option<usize>, MidiInterfaceError> {
+        if dev.poll()? {
+            for event in dev.read_events(n)? {
+                self.handle(event);
+            }
+        }
+        let played = self.tick()?;
+        self.draw(dev)?;
+        Ok(played)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Output recording the messages written to it
+    #[derive(Default)]
+    struct Recorder {
+        sent: Vec<LaunchMessage>,
+    }
+    impl Output for Recorder {
+        fn write_message(&mut self, msg: LaunchMessage) -> Result<(), MidiInterfaceError> {
+            self.sent.push(msg);
+            Ok(())
+        }
+
+        fn write_messages(&mut self, msgs: Vec<LaunchMessage>) -> Result<(), MidiInterfaceError> {
+            self.sent.extend(msgs);
+            Ok(())
+        }
+    }
+
+    /// Absolute difference of two instants
+    fn distance(a: Instant, b: Instant) -> Duration {
+        if a > b {
+            a - b
+        } else {
+            b - a
+        }
+    }
+
+    #[test]
+    fn step_times_do_not_drift() {
+        let clock = ManualClock::new();
+        let start = clock.now();
+        let mut seq = Sequencer::new(Recorder::default(), &clock);
+        // 15 / 133 s per step is no whole number of nanoseconds
+        seq.set_bpm(133.0).unwrap();
+        seq.start();
+
+        for step in 0..100_000u64 {
+            let at = seq.next_step_at().unwrap();
+            let ideal = start + Duration::from_secs_f64(step as f64 * 15.0 / 133.0);
+            assert!(
+                distance(at, ideal) <= Duration::from_micros(1),
+                "step {}",
+                step
+            );
+
+            clock.set(at);
+            assert_eq!(seq.tick().unwrap(), Some((step % 16) as usize));
+            assert_eq!(seq.tick().unwrap(), None);
+        }
+    }
+
+    #[test]
+    fn swing_delays_every_second_step() {
+        let clock = ManualClock::new();
+        let start = clock.now();
+        let mut seq = Sequencer::new(Recorder::default(), &clock);
+        seq.set_swing(66).unwrap();
+        seq.start();
+
+        // 125 ms per step at 120 BPM, the second step of a pair delayed by 16% of a pair
+        let step = Duration::from_millis(125);
+        let delay = step.mul_f64(0.32);
+        for n in 0..10_000u64 {
+            let at = seq.next_step_at().unwrap();
+            let mut ideal = start + step * n as u32;
+            if n % 2 == 1 {
+                ideal += delay;
+            }
+            assert!(
+                distance(at, ideal) <= Duration::from_micros(1),
+                "step {}",
+                n
+            );
+
+            clock.set(at);
+            seq.tick().unwrap();
+        }
+    }
+
+    #[test]
+    fn tick_plays_only_due_steps() {
+        let clock = ManualClock::new();
+        let mut seq = Sequencer::new(Recorder::default(), &clock);
+        seq.track_mut(0).unwrap().set_step(0, true);
+        seq.track_mut(1).unwrap().set_step(2, true);
+        seq.start();
+
+        assert_eq!(seq.tick().unwrap(), Some(0));
+        assert_eq!(
+            seq.output().sent,
+            vec![LaunchMessage {
+                status: 0x90,
+                data1: 36,
+                data2: 0x64,
+            }]
+        );
+
+        // not yet due
+        clock.advance(Duration::from_millis(124));
+        assert_eq!(seq.tick().unwrap(), None);
+
+        // after a stall only the last step due is played
+        clock.advance(Duration::from_millis(126));
+        assert_eq!(seq.tick().unwrap(), Some(2));
+        assert_eq!(
+            seq.output().sent[1..],
+            [
+                LaunchMessage {
+                    status: 0x80,
+                    data1: 36,
+                    data2: 0x00,
+                },
+                LaunchMessage {
+                    status: 0x90,
+                    data1: 37,
+                    data2: 0x64,
+                },
+            ]
+        );
+        assert_eq!(
+            seq.next_step_at(),
+            Some(clock.now() + Duration::from_millis(125))
+        );
+    }
+}
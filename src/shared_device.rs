@@ -0,0 +1,291 @@
+/* Copyright (C) 2023 Lucas Haupt
+
+This program is distributed under the terms of the 
+GNU Lesser General Public License v3.0, 
+see COPYING.LESSER file for license information
+*/
+
+//! # Shared device
+//!
+//! Cloneable, thread-safe handle to a Launchpad, e.g. for a render thread and an input
+//! thread using the same device. The handle owns the backend context: context, ports and
+//! LaunchDevice live on a device thread, which runs the calls of all handles one after
+//! another. The device is closed when the last handle is dropped.
+
+use std::sync::mpsc::{self, Receiver, Sender, SyncSender};
+
+use crate::device_state::DeviceState;
+use crate::launch_device::LaunchDevice;
+use crate::midilib::{
+    DeviceInfo, Input, LaunchMessage, MidiInterface, MidiInterfaceError, MidiMessage, Output,
+    RealTimeMessage, TimedMessage,
+};
+use crate::pm_impl::MidiImpl;
+use crate::units::UnitId;
+use crate::utils::PadEvent;
+use crate::Color;
+
+/// LaunchDevice with the port types of the backend erased, as used by the device thread
+pub type DynLaunchDevice<'a> = LaunchDevice<Box<dyn Input + 'a>, Box<dyn Output + 'a>>;
+
+/// Device list of the context owned by the device thread
+type DeviceList<'a> = dyn Fn() -> Result<Vec<DeviceInfo>, MidiInterfaceError> + 'a;
+
+/// Call to run on the device thread
+type Job = Box<dyn for<'a> FnOnce(&mut DynLaunchDevice<'a>, &DeviceList<'a>) + Send>;
+
+/// Unit to open on the device thread
+pub(crate) enum Target {
+    /// first unit whose port names contain the name
+    Name(String),
+    /// unit with the identifier
+    Unit(UnitId),
+}
+
+fn disconnected() -> MidiInterfaceError {
+    MidiInterfaceError::Disconnected("device thread has stopped".to_string())
+}
+
+/// Open the target unit and return the LaunchDevice on it, None if there is no such unit
+fn open_device<'a, M: MidiInterface<'a>>(
+    ctx: &'a M,
+    target: &Target,
+) -> Result<Option<DynLaunchDevice<'a>>, MidiInterfaceError> {
+    let unit = match target {
+        Target::Name(name) => ctx.get_units(name)?.into_iter().next(),
+        Target::Unit(id) => ctx.get_units(&id.name)?.into_iter().find(|u| u.id == *id),
+    };
+    let unit = match unit {
+        Some(unit) => unit,
+        None => return Ok(None),
+    };
+    let (in_p, out_p) = ctx.get_unit_in_out(&unit)?;
+    Ok(Some(LaunchDevice::new(Box::new(in_p), Box::new(out_p))))
+}
+
+/// Body of the device thread: open the device, report the result (false if there is
+/// no such unit) and run the calls until all handles are dropped
+fn run<'a, M: MidiInterface<'a>>(
+    ctx: &'a M,
+    target: &Target,
+    ready: SyncSender<Result<bool, MidiInterfaceError>>,
+    jobs: Receiver<Job>,
+) {
+    let mut dev = match open_device(ctx, target) {
+        Ok(Some(dev)) => dev,
+        Ok(None) => {
+            let _ = ready.send(Ok(false));
+            return;
+        }
+        Err(e) => {
+            let _ = ready.send(Err(e));
+            return;
+        }
+    };
+    if ready.send(Ok(true)).is_err() {
+        return;
+    }
+    let devices = || ctx.get_devices();
+    while let Ok(job) = jobs.recv() {
+        job(&mut dev, &devices);
+    }
+}
+
+/// Cloneable handle to a Launchpad running on its own device thread,
+/// all clones refer to the same device
+#[derive(Clone)]
+pub struct SharedDevice {
+    jobs: Sender<Job>,
+}
+impl SharedDevice {
+    /// Start a device thread creating the backend context with the supplied function
+    /// (e.g. `|| Ok(MockMidi::new())`) and opening the first unit whose port names contain
+    /// the supplied name (see units.rs). The context is dropped with the last handle
+    /// Returns Error, if the context can not be created or no such unit can be opened
+    pub fn open<M, F>(make_ctx: F, name: &str) -> Result<SharedDevice, MidiInterfaceError>
+    where
+        M: for<'a> MidiInterface<'a> + 'static,
+        F: FnOnce() -> Result<M, MidiInterfaceError> + Send + 'static,
+    {
+        match SharedDevice::open_target(make_ctx, Target::Name(name.to_string()))? {
+            Some((dev, _)) => Ok(dev),
+            None => Err(MidiInterfaceError::NotAnInputDevice(format!(
+                "no device named {} found",
+                name
+            ))),
+        }
+    }
+
+    /// Start a device thread creating the backend context and opening the target unit.
+    /// Returns the handle and a receiver disconnecting once the device thread has dropped
+    /// the context, or None if there is no such unit (the context is dropped already)
+    /// Returns Error, if the context can not be created or the unit can not be opened
+    pub(crate) fn open_target<M, F>(
+        make_ctx: F,
+        target: Target,
+    ) -> Result<Option<(SharedDevice, Receiver<()>)>, MidiInterfaceError>
+    where
+        M: for<'a> MidiInterface<'a> + 'static,
+        F: FnOnce() -> Result<M, MidiInterfaceError> + Send + 'static,
+    {
+        let (jobs_tx, jobs_rx) = mpsc::channel::<Job>();
+        let (ready_tx, ready_rx) = mpsc::sync_channel(1);
+        let (closed_tx, closed_rx) = mpsc::channel::<()>();
+        std::thread::spawn(move || {
+            match make_ctx() {
+                Ok(ctx) => run(&ctx, &target, ready_tx, jobs_rx),
+                Err(e) => {
+                    let _ = ready_tx.send(Err(e));
+                }
+            }
+            drop(closed_tx);
+        });
+        if ready_rx.recv().map_err(|_| disconnected())?? {
+            Ok(Some((SharedDevice { jobs: jobs_tx }, closed_rx)))
+        } else {
+            let _ = closed_rx.recv();
+            Ok(None)
+        }
+    }
+
+    /// Start a device thread with a PortMidi context, opening the first unit
+    /// whose port names contain the supplied name
+    /// Returns Error, if PortMidi can not be initialized or no such unit can be opened
+    pub fn open_portmidi(name: &str) -> Result<SharedDevice, MidiInterfaceError> {
+        SharedDevice::open(MidiImpl::new, name)
+    }
+
+    /// Run a function with exclusive access to the device and return its result.
+    /// Calls of all handles are run one after another on the device thread,
+    /// so the function must not use a handle of the same device itself (deadlock)
+    /// Returns Error, if the device thread has stopped (e.g. a call panicked)
+    pub fn with<R, F>(&self, f: F) -> Result<R, MidiInterfaceError>
+    where
+        R: Send + 'static,
+        F: for<'a> FnOnce(&mut DynLaunchDevice<'a>) -> R + Send + 'static,
+    {
+        let (tx, rx) = mpsc::sync_channel(1);
+        let job: Job = Box::new(move |dev: &mut DynLaunchDevice<'_>, _: &DeviceList<'_>| {
+            let _ = tx.send(f(dev));
+        });
+        self.jobs.send(job).map_err(|_| disconnected())?;
+        rx.recv().map_err(|_| disconnected())
+    }
+
+    /// Device list of the backend context owned by the device thread
+    /// Returns Error, if the list can not be read or the device thread has stopped
+    pub(crate) fn devices(&self) -> Result<Vec<DeviceInfo>, MidiInterfaceError> {
+        let (tx, rx) = mpsc::sync_channel(1);
+        let job: Job = Box::new(
+            move |_: &mut DynLaunchDevice<'_>, devices: &DeviceList<'_>| {
+                let _ = tx.send(devices());
+            },
+        );
+        self.jobs.send(job).map_err(|_| disconnected())?;
+        rx.recv().map_err(|_| disconnected())?
+    }
+
+    /// Last known state of the Launchpad (see LaunchDevice::state)
+    /// Returns Error, if the device thread has stopped
+    pub fn state(&self) -> Result<DeviceState, MidiInterfaceError> {
+        self.with(|dev| dev.capture())
+    }
+
+    /// Returns if messages from Launchpad are available
+    /// Returns Error, if polling fails
+    pub fn poll(&self) -> Result<bool, MidiInterfaceError> {
+        self.with(|dev| dev.poll())?
+    }
+
+    /// Read a number of midi messages and decode them into button presses and releases
+    /// Returns Error, if reading fails
+    pub fn read_events(&self, n: usize) -> Result<Vec<PadEvent>, MidiInterfaceError> {
+        self.with(move |dev| dev.read_events(n))?
+    }
+
+    /// Send multiple messages to the Launchpad
+    /// Returns Error, if sending fails
+    pub fn send_messages(&self, msgs: Vec<LaunchMessage>) -> Result<(), MidiInterfaceError> {
+        self.with(move |dev| dev.send_messages(msgs))?
+    }
+
+    /// Set the color/light at a position on the Launchpad Matrix
+    /// Returns Error, if action fails
+    pub fn set_position(&self, row: u8, col: u8, color: Color) -> Result<(), MidiInterfaceError> {
+        self.with(move |dev| dev.set_position(row, col, color))?
+    }
+
+    /// Set all buttons to one color
+    /// Returns Error, if action fails
+    pub fn set_all(&self, color: Color) -> Result<(), MidiInterfaceError> {
+        self.with(move |dev| dev.set_all(color))?
+    }
+
+    /// Reset the Launchpad
+    /// Returns Error, if action fails
+    pub fn reset(&self) -> Result<(), MidiInterfaceError> {
+        self.with(|dev| dev.reset())?
+    }
+}
+
+/// A shared device is an output itself, e.g. for a Scheduler or RateLimiter
+/// writing to a device used by other threads (short messages are tracked in its state)
+impl Output for SharedDevice {
+    fn write_message(&mut self, msg: LaunchMessage) -> Result<(), MidiInterfaceError> {
+        self.send_messages(vec![msg])
+    }
+
+    fn write_messages(&mut self, msgs: Vec<LaunchMessage>) -> Result<(), MidiInterfaceError> {
+        self.send_messages(msgs)
+    }
+
+    /// Written on the device thread, so timed messages of one call are not
+    /// interleaved with messages of other handles (timestamps are kept)
+    fn write_messages_timed(&mut self, msgs: Vec<TimedMessage>) -> Result<(), MidiInterfaceError> {
+        self.with(move |dev| dev.send_timed_messages(msgs))?
+    }
+
+    fn write_realtime(&mut self, msg: RealTimeMessage) -> Result<(), MidiInterfaceError> {
+        self.with(move |dev| dev.output_mut().write_realtime(msg))?
+    }
+
+    fn write_sysex(&mut self, msg: &[u8]) -> Result<(), MidiInterfaceError> {
+        let msg = msg.to_vec();
+        self.with(move |dev| dev.output_mut().write_sysex(&msg))?
+    }
+
+    fn write_midi(&mut self, msg: MidiMessage) -> Result<(), MidiInterfaceError> {
+        self.with(move |dev| match msg {
+            MidiMessage::SysEx(bytes) => dev.output_mut().write_sysex(&bytes),
+            msg => dev.send_messages(vec![LaunchMessage::try_from(msg)?]),
+        })?
+    }
+}
+
+/// A shared device is an input itself, messages are read by whichever handle reads first
+impl Input for SharedDevice {
+    fn poll(&self) -> Result<bool, MidiInterfaceError> {
+        SharedDevice::poll(self)
+    }
+
+    fn read_n(&self, count: usize) -> Result<Option<Vec<LaunchMessage>>, MidiInterfaceError> {
+        self.with(move |dev| dev.read_n_msgs(count))?
+    }
+
+    /// Timestamps are the ones of the backend, taken on the device thread
+    fn read_n_timed(&self, count: usize) -> Result<Option<Vec<TimedMessage>>, MidiInterfaceError> {
+        self.with(move |dev| dev.read_n_timed(count))?
+    }
+
+    fn read_realtime(&self, count: usize) -> Result<Vec<RealTimeMessage>, MidiInterfaceError> {
+        self.with(move |dev| dev.input().read_realtime(count))?
+    }
+
+    fn read_midi(&self, count: usize) -> Result<Option<Vec<MidiMessage>>, MidiInterfaceError> {
+        self.with(move |dev| dev.input().read_midi(count))?
+    }
+
+    fn read_sysex(&self, count: usize) -> Result<Vec<Vec<u8>>, MidiInterfaceError> {
+        self.with(move |dev| dev.input().read_sysex(count))?
+    }
+}
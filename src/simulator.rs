@@ -0,0 +1,282 @@
+/* Copyright (C) 2023 Lucas Haupt
+
+This program is distributed under the terms of the 
+GNU Lesser General Public License v3.0, 
+see COPYING.LESSER file for license information
+*/
+
+//! # Simulator
+//!
+//! Terminal simulator of a Launchpad Mini (requires the `simulator` feature).
+//! Draws the 9x9 button layout of a MockSurface with colors approximating the
+//! red/green LEDs and turns mouse clicks and keyboard input into button presses.
+//! Run the LaunchDevice-based application on another thread with the mock backend:
+//!
+//! ```rust,no_run
+//! use launchpad_mini_control::{MockMidi, simulator};
+//!
+//! let midi = MockMidi::new();
+//! let surface = midi.surface(0).unwrap();
+//! std::thread::spawn(move || {
+//!     let lpads = launchpad_mini_control::new_launch_devices_from_midi_interface(&midi);
+//!     // run the application on lpads
+//! });
+//! simulator::run(surface).unwrap();
+//! ```
+//!
+//! Keys: arrows/hjkl move the cursor, space taps the button under the cursor,
+//! enter holds/releases it, q or escape quits. A left click presses the button
+//! until the mouse button is released.
+
+use std::io::{stdout, Stdout, Write};
+use std::time::{Duration, Instant};
+
+use crossterm::cursor::{Hide, MoveTo, Show};
+use crossterm::event::{
+    self, DisableMouseCapture, EnableMouseCapture, Event, KeyCode, KeyEvent, KeyEventKind,
+    KeyModifiers, MouseButton, MouseEventKind,
+};
+use crossterm::style::{
+    Color as TermColor, Print, ResetColor, SetBackgroundColor, SetForegroundColor,
+};
+use crossterm::terminal::{
+    disable_raw_mode, enable_raw_mode, Clear, ClearType, EnterAlternateScreen, LeaveAlternateScreen,
+};
+use crossterm::{execute, queue};
+
+use crate::device_state::DeviceState;
+use crate::mock_impl::MockSurface;
+use crate::MatPos;
+
+/// Terminal column the layout starts at
+const ORIGIN_X: u16 = 2;
+
+/// Terminal row the layout starts at
+const ORIGIN_Y: u16 = 2;
+
+/// Width of a button in terminal columns
+const CELL_WIDTH: u16 = 5;
+
+/// Height of a button in terminal rows
+const CELL_HEIGHT: u16 = 2;
+
+/// Time between two flips of the displayed buffer in flashing mode
+const FLASH_INTERVAL: Duration = Duration::from_millis(250);
+
+/// Time waited for terminal input per frame
+const FRAME_TIME: Duration = Duration::from_millis(30);
+
+/// Position of the button drawn in a layout row and column, the control button row
+/// (row 8) is drawn on top, None for the empty corner
+fn layout_to_pos(layout_row: u8, layout_col: u8) -> Option<MatPos> {
+    match (layout_row, layout_col) {
+        (0, 8) => None,
+        (0, col) => Some(MatPos::new(8, col)),
+        (row, col) => Some(MatPos::new(row - 1, col)),
+    }
+}
+
+/// Layout row and column a button is drawn at
+fn pos_to_layout(pos: MatPos) -> (u8, u8) {
+    if pos.row == 8 {
+        (0, pos.col)
+    } else {
+        (pos.row + 1, pos.col)
+    }
+}
+
+/// Approximate the color of a light (2 bit red, 2 bit green) in the terminal
+fn term_color(state: &DeviceState, buffer: usize, pos: MatPos) -> TermColor {
+    let vel = state
+        .buffer_color(buffer, pos)
+        .map(|c| c as u8)
+        .unwrap_or(0);
+    let red = vel & 0x03;
+    let green = (vel & 0x30) >> 4;
+    if red == 0 && green == 0 {
+        return TermColor::Rgb {
+            r: 40,
+            g: 40,
+            b: 40,
+        };
+    }
+    TermColor::Rgb {
+        r: red * 85,
+        g: green * 85,
+        b: 0,
+    }
+}
+
+/// Simulator state besides the lights
+struct Simulator {
+    surface: MockSurface,
+    cursor: MatPos,
+    held_by_key: Option<MatPos>,
+    held_by_mouse: Option<MatPos>,
+}
+impl Simulator {
+    fn move_cursor(&mut self, d_row: i8, d_col: i8) {
+        let (row, col) = pos_to_layout(self.cursor);
+        let row = (row as i8 + d_row).clamp(0, 8) as u8;
+        let col = (col as i8 + d_col).clamp(0, 8) as u8;
+        if let Some(pos) = layout_to_pos(row, col) {
+            self.cursor = pos;
+        }
+    }
+
+    /// Handle a key, returns false if the simulator should quit
+    fn handle_key(&mut self, key: KeyEvent) -> bool {
+        if key.kind == KeyEventKind::Release {
+            return true;
+        }
+        match key.code {
+            KeyCode::Char('q') | KeyCode::Esc => return false,
+            KeyCode::Char('c') if key.modifiers.contains(KeyModifiers::CONTROL) => return false,
+            KeyCode::Up | KeyCode::Char('k') => self.move_cursor(-1, 0),
+            KeyCode::Down | KeyCode::Char('j') => self.move_cursor(1, 0),
+            KeyCode::Left | KeyCode::Char('h') => self.move_cursor(0, -1),
+            KeyCode::Right | KeyCode::Char('l') => self.move_cursor(0, 1),
+            KeyCode::Char(' ') => {
+                self.surface.press(self.cursor);
+                self.surface.release(self.cursor);
+            }
+            KeyCode::Enter => match self.held_by_key.take() {
+                Some(pos) => self.surface.release(pos),
+                None => {
+                    self.surface.press(self.cursor);
+                    self.held_by_key = Some(self.cursor);
+                }
+            },
+            _ => {}
+        }
+        true
+    }
+
+    fn handle_mouse(&mut self, kind: MouseEventKind, x: u16, y: u16) {
+        match kind {
+            MouseEventKind::Down(MouseButton::Left) => {
+                if x < ORIGIN_X || y < ORIGIN_Y {
+                    return;
+                }
+                let col = (x - ORIGIN_X) / CELL_WIDTH;
+                let row = (y - ORIGIN_Y) / CELL_HEIGHT;
+                if row > 8 || col > 8 {
+                    return;
+                }
+                if let Some(pos) = layout_to_pos(row as u8, col as u8) {
+                    self.cursor = pos;
+                    self.surface.press(pos);
+                    self.held_by_mouse = Some(pos);
+                }
+            }
+            MouseEventKind::Up(MouseButton::Left) => {
+                if let Some(pos) = self.held_by_mouse.take() {
+                    self.surface.release(pos);
+                }
+            }
+            _ => {}
+        }
+    }
+
+    fn draw(&self, out: &mut Stdout, state: &DeviceState, buffer: usize) -> std::io::Result<()> {
+        queue!(out, Clear(ClearType::All), MoveTo(ORIGIN_X, 0))?;
+        let status = if self.surface.is_connected() {
+            "Launchpad Mini simulator"
+        } else {
+            "Launchpad Mini simulator (unplugged)"
+        };
+        queue!(out, Print(status))?;
+
+        for layout_row in 0..9u8 {
+            for layout_col in 0..9u8 {
+                let pos = match layout_to_pos(layout_row, layout_col) {
+                    Some(pos) => pos,
+                    None => continue,
+                };
+                let (open, close) = if pos.row == 8 || pos.col == 8 {
+                    ("(", ")")
+                } else {
+                    ("[", "]")
+                };
+                let frame = if pos == self.cursor {
+                    TermColor::White
+                } else {
+                    TermColor::DarkGrey
+                };
+
+                queue!(
+                    out,
+                    MoveTo(
+                        ORIGIN_X + layout_col as u16 * CELL_WIDTH,
+                        ORIGIN_Y + layout_row as u16 * CELL_HEIGHT
+                    ),
+                    SetForegroundColor(frame),
+                    Print(open),
+                    SetBackgroundColor(term_color(state, buffer, pos)),
+                    Print("  "),
+                    ResetColor,
+                    SetForegroundColor(frame),
+                    Print(close),
+                    ResetColor
+                )?;
+            }
+        }
+
+        queue!(
+            out,
+            MoveTo(ORIGIN_X, ORIGIN_Y + 9 * CELL_HEIGHT),
+            Print("arrows/hjkl: move  space: tap  enter: hold  click: press  q: quit")
+        )?;
+        out.flush()
+    }
+}
+
+/// Run the simulator for a unit until the user quits
+/// Returns Error, if the terminal can not be controlled
+pub fn run(surface: MockSurface) -> std::io::Result<()> {
+    let mut out = stdout();
+    enable_raw_mode()?;
+    execute!(out, EnterAlternateScreen, EnableMouseCapture, Hide)?;
+
+    let mut sim = Simulator {
+        surface,
+        cursor: MatPos::new(0, 0),
+        held_by_key: None,
+        held_by_mouse: None,
+    };
+    let res = event_loop(&mut sim, &mut out);
+
+    execute!(out, Show, DisableMouseCapture, LeaveAlternateScreen)?;
+    disable_raw_mode()?;
+    res
+}
+
+fn event_loop(sim: &mut Simulator, out: &mut Stdout) -> std::io::Result<()> {
+    let start = Instant::now();
+    let mut last_frame: Option<(DeviceState, usize, MatPos, bool)> = None;
+
+    loop {
+        let state = sim.surface.state();
+        let mut buffer = state.display_buffer();
+        if state.is_flashing() {
+            let phase = (start.elapsed().as_millis() / FLASH_INTERVAL.as_millis()) % 2;
+            buffer ^= phase as usize;
+        }
+
+        let frame = (state, buffer, sim.cursor, sim.surface.is_connected());
+        if last_frame.as_ref() != Some(&frame) {
+            sim.draw(out, &frame.0, buffer)?;
+            last_frame = Some(frame);
+        }
+
+        if !event::poll(FRAME_TIME)? {
+            continue;
+        }
+        match event::read()? {
+            Event::Key(key) if !sim.handle_key(key) => return Ok(()),
+            Event::Mouse(mouse) => sim.handle_mouse(mouse.kind, mouse.column, mouse.row),
+            Event::Resize(_, _) => last_frame = None,
+            _ => {}
+        }
+    }
+}
@@ -0,0 +1,197 @@
+/* Copyright (C) 2023 Lucas Haupt
+
+This program is distributed under the terms of the 
+GNU Lesser General Public License v3.0, 
+see COPYING.LESSER file for license information
+*/
+
+//! # Supervisor
+//!
+//! Watches a Launchpad unit for being unplugged and plugged in again.
+//! On reconnect the ports are reopened and the last known state of the
+//! Launchpad (lights, grid mode, buffer setting, duty cycle) is restored.
+//!
+//! The supervisor owns the backend context, created by a supplied function: PortMidi only
+//! knows the devices present when its context has been created, so while the unit is
+//! disconnected every check drops the context and creates a new one to find the unit again.
+//! For the same reason an unplug does not show in the device list of the context, it is
+//! noticed by reads and writes failing with a Disconnected error (PortMidi reports them
+//! as host errors). Like a SharedDevice, context and LaunchDevice live on a device thread.
+
+use std::collections::VecDeque;
+use std::sync::mpsc::Receiver;
+use std::sync::Arc;
+
+use crate::device_state::DeviceState;
+use crate::midilib::{MidiInterface, MidiInterfaceError};
+use crate::pm_impl::MidiImpl;
+use crate::shared_device::{DynLaunchDevice, SharedDevice, Target};
+use crate::units::{find_units, UnitId};
+
+/// Change of the connection state of a supervised unit
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum ConnectionEvent {
+    /// the unit has been (re)connected and its state restored
+    Connected(UnitId),
+    /// the unit has been unplugged or stopped responding
+    Disconnected(UnitId),
+}
+
+/// Device thread of a connected unit and the receiver disconnecting,
+/// once the thread has dropped its context
+type Connection = (SharedDevice, Receiver<()>);
+
+/// Create a new context and open the unit on a device thread, None if the unit is not present
+type Opener = Box<dyn Fn(&UnitId) -> Result<Option<Connection>, MidiInterfaceError> + Send>;
+
+/// Return if an error means the unit is gone (as opposed to e.g. an invalid message)
+fn is_unplug(err: &MidiInterfaceError) -> bool {
+    matches!(err, MidiInterfaceError::Disconnected(_))
+}
+
+/// Supervisor around the LaunchDevice of a single unit, reconnecting automatically
+pub struct Supervisor {
+    open: Opener,
+    unit: UnitId,
+    device: Option<Connection>,
+    last_state: DeviceState,
+    events: VecDeque<ConnectionEvent>,
+}
+impl Supervisor {
+    /// Create a supervisor for the unit with the supplied identifier (see units.rs),
+    /// creating backend contexts with the supplied function (e.g. `|| Ok(MockMidi::new())`).
+    /// The unit does not have to be connected yet, call check() to connect
+    pub fn new<M, F>(make_ctx: F, unit: UnitId) -> Supervisor
+    where
+        M: for<'a> MidiInterface<'a> + 'static,
+        F: Fn() -> Result<M, MidiInterfaceError> + Send + Sync + 'static,
+    {
+        let make_ctx = Arc::new(make_ctx);
+        let open: Opener = Box::new(move |unit: &UnitId| {
+            let make_ctx = make_ctx.clone();
+            SharedDevice::open_target(move || make_ctx(), Target::Unit(unit.clone()))
+        });
+        Supervisor {
+            open,
+            unit,
+            device: None,
+            last_state: DeviceState::default(),
+            events: VecDeque::new(),
+        }
+    }
+
+    /// Create a supervisor for the unit with the supplied identifier using PortMidi contexts
+    pub fn portmidi(unit: UnitId) -> Supervisor {
+        Supervisor::new(MidiImpl::new, unit)
+    }
+
+    /// Identifier of the supervised unit
+    pub fn unit(&self) -> &UnitId {
+        &self.unit
+    }
+
+    /// Return if the unit is currently connected
+    pub fn is_connected(&self) -> bool {
+        self.device.is_some()
+    }
+
+    /// Last known state of the Launchpad, restored on reconnect
+    pub fn last_state(&self) -> DeviceState {
+        match &self.device {
+            Some((dev, _)) => dev.state().unwrap_or_else(|_| self.last_state.clone()),
+            None => self.last_state.clone(),
+        }
+    }
+
+    /// Probe a connected unit and disconnect it, if it is gone, or look for a disconnected
+    /// unit in the device list of a new context and reconnect it.
+    /// Call regularly (e.g. once a second), every check of a disconnected unit
+    /// creates a new context.
+    /// Returns all connection events since the last check or an Error,
+    /// if probing fails for another reason or no context can be created
+    pub fn check(&mut self) -> Result<Vec<ConnectionEvent>, MidiInterfaceError> {
+        if let Some((dev, _)) = &self.device {
+            match Supervisor::probe(dev, &self.unit) {
+                Ok(true) => {}
+                Ok(false) => self.disconnect(),
+                Err(e) if is_unplug(&e) => self.disconnect(),
+                Err(e) => return Err(e),
+            }
+        } else if let Some((dev, closed)) = (self.open)(&self.unit)? {
+            // the ports might not be ready right after plugging in, retry on next check
+            let state = self.last_state.clone();
+            if let Ok(Ok(())) = dev.with(move |d| d.restore(&state)) {
+                self.device = Some((dev, closed));
+                self.events
+                    .push_back(ConnectionEvent::Connected(self.unit.clone()));
+            } else {
+                drop(dev);
+                let _ = closed.recv();
+            }
+        }
+
+        Ok(self.events.drain(..).collect())
+    }
+
+    /// Return if the unit is still present: it has to be in the device list of the context
+    /// (backends updating their list) and a poll of its input and a write to its output
+    /// have to succeed. The write repeats the current buffer setting, changing nothing
+    /// Returns Error, if the unit is gone (Disconnected) or probing fails for another reason
+    fn probe(dev: &SharedDevice, unit: &UnitId) -> Result<bool, MidiInterfaceError> {
+        let devs = dev.devices()?;
+        if !find_units(&devs, &unit.name).iter().any(|u| u.id == *unit) {
+            return Ok(false);
+        }
+        dev.with(|d| {
+            d.poll()?;
+            let setting = d.state().buffer_setting();
+            d.send_ctl_msg(0x00, 0x20 | setting)
+        })??;
+        Ok(true)
+    }
+
+    /// Run an action on the LaunchDevice (on the device thread). If the action fails
+    /// because the unit is gone (Disconnected), the unit is considered
+    /// unplugged (a Disconnected event is reported by the next check)
+    /// Returns Error, if the unit is not connected or the action fails
+    pub fn run<T, F>(&mut self, action: F) -> Result<T, MidiInterfaceError>
+    where
+        T: Send + 'static,
+        F: for<'a> FnOnce(&mut DynLaunchDevice<'a>) -> Result<T, MidiInterfaceError>
+            + Send
+            + 'static,
+    {
+        let dev = match &self.device {
+            Some((dev, _)) => dev,
+            None => {
+                return Err(MidiInterfaceError::Disconnected(format!(
+                    "{} is not connected",
+                    self.unit
+                )));
+            }
+        };
+
+        let res = dev.with(action).and_then(|res| res);
+        if let Err(e) = &res {
+            if is_unplug(e) {
+                self.disconnect();
+            }
+        }
+        res
+    }
+
+    /// Drop the ports and the context, remembering the state of the Launchpad.
+    /// Waits for the device thread to drop the context, so the next context is
+    /// not created while the old one still exists
+    fn disconnect(&mut self) {
+        if let Some((dev, closed)) = self.device.take() {
+            if let Ok(state) = dev.state() {
+                self.last_state = state;
+            }
+            drop(dev);
+            let _ = closed.recv();
+            self.events
+                .push_back(ConnectionEvent::Disconnected(self.unit.clone()));
+        }
+    }
+}
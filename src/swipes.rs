@@ -0,0 +1,203 @@
+/* Copyright (C) 2023 Lucas Haupt
+
+This program is distributed under the terms of the 
+GNU Lesser General Public License v3.0, 
+see COPYING.LESSER file for license information
+*/
+
+//! # Swipes
+//!
+//! Detection of fingers sliding across the pads: presses on adjacent pads
+//! (including diagonal neighbours) following each other within a time window
+//! form a path. A straight path is reported as a swipe with direction and speed,
+//! any other path as a drag. The round control buttons are not part of paths.
+//! Like the gesture detector, time is passed in explicitly.
+
+use std::time::{Duration, Instant};
+
+use crate::launch_device::LaunchDevice;
+use crate::midilib::{Input, MidiInterfaceError, Output};
+use crate::utils::PadEvent;
+use crate::{MatPos, TOP_ROW};
+
+/// Thresholds for detecting swipes and drags
+#[derive(Debug, Clone, Copy)]
+pub struct SwipeConfig {
+    /// maximum time between the presses of two adjacent pads of a path
+    pub step_window: Duration,
+    /// minimum number of pads of a swipe
+    pub min_swipe_len: usize,
+    /// minimum number of pads of a drag
+    pub min_drag_len: usize,
+}
+impl Default for SwipeConfig {
+    fn default() -> Self {
+        SwipeConfig {
+            step_window: Duration::from_millis(150),
+            min_swipe_len: 3,
+            min_drag_len: 2,
+        }
+    }
+}
+
+/// Direction of a swipe (up being towards the control button row)
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum SwipeDirection {
+    Up,
+    Down,
+    Left,
+    Right,
+    UpLeft,
+    UpRight,
+    DownLeft,
+    DownRight,
+}
+impl SwipeDirection {
+    /// Direction of a step between two adjacent pads, None if the pads are not adjacent
+    fn of_step(from: MatPos, to: MatPos) -> Option<SwipeDirection> {
+        let d_row = to.row as i16 - from.row as i16;
+        let d_col = to.col as i16 - from.col as i16;
+        match (d_row, d_col) {
+            (-1, 0) => Some(SwipeDirection::Up),
+            (1, 0) => Some(SwipeDirection::Down),
+            (0, -1) => Some(SwipeDirection::Left),
+            (0, 1) => Some(SwipeDirection::Right),
+            (-1, -1) => Some(SwipeDirection::UpLeft),
+            (-1, 1) => Some(SwipeDirection::UpRight),
+            (1, -1) => Some(SwipeDirection::DownLeft),
+            (1, 1) => Some(SwipeDirection::DownRight),
+            _ => None,
+        }
+    }
+}
+
+/// A detected finger movement
+#[derive(Debug, Clone, PartialEq)]
+pub enum Motion {
+    /// pads pressed along a straight line
+    Swipe {
+        direction: SwipeDirection,
+        path: Vec<MatPos>,
+        duration: Duration,
+        /// pads per second
+        speed: f32,
+    },
+    /// pads pressed along any other path
+    Drag {
+        path: Vec<MatPos>,
+        duration: Duration,
+    },
+}
+
+/// A path of presses still being extended
+struct Path {
+    pads: Vec<MatPos>,
+    start: Instant,
+    last: Instant,
+}
+
+/// Detects swipes and drags from the button presses fed into it
+pub struct SwipeDetector {
+    config: SwipeConfig,
+    paths: Vec<Path>,
+}
+impl SwipeDetector {
+    pub fn new(config: SwipeConfig) -> SwipeDetector {
+        SwipeDetector {
+            config,
+            paths: Vec::new(),
+        }
+    }
+
+    /// Feed a button press or release that happened at the supplied time,
+    /// returns the movements completed up to this time
+    pub fn feed(&mut self, event: PadEvent, at: Instant) -> Vec<Motion> {
+        let res = self.tick(at);
+        let pos = event.pos;
+        if !event.pressed || pos.row == TOP_ROW {
+            return res;
+        }
+
+        let extended = self.paths.iter_mut().find(|p| match p.pads.last() {
+            Some(last) => SwipeDirection::of_step(*last, pos).is_some(),
+            None => false,
+        });
+
+        match extended {
+            Some(path) => {
+                path.pads.push(pos);
+                path.last = at;
+            }
+            None => self.paths.push(Path {
+                pads: vec![pos],
+                start: at,
+                last: at,
+            }),
+        }
+        res
+    }
+
+    /// Advance the time without a button event, paths not extended within the
+    /// step window are completed and returned as movements
+    pub fn tick(&mut self, now: Instant) -> Vec<Motion> {
+        let window = self.config.step_window;
+        let (done, active): (Vec<Path>, Vec<Path>) = std::mem::take(&mut self.paths)
+            .into_iter()
+            .partition(|p| now.saturating_duration_since(p.last) > window);
+        self.paths = active;
+
+        done.into_iter().filter_map(|p| self.classify(p)).collect()
+    }
+
+    /// Turn a completed path into a swipe or drag, None if it is too short
+    fn classify(&self, path: Path) -> Option<Motion> {
+        let duration = path.last - path.start;
+
+        let mut directions = path
+            .pads
+            .windows(2)
+            .map(|w| SwipeDirection::of_step(w[0], w[1]));
+        let first = directions.next().flatten();
+        let straight = directions.all(|d| d == first);
+
+        match first {
+            Some(direction) if straight && path.pads.len() >= self.config.min_swipe_len => {
+                let secs = duration.as_secs_f32();
+                let speed = if secs > 0.0 {
+                    (path.pads.len() - 1) as f32 / secs
+                } else {
+                    f32::INFINITY
+                };
+                Some(Motion::Swipe {
+                    direction,
+                    path: path.pads,
+                    duration,
+                    speed,
+                })
+            }
+            _ if path.pads.len() >= self.config.min_drag_len.max(2) => Some(Motion::Drag {
+                path: path.pads,
+                duration,
+            }),
+            _ => None,
+        }
+    }
+
+    /// Read up to n messages from the Launchpad, feed them with the time they were received
+    /// and return the detected movements
+    /// Returns Error, if reading fails
+    pub fn process<I: Input, O: Output>(
+        &mut self,
+        dev: &LaunchDevice<I, O>,
+        n: usize,
+    ) -> Result<Vec<Motion>, MidiInterfaceError> {
+        let mut res = Vec::new();
+        if dev.poll()? {
+            for (event, at) in dev.read_timed_events(n)? {
+                res.extend(self.feed(event, at));
+            }
+        }
+        res.extend(self.tick(Instant::now()));
+        Ok(res)
+    }
+}
@@ -0,0 +1,139 @@
+/* Copyright (C) 2023 Lucas Haupt
+
+This program is distributed under the terms of the 
+GNU Lesser General Public License v3.0, 
+see COPYING.LESSER file for license information
+*/
+
+//! # Units
+//!
+//! Enumeration of the physical Launchpad units connected to the system.
+//! Every unit shows up as one input and one output port, which are paired up here.
+//! If several units of the same model are connected, the operating system either
+//! numbers the port names ("2- Launchpad Mini" on Windows, "Launchpad Mini #2" or
+//! "Launchpad Mini (2)" elsewhere) or lists identical names, which are then numbered
+//! in the order of enumeration.
+
+use std::collections::HashSet;
+use std::fmt::{Display, Formatter};
+
+use crate::midilib::DeviceInfo;
+
+/// Part of the port name every Launchpad Mini port contains
+pub const LAUNCHPAD_MINI_NAME: &str = "Launchpad Mini";
+
+/// Stable identifier of a physical unit, consisting of the port name
+/// (with the numbering of the operating system stripped) and the unit number
+#[derive(Debug, Clone, PartialEq, Eq, Hash, PartialOrd, Ord)]
+pub struct UnitId {
+    /// port name without numbering
+    pub name: String,
+    /// number of the unit (starting from 1)
+    pub number: u32,
+}
+
+impl Display for UnitId {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{} #{}", self.name, self.number)
+    }
+}
+
+/// Input and output port belonging to the same physical unit
+#[derive(Debug, Clone)]
+pub struct DeviceUnit {
+    /// stable identifier of the unit
+    pub id: UnitId,
+    /// input port info (button presses)
+    pub input: DeviceInfo,
+    /// output port info (lights)
+    pub output: DeviceInfo,
+}
+
+/// Split a port name into the name without numbering and the unit number,
+/// if the operating system numbered the port
+fn split_unit_number(name: &str) -> (&str, Option<u32>) {
+    // "2- Launchpad Mini"
+    if let Some((num, rest)) = name.split_once("- ") {
+        if let Ok(n) = num.parse::<u32>() {
+            return (rest, Some(n));
+        }
+    }
+
+    // "Launchpad Mini #2"
+    if let Some((rest, num)) = name.rsplit_once(" #") {
+        if let Ok(n) = num.parse::<u32>() {
+            return (rest, Some(n));
+        }
+    }
+
+    // "Launchpad Mini (2)"
+    if let Some(stripped) = name.strip_suffix(')') {
+        if let Some((rest, num)) = stripped.rsplit_once(" (") {
+            if let Ok(n) = num.parse::<u32>() {
+                return (rest, Some(n));
+            }
+        }
+    }
+
+    (name, None)
+}
+
+/// Pair the input and output ports of all devices whose name contains `name`.
+/// Ports are paired, if their names are equal after stripping the numbering,
+/// identical names are paired in the order of enumeration.
+/// Ports without counterpart are left out.
+/// Returns the units sorted by their identifier
+pub fn find_units(devs: &[DeviceInfo], name: &str) -> Vec<DeviceUnit> {
+    let candidates: Vec<&DeviceInfo> = devs.iter().filter(|d| d.name.contains(name)).collect();
+
+    let mut paired_outputs: HashSet<i32> = HashSet::new();
+    let mut pairs: Vec<(&str, Option<u32>, &DeviceInfo, &DeviceInfo)> = Vec::new();
+
+    for input in candidates.iter().filter(|d| d.is_input()) {
+        let (in_name, in_num) = split_unit_number(&input.name);
+
+        let output = candidates.iter().find(|d| {
+            d.is_output()
+                && !paired_outputs.contains(&d.id)
+                && split_unit_number(&d.name) == (in_name, in_num)
+        });
+
+        if let Some(output) = output {
+            paired_outputs.insert(output.id);
+            pairs.push((in_name, in_num, input, output));
+        }
+    }
+
+    // numbered units keep their number, the others get the next free one
+    let mut taken: HashSet<(&str, u32)> = pairs
+        .iter()
+        .filter_map(|(n, num, _, _)| num.map(|num| (*n, num)))
+        .collect();
+
+    let mut units: Vec<DeviceUnit> = Vec::with_capacity(pairs.len());
+    for (unit_name, num, input, output) in pairs {
+        let number = match num {
+            Some(n) => n,
+            None => {
+                let mut n = 1;
+                while taken.contains(&(unit_name, n)) {
+                    n += 1;
+                }
+                taken.insert((unit_name, n));
+                n
+            }
+        };
+
+        units.push(DeviceUnit {
+            id: UnitId {
+                name: unit_name.to_string(),
+                number,
+            },
+            input: input.clone(),
+            output: output.clone(),
+        });
+    }
+
+    units.sort_by(|a, b| a.id.cmp(&b.id));
+    units
+}
@@ -5,12 +5,22 @@ GNU Lesser General Public License v3.0,
 see COPYING.LESSER file for license information
 */
 
+use crate::midilib::MidiInterfaceError;
 use crate::utils::MessageType;
 use crate::utils::PadIdentifier;
 use crate::LaunchMessage;
 
+/// Number of rows and columns of the square pads
+pub const GRID_SIZE: u8 = 8;
+
+/// Column of the round scene launch buttons (right of the square pads)
+pub const SCENE_COL: u8 = 8;
+
+/// Row of the round control buttons (above the square pads)
+pub const TOP_ROW: u8 = 8;
+
 /// Struct representing a position on the Launchpad matrix with various type conversions
-#[derive(Debug)]
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
 pub struct MatPos {
     pub row: u8,
     pub col: u8,
@@ -19,9 +29,153 @@ impl MatPos {
     pub fn new(row: u8, col: u8) -> MatPos {
         MatPos { row, col }
     }
+
+    /// Position of a button of the Launchpad
+    /// Returns Error, if there is no button at the position
+    pub fn try_new(row: u8, col: u8) -> Result<MatPos, MidiInterfaceError> {
+        let pos = MatPos { row, col };
+        if !pos.is_valid() {
+            return Err(MidiInterfaceError::Invalid(format!(
+                "({}, {}) is not a button of the Launchpad",
+                row, col
+            )));
+        }
+        Ok(pos)
+    }
+
+    /// Position of a square pad
+    /// Returns Error, if the row or column is not within 0 to 7
+    pub fn grid(row: u8, col: u8) -> Result<MatPos, MidiInterfaceError> {
+        if row >= GRID_SIZE || col >= GRID_SIZE {
+            return Err(MidiInterfaceError::Invalid(format!(
+                "({}, {}) is not a pad of the grid",
+                row, col
+            )));
+        }
+        Ok(MatPos { row, col })
+    }
+
+    /// Position of a scene launch button, counted from the top
+    /// Returns Error, if the index is not within 0 to 7
+    pub fn scene(index: u8) -> Result<MatPos, MidiInterfaceError> {
+        if index >= GRID_SIZE {
+            return Err(MidiInterfaceError::Invalid(format!(
+                "scene button {} does not exist (0 to 7)",
+                index
+            )));
+        }
+        Ok(MatPos::new(index, SCENE_COL))
+    }
+
+    /// Position of a round control button, counted from the left
+    /// Returns Error, if the index is not within 0 to 7
+    pub fn top(index: u8) -> Result<MatPos, MidiInterfaceError> {
+        if index >= GRID_SIZE {
+            return Err(MidiInterfaceError::Invalid(format!(
+                "control button {} does not exist (0 to 7)",
+                index
+            )));
+        }
+        Ok(MatPos::new(TOP_ROW, index))
+    }
+
     pub fn get_as_tuple(self) -> (u8, u8) {
         (self.row, self.col)
     }
+
+    /// Return if there is a button at the position
+    pub fn is_valid(self) -> bool {
+        if self.row == TOP_ROW {
+            self.col < GRID_SIZE
+        } else {
+            self.row < GRID_SIZE && self.col <= SCENE_COL
+        }
+    }
+
+    /// Return if the position is one of the square pads
+    pub fn is_grid(self) -> bool {
+        self.row < GRID_SIZE && self.col < GRID_SIZE
+    }
+
+    /// Return if the position is a scene launch button
+    pub fn is_scene(self) -> bool {
+        self.row < GRID_SIZE && self.col == SCENE_COL
+    }
+
+    /// Return if the position is a round control button
+    pub fn is_top_row(self) -> bool {
+        self.row == TOP_ROW && self.col < GRID_SIZE
+    }
+
+    /// Position as laid out on the device: (line from the top, with the control
+    /// button row being line 0, column)
+    fn layout(self) -> (i16, i16) {
+        if self.row == TOP_ROW {
+            (0, self.col as i16)
+        } else {
+            (self.row as i16 + 1, self.col as i16)
+        }
+    }
+
+    /// Button at an offset of lines (positive is down) and columns (positive is right)
+    /// as laid out on the device, None if there is no button there.
+    /// The control button row is above row 0, so up from row 0 is the control button row
+    pub fn offset(self, d_row: i8, d_col: i8) -> Option<MatPos> {
+        let (line, col) = self.layout();
+        let line = line + d_row as i16;
+        let col = col + d_col as i16;
+        if !(0..=GRID_SIZE as i16).contains(&line) || !(0..=SCENE_COL as i16).contains(&col) {
+            return None;
+        }
+        let pos = if line == 0 {
+            MatPos::new(TOP_ROW, col as u8)
+        } else {
+            MatPos::new(line as u8 - 1, col as u8)
+        };
+        Some(pos).filter(|p| p.is_valid())
+    }
+
+    /// Button above, None at the top edge
+    pub fn up(self) -> Option<MatPos> {
+        self.offset(-1, 0)
+    }
+
+    /// Button below, None at the bottom edge
+    pub fn down(self) -> Option<MatPos> {
+        self.offset(1, 0)
+    }
+
+    /// Button to the left, None at the left edge
+    pub fn left(self) -> Option<MatPos> {
+        self.offset(0, -1)
+    }
+
+    /// Button to the right, None at the right edge
+    pub fn right(self) -> Option<MatPos> {
+        self.offset(0, 1)
+    }
+
+    /// Buttons above, below, left and right (those existing)
+    pub fn neighbours(self) -> Vec<MatPos> {
+        [(-1, 0), (1, 0), (0, -1), (0, 1)]
+            .into_iter()
+            .filter_map(|(d_row, d_col)| self.offset(d_row, d_col))
+            .collect()
+    }
+
+    /// Buttons around the position including the diagonal ones (those existing)
+    pub fn surrounding(self) -> Vec<MatPos> {
+        let mut res = Vec::with_capacity(8);
+        for d_row in -1..=1 {
+            for d_col in -1..=1 {
+                if d_row == 0 && d_col == 0 {
+                    continue;
+                }
+                res.extend(self.offset(d_row, d_col));
+            }
+        }
+        res
+    }
 }
 impl From<LaunchMessage> for MatPos {
     fn from(msg: LaunchMessage) -> Self {
@@ -4,8 +4,10 @@ This program is distributed under the terms of the
 GNU Lesser General Public License v3.0, 
 see COPYING.LESSER file for license information
 */
+use std::str::FromStr;
+
 use crate::utils::PadIdentifier;
-use crate::MatPos;
+use crate::{MatPos, MidiInterfaceError};
 
 /// Color gradient array, trying to sort all colors on a spectrum
 const COLOR_GRADIENT: [Color; 16] = [
@@ -38,7 +40,7 @@ pub enum MessageType {
 }
 
 /// All colors the Launchpad is able to display
-#[derive(Copy, Clone, Debug)]
+#[derive(Copy, Clone, Debug, PartialEq, Eq, Hash)]
 pub enum Color {
     Black = 0x00,
     DimGreen = 0x10,
@@ -58,6 +60,66 @@ pub enum Color {
     DimRed = 0x01,
 }
 
+impl From<u8> for Color {
+    /// Convert a velocity byte into a Color, the flag bits (copy and clear) are ignored
+    fn from(value: u8) -> Self {
+        match value & 0x33 {
+            0x10 => Color::DimGreen,
+            0x20 => Color::MedGreen,
+            0x30 => Color::Green,
+            0x31 => Color::Grellow,
+            0x21 => Color::DimGrellow,
+            0x32 => Color::Yellow,
+            0x22 => Color::MedYellow,
+            0x11 => Color::DimYellow,
+            0x33 => Color::YellOrange,
+            0x23 => Color::Orange,
+            0x12 => Color::DimORedange,
+            0x13 => Color::ORedange,
+            0x03 => Color::Red,
+            0x02 => Color::MedRed,
+            0x01 => Color::DimRed,
+            _ => Color::Black,
+        }
+    }
+}
+
+impl FromStr for Color {
+    type Err = MidiInterfaceError;
+
+    /// Parse a Color from its name (case insensitive, e.g. "medyellow")
+    /// or its velocity byte in hex (e.g. "0x22", flag bits are not accepted)
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        if let Some(hex) = s.strip_prefix("0x") {
+            let vel = u8::from_str_radix(hex, 16).map_err(|e| {
+                MidiInterfaceError::Invalid(format!("invalid color {}: {}", s, e))
+            })?;
+            return match COLOR_GRADIENT.iter().find(|c| **c as u8 == vel) {
+                Some(c) => Ok(*c),
+                None => Err(MidiInterfaceError::Invalid(format!(
+                    "invalid color {}: no color has this velocity",
+                    s
+                ))),
+            };
+        }
+
+        match COLOR_GRADIENT
+            .iter()
+            .find(|c| format!("{:?}", c).eq_ignore_ascii_case(s))
+        {
+            Some(c) => Ok(*c),
+            None => Err(MidiInterfaceError::Invalid(format!("unknown color {}", s))),
+        }
+    }
+}
+
+/// Brightness of the LED test, turning on all lights of the Launchpad
+pub enum LedTestBrightness {
+    Low = 0x7D,
+    Medium = 0x7E,
+    Full = 0x7F,
+}
+
 /// Buffer modes for the Launchpad.
 /// The Launchpad has two internal buffers, enabling it to make use of double buffering
 /// There are four possible modes:
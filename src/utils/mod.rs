@@ -5,7 +5,15 @@
 mod mat_pos;
 mod pad_identifier;
 mod misc;
+mod pad_event;
+mod orientation;
+mod region;
+mod notes;
 
 pub use self::mat_pos::*;
 pub use self::pad_identifier::*;
 pub use self::misc::*;
+pub use self::pad_event::*;
+pub use self::orientation::*;
+pub use self::region::*;
+pub(crate) use self::notes::*;
@@ -0,0 +1,51 @@
+/* Copyright (C) 2023 Lucas Haupt
+
+This program is distributed under the terms of the 
+GNU Lesser General Public License v3.0, 
+see COPYING.LESSER file for license information
+*/
+use std::collections::HashMap;
+
+use crate::midilib::{LaunchMessage, MidiInterfaceError, Output};
+use crate::utils::{MatPos, MessageType};
+
+/// Note on (or note off, sent with velocity 0) message on a midi channel
+pub(crate) fn note_message(channel: u8, note: u8, on: bool, velocity: u8) -> LaunchMessage {
+    let status = if on {
+        MessageType::On
+    } else {
+        MessageType::Off
+    };
+    LaunchMessage {
+        status: status as u8 | channel,
+        data1: note,
+        data2: if on { velocity } else { 0x00 },
+    }
+}
+
+/// Returns Error, if the midi channel is not within 0 to 15
+pub(crate) fn check_channel(channel: u8) -> Result<(), MidiInterfaceError> {
+    if channel > 15 {
+        return Err(MidiInterfaceError::Invalid(format!(
+            "channel {} out of range (0 to 15)",
+            channel
+        )));
+    }
+    Ok(())
+}
+
+/// Send note off for all notes held by the pads (once per note) and forget them
+/// Returns Error, if sending fails
+pub(crate) fn release_notes<O: Output>(
+    out_port: &mut O,
+    channel: u8,
+    held: &mut HashMap<MatPos, u8>,
+) -> Result<(), MidiInterfaceError> {
+    let mut notes: Vec<u8> = held.drain().map(|(_, note)| note).collect();
+    notes.sort_unstable();
+    notes.dedup();
+    for note in notes {
+        out_port.write_message(note_message(channel, note, false, 0x00))?;
+    }
+    Ok(())
+}
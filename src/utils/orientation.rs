@@ -0,0 +1,172 @@
+/* Copyright (C) 2023 Lucas Haupt
+
+This program is distributed under the terms of the 
+GNU Lesser General Public License v3.0, 
+see COPYING.LESSER file for license information
+*/
+use crate::utils::{led_key, MatPos, MessageType, PadIdentifier, Region};
+use crate::utils::{GRID_SIZE, SCENE_COL, TOP_ROW};
+use crate::LaunchMessage;
+
+/// Clockwise rotation of a square grid (e.g. a Launchpad mounted sideways)
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum Rotation {
+    #[default]
+    Deg0,
+    Deg90,
+    Deg180,
+    Deg270,
+}
+impl Rotation {
+    /// Rotate a position on a square grid of the given size clockwise
+    pub fn apply(self, pos: MatPos, size: u8) -> MatPos {
+        let last = size - 1;
+        match self {
+            Rotation::Deg0 => pos,
+            Rotation::Deg90 => MatPos::new(pos.col, last - pos.row),
+            Rotation::Deg180 => MatPos::new(last - pos.row, last - pos.col),
+            Rotation::Deg270 => MatPos::new(last - pos.col, pos.row),
+        }
+    }
+
+    /// Return the rotation undoing this rotation
+    pub fn inverse(self) -> Rotation {
+        match self {
+            Rotation::Deg0 => Rotation::Deg0,
+            Rotation::Deg90 => Rotation::Deg270,
+            Rotation::Deg180 => Rotation::Deg180,
+            Rotation::Deg270 => Rotation::Deg90,
+        }
+    }
+}
+
+/// Handling of the round buttons (scene column and control button row)
+/// when the Launchpad is rotated or mirrored
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum StripPolicy {
+    /// the round buttons keep their positions: scene buttons are column 8,
+    /// control buttons row 8, numbered as on the unrotated device
+    #[default]
+    Fixed,
+    /// the round buttons turn with the grid: the strip running horizontally as seen
+    /// is addressed as control button row, the vertical one as scene column,
+    /// both numbered as seen (from left to right and from top to bottom)
+    Follow,
+}
+
+/// Mounting of a Launchpad: rotation and mirroring of the 8x8 grid as seen by the app
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub struct Orientation {
+    /// clockwise rotation of the device as mounted
+    pub rotation: Rotation,
+    /// the device is seen mirrored from left to right (before the rotation),
+    /// mirrored and rotated by 180 degrees is mirrored from top to bottom
+    pub mirrored: bool,
+    pub strips: StripPolicy,
+}
+impl Orientation {
+    pub fn new(rotation: Rotation, mirrored: bool, strips: StripPolicy) -> Orientation {
+        Orientation {
+            rotation,
+            mirrored,
+            strips,
+        }
+    }
+
+    /// Return if positions are not changed at all
+    pub fn is_identity(self) -> bool {
+        self.rotation == Rotation::Deg0 && !self.mirrored
+    }
+
+    /// Position as seen of a button of the device
+    /// (positions without button are returned unchanged)
+    pub fn to_logical(self, pos: MatPos) -> MatPos {
+        if !pos.is_valid() || (!pos.is_grid() && self.strips == StripPolicy::Fixed) {
+            return pos;
+        }
+
+        // the grid with the strips around it as 10x10 square, the device's
+        // control buttons are line 0 and its scene buttons column 9
+        let (row, col) = if pos.row == TOP_ROW {
+            (0, pos.col + 1)
+        } else {
+            (pos.row + 1, pos.col + 1)
+        };
+        let col = if self.mirrored { 9 - col } else { col };
+        let seen = self.rotation.apply(MatPos::new(row, col), 10);
+
+        if seen.row == 0 || seen.row == 9 {
+            MatPos::new(TOP_ROW, seen.col - 1)
+        } else if seen.col == 0 || seen.col == 9 {
+            MatPos::new(seen.row - 1, SCENE_COL)
+        } else {
+            MatPos::new(seen.row - 1, seen.col - 1)
+        }
+    }
+
+    /// Button of the device at a position as seen
+    /// (positions without button are returned unchanged)
+    pub fn to_physical(self, pos: MatPos) -> MatPos {
+        if !pos.is_valid() {
+            return pos;
+        }
+        if pos.is_grid() {
+            let unrotated = self.rotation.inverse().apply(pos, GRID_SIZE);
+            return if self.mirrored {
+                MatPos::new(unrotated.row, GRID_SIZE - 1 - unrotated.col)
+            } else {
+                unrotated
+            };
+        }
+        if self.strips == StripPolicy::Fixed {
+            return pos;
+        }
+        Region::SCENE_COLUMN
+            .positions()
+            .chain(Region::TOP_ROW.positions())
+            .find(|p| self.to_logical(*p) == pos)
+            .unwrap_or(pos)
+    }
+
+    /// Message with the button of a light or button message moved to another position
+    /// (note messages become control messages for the control button row and vice versa)
+    fn moved(msg: &LaunchMessage, pos: MatPos) -> LaunchMessage {
+        let off = msg.status == MessageType::Off as u8;
+        let padid = PadIdentifier::from(pos);
+        match padid.status {
+            MessageType::Ctl => LaunchMessage {
+                status: MessageType::Ctl as u8,
+                data1: padid.key,
+                // a control message turning the light off, keeping the buffer flags
+                data2: if off { msg.data2 & 0x0C } else { msg.data2 },
+            },
+            _ => LaunchMessage {
+                status: if off {
+                    MessageType::Off as u8
+                } else {
+                    MessageType::On as u8
+                },
+                data1: padid.key,
+                data2: msg.data2,
+            },
+        }
+    }
+
+    /// Light message addressed to a position as seen, sent to the button of the device
+    /// (other messages are returned unchanged, XY mode layout)
+    pub(crate) fn message_to_physical(self, msg: &LaunchMessage) -> LaunchMessage {
+        match led_key(msg) {
+            Some(_) => Orientation::moved(msg, self.to_physical(MatPos::from(msg.clone()))),
+            None => msg.clone(),
+        }
+    }
+
+    /// Button message received from the device, moved to the position as seen
+    /// (other messages are returned unchanged, XY mode layout)
+    pub(crate) fn message_to_logical(self, msg: &LaunchMessage) -> LaunchMessage {
+        match led_key(msg) {
+            Some(_) => Orientation::moved(msg, self.to_logical(MatPos::from(msg.clone()))),
+            None => msg.clone(),
+        }
+    }
+}
@@ -0,0 +1,33 @@
+/* Copyright (C) 2023 Lucas Haupt
+
+This program is distributed under the terms of the 
+GNU Lesser General Public License v3.0, 
+see COPYING.LESSER file for license information
+*/
+use crate::utils::MatPos;
+use crate::utils::MessageType;
+use crate::LaunchMessage;
+
+/// A button on the Launchpad being pressed or released
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub struct PadEvent {
+    /// position of the button (row 8 being the control button row)
+    pub pos: MatPos,
+    /// true if the button has been pressed, false if released
+    pub pressed: bool,
+}
+impl PadEvent {
+    pub fn new(pos: MatPos, pressed: bool) -> PadEvent {
+        PadEvent { pos, pressed }
+    }
+}
+impl From<LaunchMessage> for PadEvent {
+    fn from(msg: LaunchMessage) -> Self {
+        // the Launchpad sends a velocity of 0 on release (note off messages are handled likewise)
+        let pressed = msg.status != MessageType::Off as u8 && msg.data2 > 0;
+        PadEvent {
+            pos: MatPos::from(msg),
+            pressed,
+        }
+    }
+}
@@ -6,6 +6,7 @@ see COPYING.LESSER file for license information
 */
 use crate::utils::MessageType;
 use crate::utils::MatPos;
+use crate::utils::Color;
 use crate::LaunchMessage;
 
 /// PadIdentifier struct representing a button in appropriate midi data
@@ -48,3 +49,29 @@ impl From<LaunchMessage> for PadIdentifier {
         }
     }
 }
+
+/// Message setting the light of a button to a color (row 8 being the control button row)
+pub(crate) fn light_message(pos: MatPos, color: Color) -> LaunchMessage {
+    let padid = PadIdentifier::from(pos);
+    LaunchMessage {
+        status: padid.status as u8,
+        data1: padid.key,
+        data2: color as u8,
+    }
+}
+
+/// Light set by a message (grid and scene buttons by note on or off, control buttons by CC),
+/// as status and key byte (note off counting as note on), None for other messages
+pub(crate) fn led_key(msg: &LaunchMessage) -> Option<(u8, u8)> {
+    match (msg.status, msg.data1) {
+        (0x80 | 0x90, key) => Some((0x90, key)),
+        (0xB0, key @ 0x68..=0x6F) => Some((0xB0, key)),
+        _ => None,
+    }
+}
+
+/// Velocity flags (copy 0x04 and clear 0x08) of a light message: a change of a light
+/// only supersedes an earlier one writing the same buffers, so the flags have to match
+pub(crate) fn led_flags(msg: &LaunchMessage) -> u8 {
+    msg.data2 & 0x0C
+}
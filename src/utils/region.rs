@@ -0,0 +1,148 @@
+/* Copyright (C) 2023 Lucas Haupt
+
+This program is distributed under the terms of the 
+GNU Lesser General Public License v3.0, 
+see COPYING.LESSER file for license information
+*/
+use crate::midilib::MidiInterfaceError;
+use crate::utils::{MatPos, GRID_SIZE, SCENE_COL, TOP_ROW};
+
+/// Rectangle of buttons on the Launchpad, given by its first row and column and its size.
+/// Rows are counted like in MatPos, so the control button row (row 8) can only be part
+/// of a region one row high
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub struct Region {
+    row: u8,
+    col: u8,
+    height: u8,
+    width: u8,
+}
+impl Region {
+    /// The 8x8 square pads
+    pub const GRID: Region = Region {
+        row: 0,
+        col: 0,
+        height: GRID_SIZE,
+        width: GRID_SIZE,
+    };
+
+    /// The scene launch buttons (right of the square pads)
+    pub const SCENE_COLUMN: Region = Region {
+        row: 0,
+        col: SCENE_COL,
+        height: GRID_SIZE,
+        width: 1,
+    };
+
+    /// The round control buttons (above the square pads)
+    pub const TOP_ROW: Region = Region {
+        row: TOP_ROW,
+        col: 0,
+        height: 1,
+        width: GRID_SIZE,
+    };
+
+    /// Create a region of height rows and width columns starting at row and col
+    /// Returns Error, if the region is empty or not completely on the Launchpad
+    pub fn new(row: u8, col: u8, height: u8, width: u8) -> Result<Region, MidiInterfaceError> {
+        let fits = if row == TOP_ROW {
+            height == 1 && col as usize + width as usize <= GRID_SIZE as usize
+        } else {
+            row as usize + height as usize <= GRID_SIZE as usize
+                && col as usize + width as usize <= SCENE_COL as usize + 1
+        };
+        if height == 0 || width == 0 || !fits {
+            return Err(MidiInterfaceError::Invalid(format!(
+                "region of {}x{} buttons at ({}, {}) is not on the Launchpad",
+                height, width, row, col
+            )));
+        }
+        Ok(Region {
+            row,
+            col,
+            height,
+            width,
+        })
+    }
+
+    /// Create a region reaching from one position to another (both included)
+    /// Returns Error, if the region is not completely on the Launchpad
+    pub fn spanning(from: MatPos, to: MatPos) -> Result<Region, MidiInterfaceError> {
+        let row = from.row.min(to.row);
+        let col = from.col.min(to.col);
+        Region::new(
+            row,
+            col,
+            from.row.max(to.row) - row + 1,
+            from.col.max(to.col) - col + 1,
+        )
+    }
+
+    /// Upper left position (lowest row and column)
+    pub fn origin(&self) -> MatPos {
+        MatPos::new(self.row, self.col)
+    }
+
+    /// Number of rows
+    pub fn height(&self) -> u8 {
+        self.height
+    }
+
+    /// Number of columns
+    pub fn width(&self) -> u8 {
+        self.width
+    }
+
+    /// Return if the position is part of the region
+    pub fn contains(&self, pos: MatPos) -> bool {
+        self.local(pos).is_some()
+    }
+
+    /// Position at a row and column counted within the region, None if outside of the region
+    pub fn at(&self, row: u8, col: u8) -> Option<MatPos> {
+        if row >= self.height || col >= self.width {
+            return None;
+        }
+        Some(MatPos::new(self.row + row, self.col + col))
+    }
+
+    /// Row and column of a position counted within the region, None if outside of the region
+    pub fn local(&self, pos: MatPos) -> Option<(u8, u8)> {
+        let row = pos.row.checked_sub(self.row).filter(|r| *r < self.height)?;
+        let col = pos.col.checked_sub(self.col).filter(|c| *c < self.width)?;
+        Some((row, col))
+    }
+
+    /// Positions of a row counted within the region, from left to right
+    /// (none if the row is outside of the region)
+    pub fn row(&self, row: u8) -> impl Iterator<Item = MatPos> {
+        let region = *self;
+        let width = if row < self.height { self.width } else { 0 };
+        (0..width).map(move |col| MatPos::new(region.row + row, region.col + col))
+    }
+
+    /// Positions of a column counted within the region, from top to bottom
+    /// (none if the column is outside of the region)
+    pub fn col(&self, col: u8) -> impl Iterator<Item = MatPos> {
+        let region = *self;
+        let height = if col < self.width { self.height } else { 0 };
+        (0..height).map(move |row| MatPos::new(region.row + row, region.col + col))
+    }
+
+    /// All rows of the region from top to bottom
+    pub fn rows(&self) -> impl Iterator<Item = impl Iterator<Item = MatPos>> {
+        let region = *self;
+        (0..self.height).map(move |row| region.row(row))
+    }
+
+    /// All columns of the region from left to right
+    pub fn cols(&self) -> impl Iterator<Item = impl Iterator<Item = MatPos>> {
+        let region = *self;
+        (0..self.width).map(move |col| region.col(col))
+    }
+
+    /// All positions of the region, row by row
+    pub fn positions(&self) -> impl Iterator<Item = MatPos> {
+        self.rows().flatten()
+    }
+}
@@ -0,0 +1,541 @@
+/* Copyright (C) 2023 Lucas Haupt
+
+This program is distributed under the terms of the 
+GNU Lesser General Public License v3.0, 
+see COPYING.LESSER file for license information
+*/
+
+//! # Widgets
+//!
+//! Reusable controls owning a group of buttons: toggles, momentary buttons,
+//! radio groups, faders and XY pads. A widget consumes the button events on its
+//! buttons, renders its lights and reports when its value changes.
+//! A WidgetSet dispatches the events of a Launchpad to its widgets and redraws them.
+
+use crate::launch_device::LaunchDevice;
+use crate::midilib::{Input, LaunchMessage, MidiInterfaceError, Output};
+use crate::utils::{light_message, PadEvent};
+use crate::{Color, MatPos};
+
+/// Number of pads of a fader
+pub const FADER_LEN: u8 = 8;
+
+/// Value of a widget
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum WidgetValue {
+    /// state of a toggle or momentary button
+    Switch(bool),
+    /// index of the selected button of a radio group
+    Selected(u8),
+    /// number of lit pads of a fader (0 to 8)
+    Level(u8),
+    /// position on an XY pad (x from the left, y from the bottom)
+    Xy(u8, u8),
+}
+
+/// Value change of a widget in a WidgetSet
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub struct WidgetEvent {
+    /// index of the widget in the WidgetSet
+    pub widget: usize,
+    pub value: WidgetValue,
+}
+
+/// A control owning a group of buttons
+pub trait Widget {
+    /// Return if the button at a position belongs to the widget
+    fn contains(&self, pos: MatPos) -> bool;
+
+    /// Handle a press or release of one of the buttons of the widget,
+    /// returns the new value if it has changed
+    fn handle(&mut self, event: PadEvent) -> Option<WidgetValue>;
+
+    /// Colors of the buttons of the widget
+    fn render(&self) -> Vec<(MatPos, Color)>;
+
+    /// Current value of the widget
+    fn value(&self) -> WidgetValue;
+
+    /// Set the value of the widget (e.g. from a DAW)
+    /// Returns Error, if the value does not fit the widget
+    fn set_value(&mut self, value: WidgetValue) -> Result<(), MidiInterfaceError>;
+
+    /// Messages setting the lights of the widget
+    fn messages(&self) -> Vec<LaunchMessage> {
+        self.render()
+            .into_iter()
+            .map(|(pos, color)| light_message(pos, color))
+            .collect()
+    }
+}
+
+/// Error for a value not fitting a widget
+fn invalid_value(widget: &str, value: WidgetValue) -> MidiInterfaceError {
+    MidiInterfaceError::Invalid(format!("{:?} is not a value of a {}", value, widget))
+}
+
+/// Latching button switching between on and off with every press
+#[derive(Debug, Clone)]
+pub struct Toggle {
+    pub pos: MatPos,
+    pub on: bool,
+    pub on_color: Color,
+    pub off_color: Color,
+}
+impl Toggle {
+    pub fn new(pos: MatPos, on_color: Color, off_color: Color) -> Toggle {
+        Toggle {
+            pos,
+            on: false,
+            on_color,
+            off_color,
+        }
+    }
+}
+impl Widget for Toggle {
+    fn contains(&self, pos: MatPos) -> bool {
+        pos == self.pos
+    }
+
+    fn handle(&mut self, event: PadEvent) -> Option<WidgetValue> {
+        if !event.pressed || !self.contains(event.pos) {
+            return None;
+        }
+        self.on = !self.on;
+        Some(self.value())
+    }
+
+    fn render(&self) -> Vec<(MatPos, Color)> {
+        let color = if self.on {
+            self.on_color
+        } else {
+            self.off_color
+        };
+        vec![(self.pos, color)]
+    }
+
+    fn value(&self) -> WidgetValue {
+        WidgetValue::Switch(self.on)
+    }
+
+    fn set_value(&mut self, value: WidgetValue) -> Result<(), MidiInterfaceError> {
+        match value {
+            WidgetValue::Switch(on) => {
+                self.on = on;
+                Ok(())
+            }
+            _ => Err(invalid_value("toggle", value)),
+        }
+    }
+}
+
+/// Button being on while held
+#[derive(Debug, Clone)]
+pub struct Momentary {
+    pub pos: MatPos,
+    pub pressed: bool,
+    pub on_color: Color,
+    pub off_color: Color,
+}
+impl Momentary {
+    pub fn new(pos: MatPos, on_color: Color, off_color: Color) -> Momentary {
+        Momentary {
+            pos,
+            pressed: false,
+            on_color,
+            off_color,
+        }
+    }
+}
+impl Widget for Momentary {
+    fn contains(&self, pos: MatPos) -> bool {
+        pos == self.pos
+    }
+
+    fn handle(&mut self, event: PadEvent) -> Option<WidgetValue> {
+        if !self.contains(event.pos) || event.pressed == self.pressed {
+            return None;
+        }
+        self.pressed = event.pressed;
+        Some(self.value())
+    }
+
+    fn render(&self) -> Vec<(MatPos, Color)> {
+        let color = if self.pressed {
+            self.on_color
+        } else {
+            self.off_color
+        };
+        vec![(self.pos, color)]
+    }
+
+    fn value(&self) -> WidgetValue {
+        WidgetValue::Switch(self.pressed)
+    }
+
+    fn set_value(&mut self, value: WidgetValue) -> Result<(), MidiInterfaceError> {
+        match value {
+            WidgetValue::Switch(pressed) => {
+                self.pressed = pressed;
+                Ok(())
+            }
+            _ => Err(invalid_value("momentary button", value)),
+        }
+    }
+}
+
+/// Buttons next to each other in a row of which exactly one is selected
+/// (row 8 being the control button row)
+#[derive(Debug, Clone)]
+pub struct RadioGroup {
+    pub row: u8,
+    pub first_col: u8,
+    pub len: u8,
+    pub selected: u8,
+    pub on_color: Color,
+    pub off_color: Color,
+}
+impl RadioGroup {
+    /// Create a radio group of len buttons starting at first_col, the first button is selected
+    pub fn new(row: u8, first_col: u8, len: u8, on_color: Color, off_color: Color) -> RadioGroup {
+        RadioGroup {
+            row,
+            first_col,
+            len,
+            selected: 0,
+            on_color,
+            off_color,
+        }
+    }
+}
+impl Widget for RadioGroup {
+    fn contains(&self, pos: MatPos) -> bool {
+        pos.row == self.row && pos.col >= self.first_col && pos.col < self.first_col + self.len
+    }
+
+    fn handle(&mut self, event: PadEvent) -> Option<WidgetValue> {
+        if !event.pressed || !self.contains(event.pos) {
+            return None;
+        }
+        let index = event.pos.col - self.first_col;
+        if index == self.selected {
+            return None;
+        }
+        self.selected = index;
+        Some(self.value())
+    }
+
+    fn render(&self) -> Vec<(MatPos, Color)> {
+        (0..self.len)
+            .map(|i| {
+                let color = if i == self.selected {
+                    self.on_color
+                } else {
+                    self.off_color
+                };
+                (MatPos::new(self.row, self.first_col + i), color)
+            })
+            .collect()
+    }
+
+    fn value(&self) -> WidgetValue {
+        WidgetValue::Selected(self.selected)
+    }
+
+    fn set_value(&mut self, value: WidgetValue) -> Result<(), MidiInterfaceError> {
+        match value {
+            WidgetValue::Selected(index) if index < self.len => {
+                self.selected = index;
+                Ok(())
+            }
+            _ => Err(invalid_value("radio group", value)),
+        }
+    }
+}
+
+/// Direction a fader extends in
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+#[cfg_attr(
+    feature = "serde",
+    derive(serde::Serialize, serde::Deserialize),
+    serde(rename_all = "snake_case")
+)]
+pub enum FaderOrientation {
+    /// a column of the grid, filling from the bottom
+    Vertical,
+    /// a row of the grid, filling from the left
+    Horizontal,
+}
+
+/// Column or row of 8 pads showing a level, pressing a pad sets the level up to it
+/// (pressing the topmost lit pad again lowers the level by one, down to 0)
+#[derive(Debug, Clone)]
+pub struct Fader {
+    pub orientation: FaderOrientation,
+    /// column of a vertical, row of a horizontal fader
+    pub index: u8,
+    pub level: u8,
+    pub on_color: Color,
+    pub off_color: Color,
+}
+impl Fader {
+    pub fn new(
+        orientation: FaderOrientation,
+        index: u8,
+        on_color: Color,
+        off_color: Color,
+    ) -> Fader {
+        Fader {
+            orientation,
+            index,
+            level: 0,
+            on_color,
+            off_color,
+        }
+    }
+
+    /// Position of the pad showing a step of the level (0 being the first pad)
+    fn pad(&self, step: u8) -> MatPos {
+        match self.orientation {
+            FaderOrientation::Vertical => MatPos::new(FADER_LEN - 1 - step, self.index),
+            FaderOrientation::Horizontal => MatPos::new(self.index, step),
+        }
+    }
+
+    /// Step shown by the pad at a position
+    fn step(&self, pos: MatPos) -> u8 {
+        match self.orientation {
+            FaderOrientation::Vertical => FADER_LEN - 1 - pos.row,
+            FaderOrientation::Horizontal => pos.col,
+        }
+    }
+}
+impl Widget for Fader {
+    fn contains(&self, pos: MatPos) -> bool {
+        match self.orientation {
+            FaderOrientation::Vertical => pos.col == self.index && pos.row < FADER_LEN,
+            FaderOrientation::Horizontal => pos.row == self.index && pos.col < FADER_LEN,
+        }
+    }
+
+    fn handle(&mut self, event: PadEvent) -> Option<WidgetValue> {
+        if !event.pressed || !self.contains(event.pos) {
+            return None;
+        }
+        let step = self.step(event.pos);
+        self.level = if self.level == step + 1 {
+            step
+        } else {
+            step + 1
+        };
+        Some(self.value())
+    }
+
+    fn render(&self) -> Vec<(MatPos, Color)> {
+        (0..FADER_LEN)
+            .map(|step| {
+                let color = if step < self.level {
+                    self.on_color
+                } else {
+                    self.off_color
+                };
+                (self.pad(step), color)
+            })
+            .collect()
+    }
+
+    fn value(&self) -> WidgetValue {
+        WidgetValue::Level(self.level)
+    }
+
+    fn set_value(&mut self, value: WidgetValue) -> Result<(), MidiInterfaceError> {
+        match value {
+            WidgetValue::Level(level) if level <= FADER_LEN => {
+                self.level = level;
+                Ok(())
+            }
+            _ => Err(invalid_value("fader", value)),
+        }
+    }
+}
+
+/// Rectangle of pads selecting a two-dimensional position, shown as a cursor
+/// with a crosshair through it
+#[derive(Debug, Clone)]
+pub struct XyPad {
+    /// top left pad
+    pub origin: MatPos,
+    pub width: u8,
+    pub height: u8,
+    pub x: u8,
+    pub y: u8,
+    pub cursor_color: Color,
+    pub cross_color: Color,
+    pub off_color: Color,
+}
+impl XyPad {
+    /// Create an XY pad with the cursor in the bottom left corner
+    pub fn new(
+        origin: MatPos,
+        width: u8,
+        height: u8,
+        cursor_color: Color,
+        cross_color: Color,
+        off_color: Color,
+    ) -> XyPad {
+        XyPad {
+            origin,
+            width,
+            height,
+            x: 0,
+            y: 0,
+            cursor_color,
+            cross_color,
+            off_color,
+        }
+    }
+}
+impl Widget for XyPad {
+    fn contains(&self, pos: MatPos) -> bool {
+        pos.row >= self.origin.row
+            && pos.row < self.origin.row + self.height
+            && pos.col >= self.origin.col
+            && pos.col < self.origin.col + self.width
+    }
+
+    fn handle(&mut self, event: PadEvent) -> Option<WidgetValue> {
+        if !event.pressed || !self.contains(event.pos) {
+            return None;
+        }
+        let x = event.pos.col - self.origin.col;
+        let y = self.origin.row + self.height - 1 - event.pos.row;
+        if (x, y) == (self.x, self.y) {
+            return None;
+        }
+        self.x = x;
+        self.y = y;
+        Some(self.value())
+    }
+
+    fn render(&self) -> Vec<(MatPos, Color)> {
+        let mut res = Vec::with_capacity(self.width as usize * self.height as usize);
+        for y in 0..self.height {
+            for x in 0..self.width {
+                let color = if (x, y) == (self.x, self.y) {
+                    self.cursor_color
+                } else if x == self.x || y == self.y {
+                    self.cross_color
+                } else {
+                    self.off_color
+                };
+                let pos = MatPos::new(self.origin.row + self.height - 1 - y, self.origin.col + x);
+                res.push((pos, color));
+            }
+        }
+        res
+    }
+
+    fn value(&self) -> WidgetValue {
+        WidgetValue::Xy(self.x, self.y)
+    }
+
+    fn set_value(&mut self, value: WidgetValue) -> Result<(), MidiInterfaceError> {
+        match value {
+            WidgetValue::Xy(x, y) if x < self.width && y < self.height => {
+                self.x = x;
+                self.y = y;
+                Ok(())
+            }
+            _ => Err(invalid_value("XY pad", value)),
+        }
+    }
+}
+
+/// Collection of widgets on one Launchpad, events are dispatched to the first
+/// widget containing the button
+#[derive(Default)]
+pub struct WidgetSet {
+    widgets: Vec<Box<dyn Widget>>,
+}
+impl WidgetSet {
+    pub fn new() -> WidgetSet {
+        WidgetSet::default()
+    }
+
+    /// Add a widget, returns its index
+    pub fn add(&mut self, widget: impl Widget + 'static) -> usize {
+        self.widgets.push(Box::new(widget));
+        self.widgets.len() - 1
+    }
+
+    pub fn get(&self, widget: usize) -> Option<&dyn Widget> {
+        self.widgets.get(widget).map(|w| w.as_ref())
+    }
+
+    pub fn get_mut(&mut self, widget: usize) -> Option<&mut (dyn Widget + 'static)> {
+        self.widgets.get_mut(widget).map(|w| w.as_mut())
+    }
+
+    pub fn len(&self) -> usize {
+        self.widgets.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.widgets.is_empty()
+    }
+
+    /// Dispatch a button event, returns the value change it caused
+    pub fn handle(&mut self, event: PadEvent) -> Option<WidgetEvent> {
+        let (widget, w) = self
+            .widgets
+            .iter_mut()
+            .enumerate()
+            .find(|(_, w)| w.contains(event.pos))?;
+        w.handle(event).map(|value| WidgetEvent { widget, value })
+    }
+
+    /// Messages setting the lights of all widgets
+    pub fn messages(&self) -> Vec<LaunchMessage> {
+        self.widgets.iter().flat_map(|w| w.messages()).collect()
+    }
+
+    /// Draw all widgets on the Launchpad
+    /// Returns Error, if action fails
+    pub fn draw<I: Input, O: Output>(
+        &self,
+        dev: &mut LaunchDevice<I, O>,
+    ) -> Result<(), MidiInterfaceError> {
+        dev.send_messages(self.messages())
+    }
+
+    /// Read up to n messages from the Launchpad, dispatch them to the widgets,
+    /// redraw the changed widgets and return the value changes
+    /// Returns Error, if reading or drawing fails
+    pub fn process<I: Input, O: Output>(
+        &mut self,
+        dev: &mut LaunchDevice<I, O>,
+        n: usize,
+    ) -> Result<Vec<WidgetEvent>, MidiInterfaceError> {
+        if !dev.poll()? {
+            return Ok(Vec::new());
+        }
+
+        let mut res: Vec<WidgetEvent> = Vec::new();
+        for event in dev.read_events(n)? {
+            if let Some(changed) = self.handle(event) {
+                res.push(changed);
+            }
+        }
+
+        let mut msgs = Vec::new();
+        for (i, w) in self.widgets.iter().enumerate() {
+            if res.iter().any(|e| e.widget == i) {
+                msgs.extend(w.messages());
+            }
+        }
+        if !msgs.is_empty() {
+            dev.send_messages(msgs)?;
+        }
+        Ok(res)
+    }
+}